@@ -0,0 +1,190 @@
+//! Collects works from AO3's paginated listings, such as a user's bookmarks, works or a series.
+
+use anyhow::Context;
+use scraper::Html;
+
+use super::{Client, ListingLimits, SeriesPosition, WorkId, WorkListing, execute_with_retries};
+use crate::scrape::{selector, text};
+
+/// The works on one page of a listing, in the order they appear.
+struct ListingPage {
+    /// The page's main heading, e.g. a series' title
+    heading: Option<String>,
+    works: Vec<usize>,
+    has_next: bool,
+}
+
+/// All the works in a listing, in the order they appear.
+struct Listing {
+    /// The main heading of the listing's first page
+    heading: Option<String>,
+    works: Vec<WorkId>,
+}
+
+/// A series, with its works in series order.
+pub struct Series {
+    pub id: usize,
+    pub title: String,
+    pub works: Vec<WorkId>,
+}
+
+impl Series {
+    /// Where each of the series' works sits in it, going by the order they are listed in. Works
+    /// the listing leaves out (e.g. restricted ones) throw this off, so it is only a fallback for
+    /// the position on each work's own page.
+    pub fn positions(&self) -> impl Iterator<Item = (WorkId, SeriesPosition)> + '_ {
+        self.works.iter().enumerate().map(|(index, work)| {
+            let position = SeriesPosition {
+                id: self.id,
+                title: self.title.clone(),
+                position: index + 1,
+            };
+            (*work, position)
+        })
+    }
+}
+
+/// Extracts the works from one page of a listing.
+///
+/// Every listing renders each entry as a "blurb", headed by a link to the work. Entries that
+/// aren't works (bookmarked series, external works, deleted works) are skipped.
+fn parse_listing_page(html: &str) -> ListingPage {
+    let document = Html::parse_document(html);
+    let blurb = selector("li.blurb");
+    let heading_link = selector("h4.heading a");
+    let next_page = selector("ol.pagination li.next a");
+    let heading = selector("#main h2.heading");
+
+    let works = document
+        .select(&blurb)
+        .filter_map(|blurb| {
+            let href = blurb.select(&heading_link).next()?.value().attr("href")?;
+            match href.strip_prefix("/works/").map(str::parse::<usize>) {
+                Some(Ok(id)) => Some(id),
+                _ => {
+                    log::debug!("Skipping listing entry that isn't a work ({})", href);
+                    None
+                }
+            }
+        })
+        .collect();
+
+    ListingPage {
+        heading: document.select(&heading).next().map(text),
+        works,
+        has_next: document.select(&next_page).next().is_some(),
+    }
+}
+
+/// Fetches every page of the listing at `path` (or as many as `limits` allow), returning its
+/// works in order.
+///
+/// Listings only show the date a work was updated, not the exact `updated_at` timestamp that
+/// downloads need, so the works come back without timestamps.
+async fn fetch_listing(
+    client: &Client,
+    path: &str,
+    limits: ListingLimits,
+) -> anyhow::Result<Listing> {
+    let mut heading = None;
+    let mut works = Vec::new();
+
+    for page in 1.. {
+        let separator = if path.contains('?') { '&' } else { '?' };
+        let page_url = client.url(&format!("{path}{separator}page={page}"));
+
+        log::debug!("Fetching page {} of listing {}", page, path);
+
+        let req_builder = || {
+            let req = client
+                .http
+                .get(page_url.clone())
+                .build()
+                .context("Cannot build listing request")?;
+            Ok(req)
+        };
+        let html = execute_with_retries(client, req_builder)
+            .await
+            .with_context(|| format!("Cannot fetch page {} of {}", page, path))?
+            .text()
+            .await
+            .context("Listing body not convertible to string")?;
+
+        let listing = parse_listing_page(&html);
+        log::trace!("Found {} work(s) on page {}", listing.works.len(), page);
+
+        if page == 1 {
+            heading = listing.heading;
+        }
+        // Pages can have no works on them (e.g. only bookmarked series), with more after them
+        let done = !listing.has_next;
+        works.extend(listing.works.into_iter().map(WorkId::Bare));
+        if done {
+            break;
+        }
+        if let Some(max_works) = limits.max_works
+            && works.len() >= max_works
+        {
+            log::warn!("Stopping at {} work(s) of listing {}", max_works, path);
+            break;
+        }
+        if limits.max_pages.is_some_and(|max_pages| page >= max_pages) {
+            log::warn!("Stopping after {} page(s) of listing {}", page, path);
+            break;
+        }
+    }
+
+    if let Some(max_works) = limits.max_works {
+        works.truncate(max_works);
+    }
+
+    log::info!("Found {} work(s) in listing {}", works.len(), path);
+
+    Ok(Listing { heading, works })
+}
+
+/// Every work bookmarked by `username`.
+///
+/// Private bookmarks are only listed when `client` is logged in as that user.
+pub async fn bookmarks(client: &Client, username: &str) -> anyhow::Result<Vec<WorkId>> {
+    let listing = fetch_listing(
+        client,
+        &format!("/users/{}/bookmarks", username),
+        ListingLimits::default(),
+    )
+    .await
+    .with_context(|| format!("Cannot fetch bookmarks of {}", username))?;
+
+    Ok(listing.works)
+}
+
+/// The works in `listing`, in the order the archive lists them, up to `limits`.
+pub async fn works_in(
+    client: &Client,
+    listing: &WorkListing,
+    limits: ListingLimits,
+) -> anyhow::Result<Vec<WorkId>> {
+    let works = fetch_listing(client, &listing.path(), limits)
+        .await
+        .with_context(|| format!("Cannot fetch {}", listing))?;
+
+    Ok(works.works)
+}
+
+/// The series with ID `id`, and every work in it.
+pub async fn series(client: &Client, id: usize) -> anyhow::Result<Series> {
+    let listing = fetch_listing(client, &format!("/series/{}", id), ListingLimits::default())
+        .await
+        .with_context(|| format!("Cannot fetch series with ID {}", id))?;
+
+    let title = listing.heading.unwrap_or_else(|| {
+        log::warn!("Could not find title of series with ID {}", id);
+        format!("Series {}", id)
+    });
+
+    Ok(Series {
+        id,
+        title,
+        works: listing.works,
+    })
+}
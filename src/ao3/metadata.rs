@@ -0,0 +1,179 @@
+//! Scrapes a work's metadata from its work page.
+
+use anyhow::Context;
+use chrono::NaiveDate;
+use scraper::Html;
+
+use super::{Client, SeriesPosition, Work, execute_with_retries};
+use crate::scrape::{selector, text};
+
+/// Parses a number the way the archive displays them, e.g. `12,345`.
+fn number(text: &str) -> Option<usize> {
+    text.replace(',', "").trim().parse().ok()
+}
+
+/// Parses the work page of the work with ID `id`.
+pub(super) fn parse_work_page(id: usize, html: &str) -> anyhow::Result<Work> {
+    let document = Html::parse_document(html);
+    let first = |css: &str| document.select(&selector(css)).next();
+    let tags = |class: &str| {
+        document
+            .select(&selector(&format!("dl.work.meta dd.{class}.tags a.tag")))
+            .map(text)
+            .collect::<Vec<_>>()
+    };
+    let stat = |class: &str| first(&format!("dl.stats dd.{class}")).map(text);
+    let date = |class: &str| {
+        stat(class).and_then(|date| NaiveDate::parse_from_str(&date, "%Y-%m-%d").ok())
+    };
+
+    let title = first("h2.title.heading")
+        .map(text)
+        .context("Cannot find title in work HTML")?;
+
+    // Anonymous works have a byline without links
+    let byline = first("h3.byline.heading");
+    let author_link = selector("a[rel=author]");
+    let mut authors = byline
+        .iter()
+        .flat_map(|byline| byline.select(&author_link))
+        .map(text)
+        .collect::<Vec<_>>();
+    if authors.is_empty() {
+        authors.extend(byline.map(text).filter(|byline| !byline.is_empty()));
+    }
+
+    let series = document
+        .select(&selector("dl.work.meta dd.series span.position"))
+        .filter_map(|position| {
+            let link = position.select(&selector("a[href^='/series/']")).next()?;
+            let id = link
+                .value()
+                .attr("href")?
+                .strip_prefix("/series/")?
+                .parse()
+                .ok()?;
+            // "Part 2 of <a>Series</a>"
+            let position = text(position)
+                .strip_prefix("Part ")?
+                .split_whitespace()
+                .next()
+                .and_then(number)?;
+            Some(SeriesPosition {
+                id,
+                title: text(link),
+                position,
+            })
+        })
+        .collect();
+
+    let summary = first(".preface .summary blockquote.userstuff").map(|summary| {
+        let paragraphs = summary
+            .select(&selector("p"))
+            .map(text)
+            .filter(|paragraph| !paragraph.is_empty())
+            .collect::<Vec<_>>();
+        if paragraphs.is_empty() {
+            text(summary)
+        } else {
+            paragraphs.join("\n\n")
+        }
+    });
+
+    // "3/10", or "3/?" while the total is unknown
+    let (chapters, total_chapters) = match stat("chapters") {
+        Some(chapters) => {
+            let (posted, total) = chapters.split_once('/').unwrap_or((&chapters, "?"));
+            (number(posted).unwrap_or(1), number(total))
+        }
+        None => (1, Some(1)),
+    };
+
+    let published = date("published");
+
+    let updated_at = document
+        .select(&selector("li.download a[href]"))
+        .filter_map(|link| link.value().attr("href"))
+        .filter(|href| href.starts_with(&format!("/downloads/{}/", id)))
+        .find_map(|href| {
+            href.split_once('?')?
+                .1
+                .split('&')
+                .find_map(|param| param.strip_prefix("updated_at="))?
+                .parse::<usize>()
+                .ok()
+        })
+        .context("Cannot find download links in work HTML")?;
+
+    Ok(Work {
+        id,
+        title,
+        authors,
+        rating: tags("rating").into_iter().next(),
+        warnings: tags("warning"),
+        categories: tags("category"),
+        fandoms: tags("fandom"),
+        relationships: tags("relationship"),
+        characters: tags("character"),
+        freeform_tags: tags("freeform"),
+        language: first("dl.work.meta dd.language").map(text),
+        series,
+        summary,
+        words: stat("words").as_deref().and_then(number),
+        chapters,
+        total_chapters,
+        kudos: stat("kudos").as_deref().and_then(number),
+        hits: stat("hits").as_deref().and_then(number),
+        published,
+        updated: date("status").or(published),
+        updated_at,
+    })
+}
+
+/// The metadata of the work with ID `id`.
+///
+/// The work page is only fetched once per work, per client.
+pub async fn work_metadata(client: &Client, id: usize) -> anyhow::Result<Work> {
+    let cached = client.works.lock().unwrap().get(&id).cloned();
+    if let Some(work) = cached {
+        log::trace!("Found work with ID {} in metadata cache", id);
+        return Ok(work);
+    }
+
+    log::trace!("Fetching work page of work with ID {}", id);
+    // Works that may have adult content are otherwise hidden behind a warning, even when logged in
+    let work_url = client.url(&format!("/works/{}?view_adult=true", id));
+
+    let req_builder = || {
+        let req = client
+            .http
+            .get(work_url.clone())
+            .build()
+            .context("Cannot build work request")?;
+        Ok(req)
+    };
+
+    let work_html = execute_with_retries(client, req_builder)
+        .await
+        .with_context(|| format!("Cannot fetch main work page for ID {}", id))?
+        .text()
+        .await
+        .context("Work body not convertible to string")?;
+
+    let work = parse_work_page(id, &work_html)
+        .with_context(|| format!("Cannot parse work page for ID {}", id))?;
+
+    client.works.lock().unwrap().insert(id, work.clone());
+    _ = client
+        .timestamps
+        .lock()
+        .unwrap()
+        .insert(id, work.updated_at);
+
+    Ok(work)
+}
+
+/// The metadata of the work with ID `id`, if its work page has already been fetched.
+pub fn cached_work_metadata(client: &Client, id: usize) -> Option<Work> {
+    client.works.lock().unwrap().get(&id).cloned()
+}
@@ -1,20 +1,153 @@
 use core::time;
 use std::{
     collections::HashMap,
-    sync::{Mutex, OnceLock},
+    fmt,
+    fs::File,
+    io::{Seek, Write},
+    sync::Mutex,
 };
 
-use anyhow::{Context, bail};
-use reqwest::{Client, Request, Response, StatusCode, multipart};
+use anyhow::{Context, anyhow, bail};
+use reqwest::{Request, Response, StatusCode, Url, multipart};
+use tokio::time::Instant;
 
-pub use types::WorkId;
+pub use listing::{bookmarks, series, works_in};
+pub use metadata::{cached_work_metadata, work_metadata};
+pub use session::logged_in_user;
+pub use types::{ListingLimits, SeriesPosition, Work, WorkId, WorkListing, WorkRef};
 
+mod listing;
+mod metadata;
+mod session;
+#[cfg(test)]
+mod tests;
 mod types;
 
-static AO3DL_USER_AGENT: &'static str = concat!("ao3dl", "/", env!("CARGO_PKG_VERSION"));
+static AO3DL_USER_AGENT: &str = concat!("ao3dl", "/", env!("CARGO_PKG_VERSION"));
 
-static AUTHENTICITY_TOKEN_URL: &'static str = "https://archiveofourown.org/token_dispenser.json";
-static LOGIN_URL: &'static str = "https://archiveofourown.org/users/login";
+pub static DEFAULT_BASE_URL: &str = "https://archiveofourown.org";
+
+static AUTHENTICITY_TOKEN_PATH: &str = "/token_dispenser.json";
+static LOGIN_PATH: &str = "/users/login";
+
+/// How many times to download a work before giving up on getting a valid file
+const INVALID_DOWNLOAD_ATTEMPTS: usize = 3;
+/// How long to wait before downloading again after getting an invalid file
+const INVALID_DOWNLOAD_DELAY: time::Duration = time::Duration::from_secs(10);
+
+/// Failures that callers may want to handle differently from any other error.
+#[derive(Debug)]
+pub enum Error {
+    /// The page doesn't exist, e.g. because the work has been deleted
+    NotFound,
+    /// The page is only shown to logged-in users, so the archive sent us to the login form instead
+    Restricted,
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::NotFound => write!(f, "Got HTTP 404 (Not Found)"),
+            Self::Restricted => write!(f, "Only logged-in users can see this"),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+/// An HTTP client bound to a single archive (AO3 itself, a mirror, or anything else running the
+/// OTW archive software).
+pub struct Client {
+    http: reqwest::Client,
+    cookies: std::sync::Arc<session::Jar>,
+    base_url: String,
+    clock: Clock,
+    /// `updated_at` timestamps already known for each work ID
+    timestamps: Mutex<HashMap<usize, usize>>,
+    /// Metadata of every work whose work page has been fetched, by work ID
+    works: Mutex<HashMap<usize, Work>>,
+    /// When the archive last told us (with a 429) that we may resume making requests. This is
+    /// shared by everything using the client, so one `Retry-After` pauses every download.
+    paused_until: Mutex<Option<Instant>>,
+}
+
+impl Client {
+    /// Builds the absolute URL for a path on the archive, e.g. `/works/123`.
+    fn url(&self, path: &str) -> String {
+        format!("{}{}", self.base_url, path)
+    }
+
+    /// The path of the login form, including whatever path the archive is served under.
+    fn login_path(&self) -> String {
+        Url::parse(&self.url(LOGIN_PATH))
+            .map_or_else(|_| LOGIN_PATH.to_owned(), |url| url.path().to_owned())
+    }
+
+    /// The work page of the work with ID `id`, e.g. for linking back to it.
+    pub fn work_url(&self, id: usize) -> String {
+        self.url(&format!("/works/{}", id))
+    }
+
+    /// Holds off all requests for `delay`. The most recent `Retry-After` wins.
+    fn pause_for(&self, delay: time::Duration) {
+        *self.paused_until.lock().unwrap() = Some(self.clock.now() + delay);
+    }
+
+    /// Sleeps until any pause requested by the archive is over.
+    async fn wait_out_pause(&self) {
+        let paused_until = *self.paused_until.lock().unwrap();
+        if let Some(paused_until) = paused_until {
+            let remaining = paused_until.saturating_duration_since(self.clock.now());
+            if !remaining.is_zero() {
+                log::debug!(target: "ao3dl::ao3::retrier", "Waiting {} secs for rate limit to expire", remaining.as_secs_f64());
+                self.clock.sleep(remaining).await;
+            }
+        }
+    }
+}
+
+/// Where the retrier gets its sleeps from.
+///
+/// Tests use [`Clock::Manual`], which returns immediately and records how long it was asked to
+/// sleep, so that back-off behaviour can be checked without actually waiting for it. Its time
+/// only moves forward by the amount it has been asked to sleep.
+#[derive(Clone)]
+pub enum Clock {
+    Tokio,
+    #[cfg(test)]
+    Manual {
+        origin: Instant,
+        slept: std::sync::Arc<Mutex<Vec<time::Duration>>>,
+    },
+}
+
+impl Clock {
+    #[cfg(test)]
+    pub fn manual() -> (Clock, std::sync::Arc<Mutex<Vec<time::Duration>>>) {
+        let slept = std::sync::Arc::new(Mutex::new(Vec::new()));
+        let clock = Clock::Manual {
+            origin: Instant::now(),
+            slept: slept.clone(),
+        };
+        (clock, slept)
+    }
+
+    fn now(&self) -> Instant {
+        match self {
+            Clock::Tokio => Instant::now(),
+            #[cfg(test)]
+            Clock::Manual { origin, slept } => *origin + slept.lock().unwrap().iter().sum(),
+        }
+    }
+
+    async fn sleep(&self, duration: time::Duration) {
+        match self {
+            Clock::Tokio => tokio::time::sleep(duration).await,
+            #[cfg(test)]
+            Clock::Manual { slept, .. } => slept.lock().unwrap().push(duration),
+        }
+    }
+}
 
 async fn execute_with_retries(
     client: &Client,
@@ -23,6 +156,7 @@ async fn execute_with_retries(
     const DELAY_SCALING_FACTOR: f64 = 1.25;
     const DELAY_BASE: f64 = 1.0;
     let mut exponential_delay = DELAY_BASE;
+    let login_path = client.login_path();
 
     loop {
         if exponential_delay > 64.0 {
@@ -30,15 +164,21 @@ async fn execute_with_retries(
             bail!("Retried too many times (hit delay limit of 64s)");
         }
 
+        client.wait_out_pause().await;
+
         log::trace!(target: "ao3dl::ao3::retrier", "Building request");
         let req = build_req().context("Cannot (re)build request to (re)try it")?;
+        let requested_login = req.url().path() == login_path;
         log::trace!(target: "ao3dl::ao3::retrier", "Attempting request");
-        let possible_response = client.execute(req).await;
+        let possible_response = client.http.execute(req).await;
 
         match possible_response {
             Ok(resp) => {
                 let code = resp.status();
-                if code.is_success() {
+                if code.is_success() && !requested_login && resp.url().path() == login_path {
+                    log::trace!(target: "ao3dl::ao3::retrier", "Got redirected to the login form");
+                    return Err(Error::Restricted.into());
+                } else if code.is_success() {
                     log::trace!(target: "ao3dl::ao3::retrier", "Got successful response to request");
                     return Ok(resp);
                 } else if code == StatusCode::TOO_MANY_REQUESTS {
@@ -51,8 +191,8 @@ async fn execute_with_retries(
                         Some(Ok(val)) => {
                             if let Ok(delay) = val.parse::<u64>() {
                                 // This is ao3's case
-                                log::info!(target: "ao3dl::ao3::retrier", "Sleeping {} secs", delay);
-                                tokio::time::sleep(time::Duration::from_secs(delay)).await;
+                                log::info!(target: "ao3dl::ao3::retrier", "Pausing requests for {} secs", delay);
+                                client.pause_for(time::Duration::from_secs(delay));
                                 exponential_delay = DELAY_BASE;
                                 continue;
                             } else {
@@ -67,8 +207,13 @@ async fn execute_with_retries(
                 } else if code.is_server_error() {
                     exponential_delay *= DELAY_SCALING_FACTOR;
                     log::trace!(target: "ao3dl::ao3::retrier", "got server error ({}), sleeping {} secs", code.as_str(), exponential_delay);
-                    tokio::time::sleep(time::Duration::from_secs_f64(exponential_delay)).await;
+                    client
+                        .clock
+                        .sleep(time::Duration::from_secs_f64(exponential_delay))
+                        .await;
                     continue;
+                } else if code == StatusCode::NOT_FOUND {
+                    return Err(Error::NotFound.into());
                 } else {
                     bail!(
                         "Unhandled HTTP code {} ({:?})",
@@ -87,7 +232,8 @@ async fn execute_with_retries(
 async fn get_authenticity_token(client: &Client) -> anyhow::Result<String> {
     let req_builder = || {
         let req = client
-            .get(AUTHENTICITY_TOKEN_URL)
+            .http
+            .get(client.url(AUTHENTICITY_TOKEN_PATH))
             .build()
             .context("Cannot build authenticity token URL")?;
         Ok(req)
@@ -127,7 +273,8 @@ pub async fn login(client: &Client, username: &str, password: &str) -> anyhow::R
             .text("authenticity_token", token.clone());
 
         let req = client
-            .post(LOGIN_URL)
+            .http
+            .post(client.url(LOGIN_PATH))
             .multipart(form)
             .build()
             .context("Cannot build login request")?;
@@ -155,139 +302,214 @@ pub async fn login(client: &Client, username: &str, password: &str) -> anyhow::R
     Ok(())
 }
 
-async fn compute_download_url(
-    client: &Client,
-    work: &WorkId,
-    format: crate::Format,
-) -> anyhow::Result<String> {
-    log::trace!("Computing download URL for work with ID {}", &work.id());
-
-    static CACHE_MUTEX: OnceLock<Mutex<HashMap<usize, usize>>> = OnceLock::new();
-    CACHE_MUTEX.get_or_init(|| Mutex::new(HashMap::new()));
-    let mut cache = CACHE_MUTEX
-        .get()
-        .with_context(|| "Cannot get timestamp cache")?
-        .lock()
-        .unwrap();
-
-    let download_path = match work {
+/// Determines when a work was last updated, as the Unix timestamp AO3 puts in its download links.
+///
+/// Works annotated with a timestamp are trusted as-is; otherwise the work page is fetched (once per
+/// work, per client).
+pub async fn updated_at(client: &Client, work: &WorkId) -> anyhow::Result<usize> {
+    match work {
         WorkId::Bare(id) => {
-            if let Some(timestamp) = cache.get(id) {
+            let cached_timestamp = client.timestamps.lock().unwrap().get(id).copied();
+            if let Some(timestamp) = cached_timestamp {
                 log::trace!(
                     "Found matching work ID in timestamp cache; short-circuiting with timestamp {timestamp}"
                 );
-                format!(
-                    "/downloads/{id}/x.{extension}?updated_at={timestamp}",
-                    extension = format.file_extension()
-                )
-            } else {
-                log::trace!("Fetching work page to determine updated_at timestamp for work");
-                let work_url = format!("https://archiveofourown.org/works/{}", id);
-
-                let req_builder = || {
-                    let req = client
-                        .get(work_url.clone())
-                        .build()
-                        .context("Cannot build work request")?;
-                    Ok(req)
-                };
-
-                let regex = regex::Regex::new(
-                    format!(
-                        r#"<a href="(?<download_path>/downloads/{}/(?<file_name>.+)\.epub\?updated_at=(?<timestamp>\d+))">EPUB</a>"#,
-                        id
-                    )
-                    .as_str(),
-                )
-                .context("Cannot create regex!")?;
-
-                let work_html = execute_with_retries(client, req_builder)
-                    .await
-                    .with_context(|| format!("Cannot fetch main work page for ID {}", id))?
-                    .text()
-                    .await
-                    .context("Work body not convertible to string")?;
-
-                let captures = regex
-                    .captures(&work_html)
-                    .context("Cannot find EPUB download URL in work HTML")?;
-
-                let file_name = captures
-                    .name("file_name")
-                    .with_context(|| "Cannot find filename in download path")?
-                    .as_str();
-                let timestamp = captures
-                    .name("timestamp")
-                    .with_context(|| "Cannot find timestamp in download path")?
-                    .as_str()
-                    .parse::<usize>()?;
-
-                _ = cache.insert(*id, timestamp);
-
-                format!(
-                    "/downloads/{id}/{file_name}.{extension}?updated_at={timestamp}",
-                    extension = format.file_extension()
-                )
+                return Ok(timestamp);
             }
+
+            log::trace!("Fetching work page to determine updated_at timestamp for work");
+            let timestamp = work_metadata(client, *id).await?.updated_at;
+
+            Ok(timestamp)
         }
         WorkId::WithTimestamp { id, timestamp } => {
             log::trace!(
                 "Work comes annotated with timestamp {}; short-circuiting",
                 timestamp
             );
-            _ = cache.insert(*id, *timestamp);
-            format!(
-                "/downloads/{id}/x.{extension}?updated_at={timestamp}",
-                extension = format.file_extension()
-            )
+            _ = client.timestamps.lock().unwrap().insert(*id, *timestamp);
+            Ok(*timestamp)
         }
+    }
+}
+
+/// The ID of the work that chapter `chapter_id` belongs to.
+///
+/// The archive redirects chapter links without a work ID to the chapter's place in its work.
+pub async fn work_of_chapter(client: &Client, chapter_id: usize) -> anyhow::Result<usize> {
+    let chapter_url = client.url(&format!("/chapters/{}", chapter_id));
+    let req_builder = || {
+        let req = client
+            .http
+            .get(chapter_url.clone())
+            .build()
+            .context("Cannot build chapter request")?;
+        Ok(req)
     };
 
+    let resp = execute_with_retries(client, req_builder)
+        .await
+        .with_context(|| format!("Cannot fetch chapter with ID {}", chapter_id))?;
+
+    let work_id = resp
+        .url()
+        .as_str()
+        .strip_prefix(&client.base_url)
+        .and_then(|path| path.strip_prefix("/works/"))
+        .and_then(|path| path.split('/').next())
+        .and_then(|id| id.parse::<usize>().ok())
+        .with_context(|| {
+            format!(
+                "Chapter with ID {} led to '{}' rather than a work",
+                chapter_id,
+                resp.url()
+            )
+        })?;
+
+    log::debug!(
+        "Chapter with ID {} is in work with ID {}",
+        chapter_id,
+        work_id
+    );
+
+    Ok(work_id)
+}
+
+async fn compute_download_url(
+    client: &Client,
+    work: &WorkId,
+    format: crate::Format,
+) -> anyhow::Result<String> {
+    log::trace!("Computing download URL for work with ID {}", &work.id());
+
+    let timestamp = updated_at(client, work).await?;
+
+    // AO3 ignores the file name part of the path, so there's no need to work out the real one
+    let download_path = format!(
+        "/downloads/{id}/x.{extension}?updated_at={timestamp}",
+        id = work.id(),
+        extension = format.file_extension()
+    );
+
     log::trace!(
         "Computed download URL for work with ID {} as {}",
         &work.id(),
         download_path
     );
 
-    Ok(format!("https://archiveofourown.org{}", download_path))
+    Ok(client.url(&download_path))
 }
 
+/// Downloads `work` as `format` into `file`, replacing whatever was in it, and checks that what
+/// was downloaded really is a `format` file. Downloads bigger than `max_size` bytes are abandoned.
+///
+/// The download is streamed to disk, so that big works never have to fit in memory.
 pub async fn download(
     client: &Client,
     work: &WorkId,
     format: crate::Format,
-) -> anyhow::Result<bytes::Bytes> {
+    max_size: Option<u64>,
+    file: &mut File,
+) -> anyhow::Result<()> {
     log::trace!("Attempting to download work with ID {}", &work.id());
 
-    let download_url = compute_download_url(&client, work, format)
+    let download_url = compute_download_url(client, work, format)
         .await
         .with_context(|| format!("Cannot determine download URL for ID {}", work.id()))?;
 
     let req_builder = || {
         let req = client
+            .http
             .get(download_url.clone())
             .build()
             .context("Cannot build download request")?;
         Ok(req)
     };
-    let bytes = execute_with_retries(client, req_builder)
-        .await
-        .with_context(|| format!("Cannot download work with ID {}", work.id()))?
-        .bytes()
-        .await
-        .context("Cannot get body of response to download request as bytes")?;
+    let too_big = |max_size| {
+        anyhow!(
+            "Download of work with ID {} as {:?} is bigger than the maximum size of {} bytes",
+            work.id(),
+            format,
+            max_size
+        )
+    };
+    // Error and maintenance pages can stand in for downloads, and may be gone on a second try
+    let mut attempt = 1;
+    loop {
+        let mut resp = execute_with_retries(client, req_builder)
+            .await
+            .with_context(|| format!("Cannot download work with ID {}", work.id()))?;
+        if let (Some(max_size), Some(len)) = (max_size, resp.content_length())
+            && len > max_size
+        {
+            return Err(too_big(max_size));
+        }
 
-    log::trace!("Successfully downloaded work with ID {}", &work.id());
+        file.set_len(0).context("Cannot empty download file")?;
+        file.rewind().context("Cannot empty download file")?;
+        let mut len = 0;
+        while let Some(chunk) = resp
+            .chunk()
+            .await
+            .context("Cannot read body of response to download request")?
+        {
+            len += chunk.len() as u64;
+            if let Some(max_size) = max_size
+                && len > max_size
+            {
+                return Err(too_big(max_size));
+            }
+            file.write_all(&chunk)
+                .context("Cannot write download to disk")?;
+        }
+        file.rewind().context("Cannot read download back")?;
 
-    Ok(bytes)
+        match crate::extractor::validate(format, file) {
+            Ok(()) => {
+                log::trace!(
+                    "Successfully downloaded {} bytes of work with ID {}",
+                    len,
+                    &work.id()
+                );
+                file.rewind().context("Cannot read download back")?;
+                return Ok(());
+            }
+            Err(e) if attempt < INVALID_DOWNLOAD_ATTEMPTS => {
+                log::warn!(
+                    "Download of work with ID {} as {:?} is not valid ({:#}), retrying",
+                    work.id(),
+                    format,
+                    e
+                );
+                client.clock.sleep(INVALID_DOWNLOAD_DELAY).await;
+                attempt += 1;
+            }
+            Err(e) => {
+                return Err(e.context(format!(
+                    "Download of work with ID {} as {:?} is not valid",
+                    work.id(),
+                    format
+                )));
+            }
+        }
+    }
 }
 
-pub fn make_client() -> anyhow::Result<Client> {
-    let client = Client::builder()
+pub fn make_client(base_url: &Url, clock: Clock) -> anyhow::Result<Client> {
+    let cookies = std::sync::Arc::new(session::Jar::default());
+    let http = reqwest::Client::builder()
         .user_agent(AO3DL_USER_AGENT)
-        .cookie_store(true)
+        .cookie_provider(cookies.clone())
         .build()
         .context("Cannot build client")?;
 
-    Ok(client)
+    Ok(Client {
+        http,
+        cookies,
+        base_url: base_url.as_str().trim_end_matches('/').to_owned(),
+        clock,
+        timestamps: Mutex::new(HashMap::new()),
+        works: Mutex::new(HashMap::new()),
+        paused_until: Mutex::new(None),
+    })
 }
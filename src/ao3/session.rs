@@ -0,0 +1,129 @@
+//! Keeps the archive's cookies between runs, so that logging in (which the archive rate-limits) is
+//! only needed once the saved session has expired.
+
+use std::{
+    fs::File,
+    io::{self, BufReader},
+    path::Path,
+    sync::RwLock,
+};
+
+use anyhow::Context;
+use reqwest::header::HeaderValue;
+use scraper::Html;
+
+use super::{Client, execute_with_retries};
+use crate::{atomic, scrape::selector};
+
+/// Any page greets logged-in users by name; the front page is about the cheapest one to fetch
+static SESSION_CHECK_PATH: &str = "/";
+
+/// The client's cookies, kept where they can be saved and loaded again.
+#[derive(Default)]
+pub(super) struct Jar(RwLock<cookie_store::CookieStore>);
+
+impl reqwest::cookie::CookieStore for Jar {
+    fn set_cookies(
+        &self,
+        cookie_headers: &mut dyn Iterator<Item = &HeaderValue>,
+        url: &reqwest::Url,
+    ) {
+        let cookies = cookie_headers.filter_map(|value| {
+            let value = value.to_str().ok()?;
+            cookie_store::RawCookie::parse(value)
+                .map(|cookie| cookie.into_owned())
+                .ok()
+        });
+        self.0.write().unwrap().store_response_cookies(cookies, url);
+    }
+
+    fn cookies(&self, url: &reqwest::Url) -> Option<HeaderValue> {
+        let cookies = self
+            .0
+            .read()
+            .unwrap()
+            .get_request_values(url)
+            .map(|(name, value)| format!("{name}={value}"))
+            .collect::<Vec<_>>()
+            .join("; ");
+        if cookies.is_empty() {
+            return None;
+        }
+        HeaderValue::from_str(&cookies).ok()
+    }
+}
+
+impl Client {
+    /// Loads the cookies saved at `path` by [`Client::save_session`], returning whether there were
+    /// any.
+    pub fn load_session(&self, path: &Path) -> anyhow::Result<bool> {
+        let file = match File::open(path) {
+            Ok(file) => file,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                log::debug!("No saved session at '{}'", path.display());
+                return Ok(false);
+            }
+            Err(e) => {
+                return Err(e)
+                    .with_context(|| format!("Cannot read saved session at '{}'", path.display()));
+            }
+        };
+        let cookies = cookie_store::serde::json::load(BufReader::new(file))
+            .map_err(|e| anyhow::anyhow!(e))
+            .with_context(|| format!("Cannot parse saved session at '{}'", path.display()))?;
+        let loaded = cookies.iter_unexpired().next().is_some();
+        *self.cookies.0.write().unwrap() = cookies;
+
+        log::debug!("Loaded saved session from '{}'", path.display());
+
+        Ok(loaded)
+    }
+
+    /// Saves the client's cookies to `path`, readable only by the current user since they are as
+    /// good as a password.
+    pub fn save_session(&self, path: &Path) -> anyhow::Result<()> {
+        let mut file = atomic::AtomicFile::create_private(path)?;
+        // The session cookie itself only lasts "until the browser is closed", which for us is
+        // until it expires on the archive's side
+        cookie_store::serde::json::save_incl_expired_and_nonpersistent(
+            &self.cookies.0.read().unwrap(),
+            &mut file,
+        )
+        .map_err(|e| anyhow::anyhow!(e))
+        .with_context(|| format!("Cannot write session to '{}'", path.display()))?;
+        file.commit()?;
+
+        log::debug!("Saved session to '{}'", path.display());
+
+        Ok(())
+    }
+}
+
+/// Asks the archive who the client is logged in as, if anyone.
+pub async fn logged_in_user(client: &Client) -> anyhow::Result<Option<String>> {
+    let req_builder = || {
+        let req = client
+            .http
+            .get(client.url(SESSION_CHECK_PATH))
+            .build()
+            .context("Cannot build session check request")?;
+        Ok(req)
+    };
+    let html = execute_with_retries(client, req_builder)
+        .await
+        .context("Cannot make session check request")?
+        .text()
+        .await
+        .context("Cannot get body of response to session check request as text")?;
+
+    Ok(parse_greeting(&html))
+}
+
+/// Finds the name of the logged-in user in the "Hi, …!" menu at the top of every page.
+fn parse_greeting(html: &str) -> Option<String> {
+    let document = Html::parse_document(html);
+    let greeting = selector(r#"#greeting a.dropdown-toggle[href^="/users/"]"#);
+    let href = document.select(&greeting).next()?.value().attr("href")?;
+    let username = href.strip_prefix("/users/")?.split('/').next()?;
+    (!username.is_empty()).then(|| username.to_owned())
+}
@@ -0,0 +1,781 @@
+use std::{
+    io::Read,
+    sync::{Arc, Mutex},
+};
+
+use reqwest::StatusCode;
+
+use super::*;
+use crate::{
+    Format,
+    mock::{self, MockArchive, MockWork, Scripted},
+};
+
+fn client_for(archive: &MockArchive) -> (Client, Arc<Mutex<Vec<time::Duration>>>) {
+    let (clock, slept) = Clock::manual();
+    let client = make_client(&archive.base_url, clock).unwrap();
+    (client, slept)
+}
+
+/// Downloads `work` to a temporary file, and reads it back.
+async fn download_bytes(
+    client: &Client,
+    work: &WorkId,
+    format: Format,
+    max_size: Option<u64>,
+) -> anyhow::Result<Vec<u8>> {
+    let mut file = tempfile::tempfile().unwrap();
+    download(client, work, format, max_size, &mut file).await?;
+    let mut bytes = Vec::new();
+    file.read_to_end(&mut bytes).unwrap();
+    Ok(bytes)
+}
+
+#[tokio::test]
+async fn login_succeeds_with_correct_password() {
+    let archive = MockArchive::start().await;
+    let (client, _) = client_for(&archive);
+
+    login(&client, mock::USERNAME, mock::PASSWORD)
+        .await
+        .unwrap();
+
+    assert_eq!(
+        archive.requests(),
+        ["GET /token_dispenser.json", "POST /users/login"]
+    );
+}
+
+#[tokio::test]
+async fn login_fails_with_bad_password() {
+    let archive = MockArchive::start().await;
+    let (client, _) = client_for(&archive);
+
+    let err = login(&client, mock::USERNAME, "hunter2").await.unwrap_err();
+
+    assert!(err.to_string().contains("Could not log in"), "{err:#}");
+}
+
+#[tokio::test]
+async fn saved_sessions_stay_logged_in() {
+    let archive = MockArchive::start().await;
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("session.json");
+    let (client, _) = client_for(&archive);
+    assert_eq!(logged_in_user(&client).await.unwrap(), None);
+    login(&client, mock::USERNAME, mock::PASSWORD)
+        .await
+        .unwrap();
+    client.save_session(&path).unwrap();
+
+    let (resumed, _) = client_for(&archive);
+    assert!(resumed.load_session(&path).unwrap());
+
+    assert_eq!(
+        logged_in_user(&resumed).await.unwrap().as_deref(),
+        Some(mock::USERNAME)
+    );
+    archive.expire_sessions();
+    assert_eq!(logged_in_user(&resumed).await.unwrap(), None);
+}
+
+#[tokio::test]
+async fn download_url_is_scraped_from_work_page() {
+    let archive = MockArchive::start().await;
+    archive.add_work(1, MockWork::new("Some Title", 1700000000));
+    let (client, _) = client_for(&archive);
+
+    let url = compute_download_url(&client, &WorkId::Bare(1), Format::EPUB)
+        .await
+        .unwrap();
+    assert_eq!(
+        url,
+        format!(
+            "{}downloads/1/x.epub?updated_at=1700000000",
+            archive.base_url
+        )
+    );
+
+    // The timestamp is cached, so other formats don't refetch the work page
+    let url = compute_download_url(&client, &WorkId::Bare(1), Format::PDF)
+        .await
+        .unwrap();
+    assert_eq!(
+        url,
+        format!(
+            "{}downloads/1/x.pdf?updated_at=1700000000",
+            archive.base_url
+        )
+    );
+    assert_eq!(archive.hits("/works/1"), 1);
+}
+
+#[tokio::test]
+async fn download_url_short_circuits_with_timestamp() {
+    let archive = MockArchive::start().await;
+    let (client, _) = client_for(&archive);
+
+    let work = WorkId::WithTimestamp {
+        id: 2,
+        timestamp: 1600000000,
+    };
+    let url = compute_download_url(&client, &work, Format::EPUB)
+        .await
+        .unwrap();
+
+    assert_eq!(
+        url,
+        format!(
+            "{}downloads/2/x.epub?updated_at=1600000000",
+            archive.base_url
+        )
+    );
+    assert!(archive.requests().is_empty());
+}
+
+#[tokio::test]
+async fn download_url_fails_for_missing_work() {
+    let archive = MockArchive::start().await;
+    let (client, _) = client_for(&archive);
+
+    let err = compute_download_url(&client, &WorkId::Bare(3), Format::EPUB)
+        .await
+        .unwrap_err();
+
+    assert!(format!("{err:#}").contains("404"), "{err:#}");
+}
+
+#[tokio::test]
+async fn retries_after_delay_given_by_429() {
+    let archive = MockArchive::start().await;
+    archive.add_work(4, MockWork::new("Rate Limited", 1700000000));
+    archive.script(
+        "/works/4",
+        [
+            Scripted::TooManyRequests {
+                retry_after: Some("30"),
+            },
+            Scripted::TooManyRequests {
+                retry_after: Some("5"),
+            },
+        ],
+    );
+    let (client, slept) = client_for(&archive);
+
+    download_bytes(&client, &WorkId::Bare(4), Format::HTML, None)
+        .await
+        .unwrap();
+
+    assert_eq!(archive.hits("/works/4"), 3);
+    assert_eq!(
+        *slept.lock().unwrap(),
+        [time::Duration::from_secs(30), time::Duration::from_secs(5)]
+    );
+}
+
+#[tokio::test]
+async fn gives_up_on_429_without_retry_after() {
+    let archive = MockArchive::start().await;
+    archive.script(
+        "/token_dispenser.json",
+        [Scripted::TooManyRequests { retry_after: None }],
+    );
+    let (client, slept) = client_for(&archive);
+
+    let err = login(&client, mock::USERNAME, mock::PASSWORD)
+        .await
+        .unwrap_err();
+
+    assert!(
+        format!("{err:#}").contains("without Retry-After"),
+        "{err:#}"
+    );
+    assert!(slept.lock().unwrap().is_empty());
+}
+
+#[tokio::test]
+async fn backs_off_exponentially_on_server_errors() {
+    let archive = MockArchive::start().await;
+    archive.add_work(5, MockWork::new("Flaky", 1700000000));
+    archive.script(
+        "/downloads/5/x.pdf",
+        [
+            Scripted::Status(StatusCode::BAD_GATEWAY),
+            Scripted::Status(StatusCode::SERVICE_UNAVAILABLE),
+            Scripted::Status(StatusCode::INTERNAL_SERVER_ERROR),
+        ],
+    );
+    let (client, slept) = client_for(&archive);
+
+    let work = WorkId::WithTimestamp {
+        id: 5,
+        timestamp: 1700000000,
+    };
+    let bytes = download_bytes(&client, &work, Format::PDF, None)
+        .await
+        .unwrap();
+
+    assert!(bytes.starts_with(b"%PDF-"));
+    let slept = slept.lock().unwrap();
+    assert_eq!(slept.len(), 3);
+    assert!(slept.windows(2).all(|pair| pair[0] < pair[1]));
+}
+
+#[tokio::test]
+async fn gives_up_after_server_error_storm() {
+    let archive = MockArchive::start().await;
+    archive.script(
+        "/token_dispenser.json",
+        std::iter::repeat_n(Scripted::Status(StatusCode::SERVICE_UNAVAILABLE), 100),
+    );
+    let (client, slept) = client_for(&archive);
+
+    let err = login(&client, mock::USERNAME, mock::PASSWORD)
+        .await
+        .unwrap_err();
+
+    assert!(
+        format!("{err:#}").contains("Retried too many times"),
+        "{err:#}"
+    );
+    assert_eq!(
+        archive.hits("/token_dispenser.json"),
+        slept.lock().unwrap().len()
+    );
+}
+
+#[tokio::test(start_paused = true)]
+async fn retry_after_pauses_every_request_on_the_client() {
+    let client = make_client(&DEFAULT_BASE_URL.parse().unwrap(), Clock::Tokio).unwrap();
+    let start = Instant::now();
+
+    client.pause_for(time::Duration::from_secs(30));
+    let finished = tokio::join!(
+        async {
+            client.wait_out_pause().await;
+            Instant::now()
+        },
+        async {
+            client.wait_out_pause().await;
+            Instant::now()
+        },
+    );
+
+    // Both waited for the same pause, concurrently
+    assert_eq!(finished.0 - start, time::Duration::from_secs(30));
+    assert_eq!(finished.1 - start, time::Duration::from_secs(30));
+
+    // Once it has expired, nothing waits any more
+    client.wait_out_pause().await;
+    assert_eq!(Instant::now() - start, time::Duration::from_secs(30));
+}
+
+#[tokio::test]
+async fn bookmarks_are_collected_from_every_page() {
+    let archive = MockArchive::start().await;
+    archive.add_bookmark("/works/11", false);
+    archive.add_bookmark("/series/12", false);
+    archive.add_bookmark("/works/13", true);
+    archive.add_bookmark("/works/14", false);
+    archive.add_bookmark("/external_works/15", false);
+    let (client, _) = client_for(&archive);
+
+    let public = bookmarks(&client, mock::USERNAME).await.unwrap();
+    assert_eq!(
+        public.iter().map(WorkId::id).collect::<Vec<_>>(),
+        [&11, &14]
+    );
+
+    login(&client, mock::USERNAME, mock::PASSWORD)
+        .await
+        .unwrap();
+    let all = bookmarks(&client, mock::USERNAME).await.unwrap();
+    assert_eq!(
+        all.iter().map(WorkId::id).collect::<Vec<_>>(),
+        [&11, &13, &14]
+    );
+    assert_eq!(
+        archive.hits(&format!("/users/{}/bookmarks", mock::USERNAME)),
+        2 + 3
+    );
+}
+
+#[tokio::test]
+async fn pages_without_works_do_not_end_the_listing() {
+    let archive = MockArchive::start().await;
+    archive.add_bookmark("/works/21", false);
+    archive.add_bookmark("/works/22", false);
+    archive.add_bookmark("/series/23", false);
+    archive.add_bookmark("/external_works/24", false);
+    archive.add_bookmark("/works/25", false);
+    let (client, _) = client_for(&archive);
+
+    let works = bookmarks(&client, mock::USERNAME).await.unwrap();
+
+    assert_eq!(
+        works.iter().map(WorkId::id).collect::<Vec<_>>(),
+        [&21, &22, &25]
+    );
+    assert_eq!(
+        archive.hits(&format!("/users/{}/bookmarks", mock::USERNAME)),
+        3
+    );
+}
+
+#[tokio::test]
+async fn series_keeps_its_title_and_order_across_pages() {
+    let archive = MockArchive::start().await;
+    archive.add_series(21, "The Long Way Round", &[33, 31, 32]);
+    let (client, _) = client_for(&archive);
+
+    let found = series(&client, 21).await.unwrap();
+
+    assert_eq!(found.title, "The Long Way Round");
+    assert_eq!(
+        found
+            .positions()
+            .map(|(work, position)| (*work.id(), position.position))
+            .collect::<Vec<_>>(),
+        [(33, 1), (31, 2), (32, 3)]
+    );
+    assert_eq!(archive.hits("/series/21"), 2);
+}
+
+#[tokio::test]
+async fn missing_series_is_an_error() {
+    let archive = MockArchive::start().await;
+    let (client, _) = client_for(&archive);
+
+    let err = series(&client, 22).await.err().unwrap();
+
+    assert!(
+        matches!(err.downcast_ref(), Some(Error::NotFound)),
+        "{err:#}"
+    );
+}
+
+#[test]
+fn user_listings_are_recognised() {
+    assert_eq!(
+        WorkListing::from_path("/users/someone/works?page=3"),
+        Some(WorkListing::UserWorks {
+            user: "someone".to_owned()
+        })
+    );
+    assert_eq!(
+        WorkListing::from_path("/users/someone/pseuds/Other%20Name/works/"),
+        Some(WorkListing::PseudWorks {
+            user: "someone".to_owned(),
+            pseud: "Other%20Name".to_owned()
+        })
+    );
+    assert_eq!(
+        WorkListing::from_path("/users/someone/gifts").map(|listing| listing.path()),
+        Some("/users/someone/gifts".to_owned())
+    );
+    assert_eq!(WorkListing::from_path("/users/someone/bookmarks"), None);
+    assert_eq!(WorkListing::from_path("/works/1"), None);
+}
+
+#[tokio::test]
+async fn user_works_are_collected_from_every_page() {
+    let archive = MockArchive::start().await;
+    archive.add_listing("/users/someone/works", &[43, 41, 42]);
+    let (client, _) = client_for(&archive);
+    let listing = WorkListing::UserWorks {
+        user: "someone".to_owned(),
+    };
+
+    let works = works_in(&client, &listing, ListingLimits::default())
+        .await
+        .unwrap();
+
+    assert_eq!(
+        works.iter().map(WorkId::id).collect::<Vec<_>>(),
+        [&43, &41, &42]
+    );
+}
+
+#[test]
+fn tag_search_and_collection_listings_are_recognised() {
+    assert_eq!(
+        WorkListing::from_path("/tags/Alternate%20Universe/works").map(|listing| listing.path()),
+        Some("/tags/Alternate%20Universe/works".to_owned())
+    );
+    assert_eq!(
+        WorkListing::from_path("/collections/SomeFest2024/works?page=4"),
+        Some(WorkListing::Collection {
+            name: "SomeFest2024".to_owned()
+        })
+    );
+    assert_eq!(
+        WorkListing::from_path("/works/search?page=2&work_search%5Bquery%5D=heist"),
+        Some(WorkListing::Search {
+            query: "work_search%5Bquery%5D=heist".to_owned()
+        })
+    );
+    assert_eq!(
+        WorkListing::from_path("/works?work_search[complete]=T&tag_id=Fluff&page=9")
+            .map(|listing| listing.path()),
+        Some("/works?work_search[complete]=T&tag_id=Fluff".to_owned())
+    );
+    assert_eq!(WorkListing::from_path("/works"), None);
+    assert_eq!(WorkListing::from_path("/works/search"), None);
+}
+
+#[tokio::test]
+async fn listings_stop_at_their_limits() {
+    let archive = MockArchive::start().await;
+    archive.add_listing("/tags/Fluff/works", &[51, 52, 53, 54, 55, 56, 57]);
+    let (client, _) = client_for(&archive);
+    let listing = WorkListing::Tag {
+        tag: "Fluff".to_owned(),
+    };
+
+    let limits = ListingLimits {
+        max_works: Some(3),
+        max_pages: None,
+    };
+    let works = works_in(&client, &listing, limits).await.unwrap();
+    assert_eq!(
+        works.iter().map(WorkId::id).collect::<Vec<_>>(),
+        [&51, &52, &53]
+    );
+    assert_eq!(archive.hits("/tags/Fluff/works"), 2);
+
+    let limits = ListingLimits {
+        max_works: None,
+        max_pages: Some(3),
+    };
+    let works = works_in(&client, &listing, limits).await.unwrap();
+    assert_eq!(works.len(), 3 * mock::PAGE_SIZE);
+    assert_eq!(archive.hits("/tags/Fluff/works"), 2 + 3);
+}
+
+#[test]
+fn work_refs_cover_every_link_shape() {
+    let base_url = Url::parse(DEFAULT_BASE_URL).unwrap();
+    let parse = |line| WorkRef::parse(line, &base_url).unwrap();
+    let work = |id| WorkRef::Work(WorkId::Bare(id));
+
+    assert_eq!(parse("123"), work(123));
+    assert_eq!(
+        parse(r#"{"id": 123, "timestamp": 1700000000}"#),
+        WorkRef::Work(WorkId::WithTimestamp {
+            id: 123,
+            timestamp: 1700000000
+        })
+    );
+    for line in [
+        "https://archiveofourown.org/works/123",
+        "http://archiveofourown.org/works/123/",
+        "  https://archiveofourown.org/works/123/chapters/456#workskin  ",
+        "https://archiveofourown.org/works/123?view_full_work=true",
+        "https://archiveofourown.org/collections/SomeFest/works/123",
+        "https://www.archiveofourown.org/works/123",
+        "https://archiveofourown.com/works/123",
+        "https://ao3.org/works/123",
+        "archiveofourown.org/works/123",
+    ] {
+        assert_eq!(parse(line), work(123), "{line}");
+    }
+    assert_eq!(
+        parse("https://archiveofourown.org/chapters/456"),
+        WorkRef::Chapter(456)
+    );
+    assert_eq!(
+        parse("https://archiveofourown.org/series/7"),
+        WorkRef::Series(7)
+    );
+    assert_eq!(
+        parse("https://archiveofourown.org/collections/SomeFest/works"),
+        WorkRef::Listing(WorkListing::Collection {
+            name: "SomeFest".to_owned()
+        })
+    );
+}
+
+#[test]
+fn work_refs_follow_the_base_url() {
+    let base_url = Url::parse("http://localhost:8080/ao3/").unwrap();
+
+    assert_eq!(
+        WorkRef::parse("http://localhost:8080/ao3/works/5", &base_url).unwrap(),
+        WorkRef::Work(WorkId::Bare(5))
+    );
+    // Another port is another archive
+    assert!(WorkRef::parse("http://localhost:8081/ao3/works/5", &base_url).is_err());
+}
+
+#[test]
+fn work_refs_reject_everything_else() {
+    let base_url = Url::parse(DEFAULT_BASE_URL).unwrap();
+
+    for line in [
+        "not a work",
+        "https://example.com/works/123",
+        "https://archiveofourown.org/works/abc",
+        "https://archiveofourown.org/users/someone/bookmarks",
+        "ftp://archiveofourown.org/works/123",
+        "-5",
+    ] {
+        assert!(WorkRef::parse(line, &base_url).is_err(), "{line}");
+    }
+}
+
+#[tokio::test]
+async fn chapters_resolve_to_their_work() {
+    let archive = MockArchive::start().await;
+    archive.add_work(61, MockWork::new("Chaptered", 1700000000));
+    archive.add_chapter(6102, 61);
+    let (client, _) = client_for(&archive);
+
+    assert_eq!(work_of_chapter(&client, 6102).await.unwrap(), 61);
+    assert!(work_of_chapter(&client, 6199).await.is_err());
+}
+
+/// Trimmed down from a real work page
+const WORK_PAGE: &str = r##"<!DOCTYPE html>
+<html><body><div id="main" class="works-show region">
+<div class="wrapper">
+  <dl class="work meta group">
+    <dt class="rating tags">Rating:</dt>
+    <dd class="rating tags"><ul class="commas"><li><a class="tag" href="/tags/Teen%20And%20Up%20Audiences/works">Teen And Up Audiences</a></li></ul></dd>
+    <dt class="warning tags">Archive Warning:</dt>
+    <dd class="warning tags"><ul class="commas"><li><a class="tag" href="/tags/No%20Archive%20Warnings%20Apply/works">No Archive Warnings Apply</a></li></ul></dd>
+    <dt class="category tags">Categories:</dt>
+    <dd class="category tags"><ul class="commas"><li><a class="tag" href="/tags/F*s*M/works">F/M</a></li><li><a class="tag" href="/tags/Gen/works">Gen</a></li></ul></dd>
+    <dt class="fandom tags">Fandom:</dt>
+    <dd class="fandom tags"><ul class="commas"><li><a class="tag" href="/tags/Original%20Work/works">Original Work</a></li></ul></dd>
+    <dt class="relationship tags">Relationship:</dt>
+    <dd class="relationship tags"><ul class="commas"><li><a class="tag" href="/tags/A*s*B/works">A/B</a></li></ul></dd>
+    <dt class="character tags">Characters:</dt>
+    <dd class="character tags"><ul class="commas"><li><a class="tag" href="/tags/A/works">A</a></li><li><a class="tag" href="/tags/B/works">B</a></li></ul></dd>
+    <dt class="freeform tags">Additional Tags:</dt>
+    <dd class="freeform tags"><ul class="commas"><li><a class="tag" href="/tags/Fluff/works">Fluff</a></li></ul></dd>
+    <dt class="language">Language:</dt>
+    <dd class="language" lang="en">
+      English
+    </dd>
+    <dt class="series">Series:</dt>
+    <dd class="series">
+      <span class="series"><a href="/series/7/navigate" class="previous">←</a> <span class="position">Part 2 of <a href="/series/7">The Long Way Round</a></span></span>
+    </dd>
+    <dt class="stats">Stats:</dt>
+    <dd class="stats"><dl class="stats">
+      <dt class="published">Published:</dt><dd class="published">2021-03-04</dd>
+      <dt class="status">Updated:</dt><dd class="status">2023-11-12</dd>
+      <dt class="words">Words:</dt><dd class="words">12,345</dd>
+      <dt class="chapters">Chapters:</dt><dd class="chapters"><a href="/works/123/chapters/9">3</a>/?</dd>
+      <dt class="kudos">Kudos:</dt><dd class="kudos">1,024</dd>
+      <dt class="hits">Hits:</dt><dd class="hits">20,480</dd>
+    </dl></dd>
+  </dl>
+</div>
+<ul class="work navigation actions">
+  <li class="download"><a href="#">Download</a>
+    <ul class="expandable secondary">
+      <li><a href="/downloads/123/A_Title.azw3?updated_at=1700000123">AZW3</a></li>
+      <li><a href="/downloads/123/A_Title.epub?updated_at=1700000123">EPUB</a></li>
+    </ul>
+  </li>
+</ul>
+<div id="workskin">
+  <div class="preface group">
+    <h2 class="title heading">
+      A   Title
+    </h2>
+    <h3 class="byline heading"><a rel="author" href="/users/one/pseuds/one">one</a>, <a rel="author" href="/users/two/pseuds/Second">Second (two)</a></h3>
+    <div class="summary module">
+      <h3 class="heading">Summary:</h3>
+      <blockquote class="userstuff"><p>First paragraph.</p><p>Second <i>paragraph</i>.</p></blockquote>
+    </div>
+  </div>
+</div>
+</div></body></html>"##;
+
+#[test]
+fn work_page_is_parsed_into_metadata() {
+    let work = metadata::parse_work_page(123, WORK_PAGE).unwrap();
+
+    assert_eq!(
+        work,
+        Work {
+            id: 123,
+            title: "A Title".to_owned(),
+            authors: vec!["one".to_owned(), "Second (two)".to_owned()],
+            rating: Some("Teen And Up Audiences".to_owned()),
+            warnings: vec!["No Archive Warnings Apply".to_owned()],
+            categories: vec!["F/M".to_owned(), "Gen".to_owned()],
+            fandoms: vec!["Original Work".to_owned()],
+            relationships: vec!["A/B".to_owned()],
+            characters: vec!["A".to_owned(), "B".to_owned()],
+            freeform_tags: vec!["Fluff".to_owned()],
+            language: Some("English".to_owned()),
+            series: vec![SeriesPosition {
+                id: 7,
+                title: "The Long Way Round".to_owned(),
+                position: 2,
+            }],
+            summary: Some("First paragraph.\n\nSecond paragraph.".to_owned()),
+            words: Some(12345),
+            chapters: 3,
+            total_chapters: None,
+            kudos: Some(1024),
+            hits: Some(20480),
+            published: chrono::NaiveDate::from_ymd_opt(2021, 3, 4),
+            updated: chrono::NaiveDate::from_ymd_opt(2023, 11, 12),
+            updated_at: 1700000123,
+        }
+    );
+}
+
+#[test]
+fn sparse_work_page_still_parses() {
+    let html = r#"<html><body><h2 class="title heading">Untitled</h2><h3 class="byline heading">Anonymous</h3>
+<li class="download"><ul><li><a href="/downloads/5/Untitled.pdf?updated_at=42">PDF</a></li></ul></li></body></html>"#;
+
+    let work = metadata::parse_work_page(5, html).unwrap();
+
+    assert_eq!(work.authors, ["Anonymous"]);
+    assert_eq!((work.chapters, work.total_chapters), (1, Some(1)));
+    assert_eq!((work.published, work.updated), (None, None));
+    assert_eq!(work.updated_at, 42);
+    assert!(metadata::parse_work_page(6, html).is_err());
+}
+
+#[tokio::test]
+async fn work_metadata_is_fetched_once() {
+    let archive = MockArchive::start().await;
+    archive.add_work(71, MockWork::new("Cached", 1700000000));
+    let (client, _) = client_for(&archive);
+
+    let work = work_metadata(&client, 71).await.unwrap();
+    assert_eq!(
+        (work.title.as_str(), work.updated_at),
+        ("Cached", 1700000000)
+    );
+    assert_eq!(
+        updated_at(&client, &WorkId::Bare(71)).await.unwrap(),
+        1700000000
+    );
+    assert_eq!(cached_work_metadata(&client, 71), Some(work));
+
+    assert_eq!(archive.hits("/works/71"), 1);
+}
+
+const MAINTENANCE_PAGE: &str =
+    "<!DOCTYPE html><html><body><h2>The archive is down for maintenance</h2></body></html>";
+
+#[tokio::test]
+async fn retries_invalid_downloads() {
+    let archive = MockArchive::start().await;
+    archive.add_work(5, MockWork::new("Down", 1700000000));
+    archive.script("/downloads/5/x.pdf", [Scripted::Body(MAINTENANCE_PAGE)]);
+    let (client, slept) = client_for(&archive);
+
+    let work = WorkId::WithTimestamp {
+        id: 5,
+        timestamp: 1700000000,
+    };
+    let bytes = download_bytes(&client, &work, Format::PDF, None)
+        .await
+        .unwrap();
+
+    assert!(bytes.starts_with(b"%PDF-"));
+    assert_eq!(archive.hits("/downloads/5/x.pdf"), 2);
+    assert_eq!(*slept.lock().unwrap(), [INVALID_DOWNLOAD_DELAY]);
+}
+
+#[tokio::test]
+async fn gives_up_on_invalid_downloads() {
+    let archive = MockArchive::start().await;
+    archive.add_work(5, MockWork::new("Down", 1700000000));
+    archive.script(
+        "/downloads/5/x.html",
+        std::iter::repeat_n(Scripted::Body(MAINTENANCE_PAGE), INVALID_DOWNLOAD_ATTEMPTS),
+    );
+    let (client, _) = client_for(&archive);
+
+    let work = WorkId::WithTimestamp {
+        id: 5,
+        timestamp: 1700000000,
+    };
+    let err = download_bytes(&client, &work, Format::HTML, None)
+        .await
+        .unwrap_err();
+
+    assert!(format!("{err:#}").contains("no work preface"), "{err:#}");
+    assert_eq!(
+        archive.hits("/downloads/5/x.html"),
+        INVALID_DOWNLOAD_ATTEMPTS
+    );
+}
+
+#[tokio::test]
+async fn refuses_downloads_over_max_size() {
+    let archive = MockArchive::start().await;
+    archive.add_work(5, MockWork::new("Huge", 1700000000));
+    let (client, _) = client_for(&archive);
+
+    let work = WorkId::WithTimestamp {
+        id: 5,
+        timestamp: 1700000000,
+    };
+    let err = download_bytes(&client, &work, Format::PDF, Some(100))
+        .await
+        .unwrap_err();
+    let bytes = download_bytes(&client, &work, Format::PDF, Some(1 << 20))
+        .await
+        .unwrap();
+
+    assert!(
+        format!("{err:#}").contains("bigger than the maximum size of 100 bytes"),
+        "{err:#}"
+    );
+    assert!(bytes.starts_with(b"%PDF-"));
+}
+
+#[tokio::test]
+async fn adult_works_skip_the_content_warning() {
+    let archive = MockArchive::start().await;
+    archive.add_work(72, MockWork::new("Explicit", 1700000000).adult());
+    let (client, _) = client_for(&archive);
+
+    let work = work_metadata(&client, 72).await.unwrap();
+
+    assert_eq!(work.title, "Explicit");
+    assert_eq!(archive.requests(), ["GET /works/72?view_adult=true"]);
+}
+
+#[tokio::test]
+async fn restricted_works_need_logging_in() {
+    let archive = MockArchive::start().await;
+    archive.add_work(73, MockWork::new("Members Only", 1700000000).restricted());
+    let (client, _) = client_for(&archive);
+
+    let err = work_metadata(&client, 73).await.unwrap_err();
+    assert!(
+        matches!(err.downcast_ref(), Some(Error::Restricted)),
+        "{err:#}"
+    );
+
+    login(&client, mock::USERNAME, mock::PASSWORD)
+        .await
+        .unwrap();
+    assert_eq!(
+        work_metadata(&client, 73).await.unwrap().title,
+        "Members Only"
+    );
+}
+
+#[test]
+fn login_path_keeps_the_base_url_path() {
+    let client = make_client(
+        &"https://example.org/archive/".parse().unwrap(),
+        Clock::Tokio,
+    )
+    .unwrap();
+
+    assert_eq!(client.login_path(), "/archive/users/login");
+}
@@ -1,17 +1,62 @@
-use serde::Deserialize;
+use anyhow::{Context, bail};
+use chrono::NaiveDate;
+use reqwest::Url;
+use serde::{Deserialize, Serialize};
 
 #[derive(Deserialize)]
 pub struct AuthenticityToken {
     pub token: String,
 }
 
-#[derive(Deserialize, Clone, Copy)]
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, PartialEq)]
 #[serde(untagged)]
 pub enum WorkId {
     Bare(usize),
     WithTimestamp { id: usize, timestamp: usize },
 }
 
+/// Where a work sits in a series.
+#[derive(Deserialize, Serialize, Clone, Debug, PartialEq)]
+pub struct SeriesPosition {
+    /// The series' ID
+    pub id: usize,
+    pub title: String,
+    /// Counting from 1
+    pub position: usize,
+}
+
+/// Everything the work page says about a work.
+#[derive(Deserialize, Serialize, Clone, Debug, PartialEq)]
+pub struct Work {
+    pub id: usize,
+    pub title: String,
+    /// Pseuds, as displayed in the byline (or "Anonymous")
+    pub authors: Vec<String>,
+    pub rating: Option<String>,
+    pub warnings: Vec<String>,
+    pub categories: Vec<String>,
+    pub fandoms: Vec<String>,
+    pub relationships: Vec<String>,
+    pub characters: Vec<String>,
+    pub freeform_tags: Vec<String>,
+    pub language: Option<String>,
+    pub series: Vec<SeriesPosition>,
+    /// Paragraphs are separated by blank lines
+    pub summary: Option<String>,
+    pub words: Option<usize>,
+    /// How many chapters have been posted so far
+    pub chapters: usize,
+    /// How many chapters the work will have, if the author has said
+    pub total_chapters: Option<usize>,
+    pub kudos: Option<usize>,
+    pub hits: Option<usize>,
+    pub published: Option<NaiveDate>,
+    /// The date shown as "Updated" or "Completed", or the published date if there is neither
+    pub updated: Option<NaiveDate>,
+    /// The timestamp the archive uses to version downloads
+    pub updated_at: usize,
+}
+
 impl WorkId {
     pub fn id(&self) -> &usize {
         match self {
@@ -20,3 +65,189 @@ impl WorkId {
         }
     }
 }
+
+/// Hosts that serve the archive, besides whichever base URL it is being downloaded from
+static ARCHIVE_HOSTS: &[&str] = &[
+    "archiveofourown.org",
+    "archiveofourown.com",
+    "archiveofourown.net",
+    "archive.transformativeworks.org",
+    "ao3.org",
+];
+
+/// One line of a works file: something that refers to one or more works.
+#[derive(Clone, Debug, PartialEq)]
+pub enum WorkRef {
+    Work(WorkId),
+    /// A chapter, linked without its work's ID (`/chapters/{id}`)
+    Chapter(usize),
+    Series(usize),
+    Listing(WorkListing),
+}
+
+impl WorkRef {
+    /// Parses a work ID, a JSON [`WorkId`] or a link to the archive at `base_url` (or one of its
+    /// mirrors), with or without a scheme.
+    pub fn parse(line: &str, base_url: &Url) -> anyhow::Result<WorkRef> {
+        let line = line.trim();
+        if let Ok(work_id) = serde_json::from_str(line) {
+            return Ok(WorkRef::Work(work_id));
+        }
+        if let Ok(id) = line.parse::<usize>() {
+            return Ok(WorkRef::Work(WorkId::Bare(id)));
+        }
+
+        let url = if line.contains("://") {
+            Url::parse(line)
+        } else {
+            Url::parse(&format!("https://{}", line))
+        }
+        .context("Not a work ID or a URL")?;
+        if !matches!(url.scheme(), "http" | "https") {
+            bail!("Not an HTTP(S) URL");
+        }
+        let host = url.host_str().unwrap_or_default();
+        let path = if url.host_str() == base_url.host_str()
+            && url.port_or_known_default() == base_url.port_or_known_default()
+        {
+            // The base URL may itself have a path, e.g. behind a reverse proxy
+            url.path()
+                .strip_prefix(base_url.path().trim_end_matches('/'))
+                .unwrap_or(url.path())
+        } else if ARCHIVE_HOSTS.contains(&host.strip_prefix("www.").unwrap_or(host)) {
+            url.path()
+        } else {
+            bail!("Not a link to the archive");
+        };
+
+        let segments = path
+            .trim_end_matches('/')
+            .strip_prefix('/')
+            .unwrap_or_default()
+            .split('/')
+            .collect::<Vec<_>>();
+        let id = |segment: &str| {
+            segment
+                .parse::<usize>()
+                .with_context(|| format!("'{}' is not an ID", segment))
+        };
+        match segments.as_slice() {
+            // Also covers chapter links and `?view_full_work=true`
+            ["works", work, ..] | ["collections", _, "works", work, ..] if *work != "search" => {
+                Ok(WorkRef::Work(WorkId::Bare(id(work)?)))
+            }
+            ["chapters", chapter, ..] => Ok(WorkRef::Chapter(id(chapter)?)),
+            ["series", series, ..] => Ok(WorkRef::Series(id(series)?)),
+            _ => {
+                let path = match url.query() {
+                    Some(query) => format!("{}?{}", path, query),
+                    None => path.to_owned(),
+                };
+                WorkListing::from_path(&path)
+                    .map(WorkRef::Listing)
+                    .context("Not a link to a work, series or listing of works")
+            }
+        }
+    }
+}
+
+/// A page on the archive that lists works, and can be expanded into them.
+#[derive(Clone, Debug, PartialEq)]
+pub enum WorkListing {
+    /// `/users/{user}/works`
+    UserWorks { user: String },
+    /// `/users/{user}/pseuds/{pseud}/works`
+    PseudWorks { user: String, pseud: String },
+    /// `/users/{user}/gifts`
+    Gifts { user: String },
+    /// `/tags/{tag}/works`, with the tag as it appears in the URL
+    Tag { tag: String },
+    /// `/collections/{name}/works`
+    Collection { name: String },
+    /// `/works/search?{query}`
+    Search { query: String },
+    /// `/works?{query}`, i.e. a works index narrowed down with the filters sidebar
+    Filtered { query: String },
+}
+
+impl WorkListing {
+    /// Recognises the listing at `path` (relative to the archive's base URL), ignoring any
+    /// fragment and the query string's `page`.
+    pub fn from_path(path: &str) -> Option<WorkListing> {
+        let path = path.split('#').next()?;
+        let (path, query) = path.split_once('?').unwrap_or((path, ""));
+        let query = query
+            .split('&')
+            .filter(|param| !param.is_empty() && !param.starts_with("page="))
+            .collect::<Vec<_>>()
+            .join("&");
+        let segments = path
+            .trim_end_matches('/')
+            .strip_prefix('/')?
+            .split('/')
+            .collect::<Vec<_>>();
+        match segments.as_slice() {
+            ["users", user, "works"] => Some(WorkListing::UserWorks {
+                user: user.to_string(),
+            }),
+            ["users", user, "pseuds", pseud, "works"] => Some(WorkListing::PseudWorks {
+                user: user.to_string(),
+                pseud: pseud.to_string(),
+            }),
+            ["users", user, "gifts"] => Some(WorkListing::Gifts {
+                user: user.to_string(),
+            }),
+            ["tags", tag, "works"] => Some(WorkListing::Tag {
+                tag: tag.to_string(),
+            }),
+            ["collections", name, "works"] => Some(WorkListing::Collection {
+                name: name.to_string(),
+            }),
+            ["works", "search"] if !query.is_empty() => Some(WorkListing::Search { query }),
+            // Without filters, this would be every work on the archive
+            ["works"] if !query.is_empty() => Some(WorkListing::Filtered { query }),
+            _ => None,
+        }
+    }
+
+    /// The listing's path, relative to the archive's base URL
+    pub fn path(&self) -> String {
+        match self {
+            WorkListing::UserWorks { user } => format!("/users/{}/works", user),
+            WorkListing::PseudWorks { user, pseud } => {
+                format!("/users/{}/pseuds/{}/works", user, pseud)
+            }
+            WorkListing::Gifts { user } => format!("/users/{}/gifts", user),
+            WorkListing::Tag { tag } => format!("/tags/{}/works", tag),
+            WorkListing::Collection { name } => format!("/collections/{}/works", name),
+            WorkListing::Search { query } => format!("/works/search?{}", query),
+            WorkListing::Filtered { query } => format!("/works?{}", query),
+        }
+    }
+}
+
+impl std::fmt::Display for WorkListing {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            WorkListing::UserWorks { user } => write!(f, "works by {}", user),
+            WorkListing::PseudWorks { user, pseud } => {
+                write!(f, "works by {} (as {})", user, pseud)
+            }
+            WorkListing::Gifts { user } => write!(f, "gifts for {}", user),
+            WorkListing::Tag { tag } => write!(f, "works tagged {}", tag),
+            WorkListing::Collection { name } => write!(f, "works in collection {}", name),
+            WorkListing::Search { query } => write!(f, "search results for {}", query),
+            WorkListing::Filtered { query } => write!(f, "works filtered by {}", query),
+        }
+    }
+}
+
+/// How much of a listing to fetch. Listings such as a popular tag's works can run to thousands of
+/// pages.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct ListingLimits {
+    /// Stop after this many works
+    pub max_works: Option<usize>,
+    /// Stop after this many pages
+    pub max_pages: Option<usize>,
+}
@@ -0,0 +1,307 @@
+//! Writing files and directories so that they only ever appear complete.
+//!
+//! Everything is first written to a temporary next to its final path, synced to disk and then
+//! renamed into place, so a crash, a full disk or Ctrl-C leaves at worst a temporary behind, which
+//! [`clean_up`] removes on the next run.
+
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{self, Write},
+    path::{Path, PathBuf},
+};
+
+use anyhow::Context;
+
+#[cfg(test)]
+mod tests;
+
+/// What the names of temporaries end in, so that they can be told apart from real downloads
+const TEMP_SUFFIX: &str = ".ao3dl-tmp";
+
+/// Name of the file a run locks in its output directory
+pub const LOCK_FILE_NAME: &str = ".ao3dl-lock";
+
+/// Where whatever is bound for `path` is written first.
+///
+/// Named after a hash of the final name rather than the name itself, which may already be as long
+/// as the file system allows.
+fn temp_path(path: &Path, kind: &str) -> PathBuf {
+    let name = path.file_name().unwrap_or_default().as_encoded_bytes();
+    let hash = crate::library::sha256(name);
+    path.with_file_name(format!(".{}{}{}", &hash[..16], kind, TEMP_SUFFIX))
+}
+
+/// A file that only appears at its path once [`AtomicFile::commit`] (or [`AtomicFile::commit_as`])
+/// is called. Dropping it before then removes what was written.
+#[derive(Debug)]
+pub struct AtomicFile {
+    file: File,
+    temp: PathBuf,
+    path: PathBuf,
+    committed: bool,
+}
+
+impl AtomicFile {
+    pub fn create(path: &Path) -> anyhow::Result<AtomicFile> {
+        AtomicFile::open(path, OpenOptions::new())
+    }
+
+    /// Like [`AtomicFile::create`], but for secrets: on Unix, only the current user can read or
+    /// write the file.
+    pub fn create_private(path: &Path) -> anyhow::Result<AtomicFile> {
+        let mut options = OpenOptions::new();
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::OpenOptionsExt;
+            options.mode(0o600);
+        }
+        let file = AtomicFile::open(path, options)?;
+        // A stale temporary keeps whatever mode it was created with
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            file.file
+                .set_permissions(fs::Permissions::from_mode(0o600))
+                .with_context(|| format!("Cannot restrict access to '{}'", file.temp.display()))?;
+        }
+        Ok(file)
+    }
+
+    fn open(path: &Path, mut options: OpenOptions) -> anyhow::Result<AtomicFile> {
+        let temp = temp_path(path, "");
+        // Readable too, so that downloads can be checked before they are committed
+        let file = options
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(&temp)
+            .with_context(|| format!("Cannot create temporary file '{}'", temp.display()))?;
+        Ok(AtomicFile {
+            file,
+            temp,
+            path: path.to_owned(),
+            committed: false,
+        })
+    }
+
+    /// The temporary file, for reading back what was written so far
+    pub fn file_mut(&mut self) -> &mut File {
+        &mut self.file
+    }
+
+    /// Syncs what was written to disk, and moves it into place over whatever was there before.
+    pub fn commit(self) -> anyhow::Result<()> {
+        let path = self.path.clone();
+        self.commit_as(&path)
+    }
+
+    /// Like [`AtomicFile::commit`], but moves it to `path` instead, which must be on the same file
+    /// system. For when what was written decides where it goes.
+    pub fn commit_as(mut self, path: &Path) -> anyhow::Result<()> {
+        self.file
+            .sync_all()
+            .with_context(|| format!("Cannot sync '{}' to disk", self.temp.display()))?;
+        fs::rename(&self.temp, path).with_context(|| {
+            format!(
+                "Cannot move '{}' into place at '{}'",
+                self.temp.display(),
+                path.display()
+            )
+        })?;
+        self.committed = true;
+        sync_parent(path)
+    }
+}
+
+impl Write for AtomicFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.file.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
+
+impl Drop for AtomicFile {
+    fn drop(&mut self) {
+        if !self.committed {
+            let _ = fs::remove_file(&self.temp);
+        }
+    }
+}
+
+/// Writes `contents` to the file at `path`, all at once or not at all.
+pub fn write(path: &Path, contents: impl AsRef<[u8]>) -> anyhow::Result<()> {
+    let mut file = AtomicFile::create(path)?;
+    file.write_all(contents.as_ref())
+        .with_context(|| format!("Cannot write to '{}'", file.temp.display()))?;
+    file.commit()
+}
+
+/// Makes the directory at `path` by having `fill` fill in an empty temporary directory, then moving
+/// it into place over whatever was there before.
+pub fn write_dir(
+    path: &Path,
+    fill: impl FnOnce(&Path) -> anyhow::Result<()>,
+) -> anyhow::Result<()> {
+    let temp = temp_path(path, "");
+    if temp.exists() {
+        fs::remove_dir_all(&temp)
+            .with_context(|| format!("Cannot remove stale '{}'", temp.display()))?;
+    }
+    fs::create_dir(&temp)
+        .with_context(|| format!("Cannot create temporary directory '{}'", temp.display()))?;
+
+    let filled = fill(&temp).and_then(|()| {
+        sync_tree(&temp).with_context(|| format!("Cannot sync '{}' to disk", temp.display()))
+    });
+    if let Err(e) = filled {
+        let _ = fs::remove_dir_all(&temp);
+        return Err(e);
+    }
+
+    // Directories can't be renamed over each other, so the old one is moved aside first
+    let old = temp_path(path, ".old");
+    let replacing = path.exists();
+    if replacing {
+        fs::rename(path, &old)
+            .with_context(|| format!("Cannot move '{}' out of the way", path.display()))?;
+    }
+    fs::rename(&temp, path).with_context(|| {
+        format!(
+            "Cannot move '{}' into place at '{}'",
+            temp.display(),
+            path.display()
+        )
+    })?;
+    if replacing {
+        remove(&old).with_context(|| format!("Cannot remove '{}'", old.display()))?;
+    }
+    sync_parent(path)
+}
+
+/// Claims `dir` for this run until the returned file is dropped, so that runs in the same directory
+/// can tell whether the temporaries in it are still being written. Returns nothing if another run
+/// already has it, or it can't be locked at all.
+pub fn lock(dir: &Path) -> Option<File> {
+    let path = dir.join(LOCK_FILE_NAME);
+    let file = match OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(&path)
+    {
+        Ok(file) => file,
+        Err(e) => {
+            log::warn!(
+                "Cannot create lock file '{}', because {}",
+                path.display(),
+                e
+            );
+            return None;
+        }
+    };
+    match file.try_lock() {
+        Ok(()) => Some(file),
+        Err(fs::TryLockError::WouldBlock) => None,
+        Err(fs::TryLockError::Error(e)) => {
+            log::warn!("Cannot lock '{}', because {}", path.display(), e);
+            None
+        }
+    }
+}
+
+/// Removes every temporary left behind in `dir` by an interrupted run. Only `dir` itself is
+/// searched, not its subdirectories, and anything that can't be read or removed is only warned
+/// about.
+pub fn clean_up(dir: &Path) {
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return,
+        Err(e) => {
+            log::warn!(
+                "Cannot clean up after an interrupted run in '{}', because {}",
+                dir.display(),
+                e
+            );
+            return;
+        }
+    };
+    for entry in entries {
+        let entry = match entry {
+            Ok(entry) => entry,
+            Err(e) => {
+                log::warn!(
+                    "Cannot clean up after an interrupted run in '{}', because {}",
+                    dir.display(),
+                    e
+                );
+                return;
+            }
+        };
+        let is_temp = entry
+            .file_name()
+            .to_str()
+            .is_some_and(|name| name.starts_with('.') && name.ends_with(TEMP_SUFFIX));
+        if !is_temp {
+            continue;
+        }
+        let path = entry.path();
+        log::info!(
+            "Removing '{}', left behind by an interrupted run",
+            path.display()
+        );
+        if let Err(e) = remove(&path) {
+            log::warn!("Cannot remove '{}', because {}", path.display(), e);
+        }
+    }
+}
+
+fn remove(path: &Path) -> io::Result<()> {
+    if fs::symlink_metadata(path)?.is_dir() {
+        fs::remove_dir_all(path)
+    } else {
+        fs::remove_file(path)
+    }
+}
+
+/// Syncs every file and directory under `path` to disk.
+fn sync_tree(path: &Path) -> io::Result<()> {
+    if fs::symlink_metadata(path)?.is_dir() {
+        for entry in fs::read_dir(path)? {
+            sync_tree(&entry?.path())?;
+        }
+        sync_dir(path)
+    } else {
+        File::open(path)?.sync_all()
+    }
+}
+
+/// Syncs the directory `path` is in, so that renaming it into place survives a crash.
+fn sync_parent(path: &Path) -> anyhow::Result<()> {
+    match path.parent() {
+        Some(parent) => {
+            sync_dir(parent).with_context(|| format!("Cannot sync '{}' to disk", parent.display()))
+        }
+        None => Ok(()),
+    }
+}
+
+#[cfg(unix)]
+fn sync_dir(dir: &Path) -> io::Result<()> {
+    // An empty parent is the current directory
+    let dir = if dir.as_os_str().is_empty() {
+        Path::new(".")
+    } else {
+        dir
+    };
+    File::open(dir)?.sync_all()
+}
+
+/// Windows can't open directories as files, and syncs renames along with the files themselves
+#[cfg(not(unix))]
+fn sync_dir(_dir: &Path) -> io::Result<()> {
+    Ok(())
+}
@@ -0,0 +1,138 @@
+use super::*;
+
+fn names_in(dir: &Path) -> Vec<String> {
+    let mut names = fs::read_dir(dir)
+        .unwrap()
+        .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+        .collect::<Vec<_>>();
+    names.sort();
+    names
+}
+
+#[test]
+fn write_replaces_the_file() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("a.epub");
+    fs::write(&path, "old").unwrap();
+
+    write(&path, "new").unwrap();
+
+    assert_eq!(fs::read_to_string(&path).unwrap(), "new");
+    assert_eq!(names_in(dir.path()), ["a.epub"]);
+}
+
+#[test]
+fn uncommitted_files_never_appear() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("a.epub");
+
+    let mut file = AtomicFile::create(&path).unwrap();
+    file.write_all(b"half").unwrap();
+    assert!(!path.exists());
+    drop(file);
+
+    assert!(names_in(dir.path()).is_empty());
+}
+
+#[test]
+fn write_dir_replaces_the_directory() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("a.epub");
+    fs::create_dir(&path).unwrap();
+    fs::write(path.join("stale.xhtml"), "old").unwrap();
+
+    write_dir(&path, |temp| {
+        fs::create_dir(temp.join("OEBPS"))?;
+        fs::write(temp.join("OEBPS").join("content.opf"), "new")?;
+        Ok(())
+    })
+    .unwrap();
+
+    assert_eq!(names_in(&path), ["OEBPS"]);
+    assert_eq!(names_in(dir.path()), ["a.epub"]);
+}
+
+#[test]
+fn failed_write_dir_leaves_the_old_directory() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("a.epub");
+    fs::create_dir(&path).unwrap();
+    fs::write(path.join("content.opf"), "old").unwrap();
+
+    let result = write_dir(&path, |temp| {
+        fs::write(temp.join("content.opf"), "half")?;
+        anyhow::bail!("Disk full")
+    });
+
+    assert!(result.is_err());
+    assert_eq!(fs::read_to_string(path.join("content.opf")).unwrap(), "old");
+    assert_eq!(names_in(dir.path()), ["a.epub"]);
+}
+
+#[test]
+fn names_as_long_as_allowed_still_get_temporaries() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join(format!("{}.epub", "a".repeat(250)));
+
+    write(&path, "new").unwrap();
+    write_dir(&dir.path().join("b".repeat(255)), |_| Ok(())).unwrap();
+    write_dir(&dir.path().join("b".repeat(255)), |_| Ok(())).unwrap();
+
+    assert_eq!(fs::read_to_string(&path).unwrap(), "new");
+    assert_eq!(names_in(dir.path()).len(), 2);
+}
+
+#[test]
+fn clean_up_removes_only_temporaries() {
+    let dir = tempfile::tempdir().unwrap();
+    let nested = dir.path().join("Fandom");
+    fs::create_dir(&nested).unwrap();
+    fs::write(dir.path().join("a.epub"), "done").unwrap();
+    fs::write(dir.path().join(".a.epub.ao3dl-tmp"), "half").unwrap();
+    fs::write(nested.join(".b.pdf.ao3dl-tmp"), "half").unwrap();
+    fs::create_dir(nested.join(".c.epub.old.ao3dl-tmp")).unwrap();
+    fs::write(nested.join(".hidden"), "mine").unwrap();
+
+    clean_up(dir.path());
+
+    assert_eq!(names_in(dir.path()), ["Fandom", "a.epub"]);
+    // Subdirectories are only cleaned up when asked for
+    assert_eq!(names_in(&nested).len(), 3);
+    clean_up(&nested);
+    assert_eq!(names_in(&nested), [".hidden"]);
+}
+
+#[test]
+fn clean_up_of_missing_directories_does_nothing() {
+    let dir = tempfile::tempdir().unwrap();
+
+    clean_up(&dir.path().join("missing"));
+}
+
+#[test]
+fn only_one_run_can_lock_a_directory() {
+    let dir = tempfile::tempdir().unwrap();
+
+    let lock = super::lock(dir.path()).unwrap();
+    assert!(super::lock(dir.path()).is_none());
+    drop(lock);
+
+    assert!(super::lock(dir.path()).is_some());
+}
+
+#[cfg(unix)]
+#[test]
+fn private_files_are_only_ever_readable_by_their_owner() {
+    use std::os::unix::fs::PermissionsExt;
+
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("session.json");
+    let mode = |path: &Path| fs::metadata(path).unwrap().permissions().mode() & 0o777;
+
+    let mut file = AtomicFile::create_private(&path).unwrap();
+    assert_eq!(mode(&file.temp), 0o600);
+    file.write_all(b"secret").unwrap();
+    file.commit().unwrap();
+
+    assert_eq!(mode(&path), 0o600);
+}
@@ -0,0 +1,302 @@
+//! Works out which account to log in with, from wherever the user keeps their password.
+//!
+//! Each source is tried in turn: `--password-stdin`, `--password-command`, the `USERNAME` and
+//! `PASSWORD` environment variables, the config file, `.netrc`, and finally a prompt, but only when
+//! there is a terminal to prompt on.
+
+use std::{
+    env, fs,
+    io::{self, BufRead, Write},
+    path::{Path, PathBuf},
+    process::{Command, Stdio},
+};
+
+use anyhow::{Context, bail};
+use serde::Deserialize;
+
+#[cfg(test)]
+mod tests;
+
+/// Where the config file is looked for, under the user's config directory
+const CONFIG_FILE_NAME: &str = "ao3dl/config.json";
+
+/// Where the login session is kept between runs, under the user's state directory
+const SESSION_FILE_NAME: &str = "ao3dl/session.json";
+
+/// Where to look for the username and password.
+pub struct Sources {
+    /// The archive's host name, which `.netrc` entries are matched against
+    pub host: String,
+    /// From the `USERNAME` environment variable
+    pub username: Option<String>,
+    /// From the `PASSWORD` environment variable
+    pub password: Option<String>,
+    /// Whether to read the password from the first line of standard input
+    pub password_stdin: bool,
+    /// A command that prints the password, such as `pass show ao3`
+    pub password_command: Option<String>,
+    pub config_file: Option<PathBuf>,
+    /// Whether `config_file` was asked for, so that it has to exist
+    pub config_file_required: bool,
+    pub netrc_file: Option<PathBuf>,
+    /// Whether there is someone at a terminal to ask for whatever is still missing
+    pub interactive: bool,
+}
+
+/// The config file, a JSON object with any of these keys.
+#[derive(Deserialize, Default, Debug, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    pub username: Option<String>,
+    pub password: Option<String>,
+    pub password_command: Option<String>,
+}
+
+impl Config {
+    /// Loads the config file at `path`, or returns an empty config if there isn't one and it
+    /// wasn't `required`.
+    pub fn load(path: &Path, required: bool) -> anyhow::Result<Config> {
+        let contents = match fs::read_to_string(path) {
+            Ok(contents) => contents,
+            Err(e) if e.kind() == io::ErrorKind::NotFound && !required => {
+                log::debug!("No config file at '{}'", path.display());
+                return Ok(Config::default());
+            }
+            Err(e) => {
+                return Err(e)
+                    .with_context(|| format!("Cannot read config file '{}'", path.display()));
+            }
+        };
+        serde_json::from_str(&contents)
+            .with_context(|| format!("Cannot parse config file '{}'", path.display()))
+    }
+}
+
+/// A `machine` (or `default`) entry of a `.netrc` file.
+#[derive(Debug, PartialEq)]
+pub struct NetrcEntry {
+    /// `None` for the `default` entry
+    pub machine: Option<String>,
+    pub login: Option<String>,
+    pub password: Option<String>,
+}
+
+/// Parses a `.netrc` file, skipping macro definitions and anything else that isn't an entry.
+pub fn parse_netrc(contents: &str) -> Vec<NetrcEntry> {
+    let mut tokens = Vec::new();
+    let mut in_macro = false;
+    for line in contents.lines() {
+        // Macros run until the next blank line
+        if in_macro {
+            in_macro = !line.trim().is_empty();
+            continue;
+        }
+        for token in line.split_whitespace() {
+            if token == "macdef" {
+                in_macro = true;
+                break;
+            }
+            tokens.push(token);
+        }
+    }
+
+    let mut entries = Vec::<NetrcEntry>::new();
+    let mut tokens = tokens.into_iter();
+    while let Some(token) = tokens.next() {
+        match token {
+            "machine" => entries.push(NetrcEntry {
+                machine: tokens.next().map(str::to_owned),
+                login: None,
+                password: None,
+            }),
+            "default" => entries.push(NetrcEntry {
+                machine: None,
+                login: None,
+                password: None,
+            }),
+            "login" | "password" | "account" => {
+                let value = tokens.next().map(str::to_owned);
+                let Some(entry) = entries.last_mut() else {
+                    continue;
+                };
+                match token {
+                    "login" => entry.login = value,
+                    "password" => entry.password = value,
+                    _ => {}
+                }
+            }
+            _ => log::debug!("Ignoring unexpected token in .netrc: {}", token),
+        }
+    }
+    entries
+}
+
+/// The entry for `host`, or else the `default` entry.
+fn netrc_entry<'a>(entries: &'a [NetrcEntry], host: &str) -> Option<&'a NetrcEntry> {
+    entries
+        .iter()
+        .find(|entry| {
+            entry
+                .machine
+                .as_deref()
+                .is_some_and(|machine| machine.eq_ignore_ascii_case(host))
+        })
+        .or_else(|| entries.iter().find(|entry| entry.machine.is_none()))
+}
+
+impl Sources {
+    /// Finds the username and password, reading `stdin` for `--password-stdin` and prompts.
+    pub fn resolve(self, mut stdin: impl BufRead) -> anyhow::Result<(String, String)> {
+        let config = match &self.config_file {
+            Some(path) => Config::load(path, self.config_file_required)?,
+            None => Config::default(),
+        };
+        let netrc = match &self.netrc_file {
+            Some(path) => match fs::read_to_string(path) {
+                Ok(contents) => parse_netrc(&contents),
+                Err(e) if e.kind() == io::ErrorKind::NotFound => Vec::new(),
+                Err(e) => {
+                    return Err(e).with_context(|| format!("Cannot read '{}'", path.display()));
+                }
+            },
+            None => Vec::new(),
+        };
+        let netrc = netrc_entry(&netrc, &self.host);
+
+        let mut username = self.username.or(config.username);
+        if username.is_none() {
+            username = netrc.and_then(|entry| entry.login.clone());
+        }
+        let username = match username {
+            Some(username) => username,
+            // Standard input is taken when the password came from it
+            None if self.interactive && !self.password_stdin => {
+                print!("Username? ");
+                io::stdout().flush().context("Cannot prompt for username")?;
+                let mut line = String::new();
+                stdin.read_line(&mut line).context("Cannot read username")?;
+                line.trim_end_matches(['\r', '\n']).to_owned()
+            }
+            None => bail!(
+                "No username to log in with. Set USERNAME, add it to the config file or \
+                 ~/.netrc, or use --no-login"
+            ),
+        };
+
+        // A password from .netrc is only any good for the login it was saved with
+        let netrc_password = netrc
+            .filter(|entry| {
+                entry.login.is_none() || entry.login.as_deref() == Some(username.as_str())
+            })
+            .and_then(|entry| entry.password.clone());
+
+        let password = if self.password_stdin {
+            let mut line = String::new();
+            stdin
+                .read_line(&mut line)
+                .context("Cannot read password from standard input")?;
+            Some(line.trim_end_matches(['\r', '\n']).to_owned())
+                .filter(|password| !password.is_empty())
+                .context("Got no password on standard input")?
+        } else if let Some(command) = &self.password_command {
+            run_password_command(command)?
+        } else if let Some(password) = self.password {
+            password
+        } else if let Some(command) = &config.password_command {
+            run_password_command(command)?
+        } else if let Some(password) = config.password.or(netrc_password) {
+            password
+        } else if self.interactive {
+            rpassword::prompt_password("Password? ").context("Cannot read password")?
+        } else {
+            bail!(
+                "No password to log in with. Set PASSWORD, pass --password-stdin or \
+                 --password-command, add it to the config file or ~/.netrc, or use --no-login"
+            );
+        };
+
+        Ok((username, password))
+    }
+}
+
+/// Runs `command` with the system shell, and returns the first line it prints.
+pub fn run_password_command(command: &str) -> anyhow::Result<String> {
+    log::debug!("Running password command `{}`", command);
+
+    #[cfg(unix)]
+    let mut shell = {
+        let mut shell = Command::new("sh");
+        shell.arg("-c");
+        shell
+    };
+    #[cfg(windows)]
+    let mut shell = {
+        let mut shell = Command::new("cmd");
+        shell.arg("/C");
+        shell
+    };
+    // Password managers may ask for a passphrase, so they get the terminal
+    let output = shell
+        .arg(command)
+        .stdin(Stdio::inherit())
+        .stderr(Stdio::inherit())
+        .output()
+        .with_context(|| format!("Cannot run password command `{}`", command))?;
+    if !output.status.success() {
+        bail!("Password command `{}` failed ({})", command, output.status);
+    }
+
+    let stdout = String::from_utf8(output.stdout)
+        .with_context(|| format!("Password command `{}` printed invalid UTF-8", command))?;
+    let password = stdout.lines().next().unwrap_or_default();
+    if password.is_empty() {
+        bail!("Password command `{}` printed no password", command);
+    }
+    Ok(password.to_owned())
+}
+
+/// `~/.config/ao3dl/config.json`, or wherever `XDG_CONFIG_HOME` (or `APPDATA` on Windows) says.
+pub fn default_config_file() -> Option<PathBuf> {
+    let config_dir = env::var_os("XDG_CONFIG_HOME")
+        .filter(|dir| !dir.is_empty())
+        .map(PathBuf::from)
+        .or_else(|| {
+            if cfg!(windows) {
+                env::var_os("APPDATA").map(PathBuf::from)
+            } else {
+                home_dir().map(|home| home.join(".config"))
+            }
+        })?;
+    Some(config_dir.join(CONFIG_FILE_NAME))
+}
+
+/// `~/.local/state/ao3dl/session.json`, or wherever `XDG_STATE_HOME` (or `LOCALAPPDATA` on Windows)
+/// says. Kept out of the output directory, which may well be synced or shared.
+pub fn default_session_file() -> Option<PathBuf> {
+    let state_dir = env::var_os("XDG_STATE_HOME")
+        .filter(|dir| !dir.is_empty())
+        .map(PathBuf::from)
+        .or_else(|| {
+            if cfg!(windows) {
+                env::var_os("LOCALAPPDATA").map(PathBuf::from)
+            } else {
+                home_dir().map(|home| home.join(".local").join("state"))
+            }
+        })?;
+    Some(state_dir.join(SESSION_FILE_NAME))
+}
+
+/// `~/.netrc`, unless the `NETRC` environment variable points elsewhere.
+pub fn default_netrc_file() -> Option<PathBuf> {
+    env::var_os("NETRC")
+        .filter(|path| !path.is_empty())
+        .map(PathBuf::from)
+        .or_else(|| home_dir().map(|home| home.join(".netrc")))
+}
+
+fn home_dir() -> Option<PathBuf> {
+    let var = if cfg!(windows) { "USERPROFILE" } else { "HOME" };
+    env::var_os(var)
+        .filter(|home| !home.is_empty())
+        .map(PathBuf::from)
+}
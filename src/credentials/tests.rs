@@ -0,0 +1,208 @@
+use super::*;
+
+const HOST: &str = "archiveofourown.org";
+
+/// Sources with nothing in them, looking for files in `dir`.
+fn sources(dir: &Path) -> Sources {
+    Sources {
+        host: HOST.to_owned(),
+        username: None,
+        password: None,
+        password_stdin: false,
+        password_command: None,
+        config_file: Some(dir.join("config.json")),
+        config_file_required: false,
+        netrc_file: Some(dir.join(".netrc")),
+        interactive: false,
+    }
+}
+
+fn resolve(sources: Sources) -> anyhow::Result<(String, String)> {
+    sources.resolve(io::empty())
+}
+
+#[test]
+fn netrc_entries_are_parsed() {
+    let netrc = "machine example.com login other password nope\n\
+                 macdef init\n\
+                 machine archiveofourown.org login fake\n\
+                 \n\
+                 machine archiveofourown.org\n  login reader\n  password hunter2\n\
+                 default login anyone password guest\n";
+
+    assert_eq!(
+        parse_netrc(netrc),
+        [
+            NetrcEntry {
+                machine: Some("example.com".to_owned()),
+                login: Some("other".to_owned()),
+                password: Some("nope".to_owned()),
+            },
+            NetrcEntry {
+                machine: Some(HOST.to_owned()),
+                login: Some("reader".to_owned()),
+                password: Some("hunter2".to_owned()),
+            },
+            NetrcEntry {
+                machine: None,
+                login: Some("anyone".to_owned()),
+                password: Some("guest".to_owned()),
+            },
+        ]
+    );
+}
+
+#[test]
+fn netrc_entry_for_the_archive_is_used() {
+    let dir = tempfile::tempdir().unwrap();
+    fs::write(
+        dir.path().join(".netrc"),
+        "default login anyone password guest\nmachine archiveofourown.org login reader password hunter2\n",
+    )
+    .unwrap();
+
+    assert_eq!(
+        resolve(sources(dir.path())).unwrap(),
+        ("reader".to_owned(), "hunter2".to_owned())
+    );
+}
+
+#[test]
+fn netrc_passwords_of_other_logins_are_ignored() {
+    let dir = tempfile::tempdir().unwrap();
+    fs::write(
+        dir.path().join(".netrc"),
+        "machine archiveofourown.org login reader password hunter2\n",
+    )
+    .unwrap();
+
+    let err = resolve(Sources {
+        username: Some("writer".to_owned()),
+        ..sources(dir.path())
+    })
+    .unwrap_err();
+
+    assert!(err.to_string().contains("No password"), "{err:#}");
+}
+
+#[test]
+fn environment_wins_over_config_file_and_netrc() {
+    let dir = tempfile::tempdir().unwrap();
+    fs::write(
+        dir.path().join("config.json"),
+        r#"{"username": "config", "password": "from config"}"#,
+    )
+    .unwrap();
+    fs::write(
+        dir.path().join(".netrc"),
+        "machine archiveofourown.org login netrc password netrc\n",
+    )
+    .unwrap();
+
+    assert_eq!(
+        resolve(sources(dir.path())).unwrap(),
+        ("config".to_owned(), "from config".to_owned())
+    );
+    assert_eq!(
+        resolve(Sources {
+            username: Some("env".to_owned()),
+            password: Some("from env".to_owned()),
+            ..sources(dir.path())
+        })
+        .unwrap(),
+        ("env".to_owned(), "from env".to_owned())
+    );
+}
+
+#[test]
+fn missing_config_file_is_only_an_error_when_asked_for() {
+    let dir = tempfile::tempdir().unwrap();
+
+    let err = resolve(Sources {
+        config_file_required: true,
+        ..sources(dir.path())
+    })
+    .unwrap_err();
+
+    assert!(
+        err.to_string().contains("Cannot read config file"),
+        "{err:#}"
+    );
+    assert!(Config::load(&dir.path().join("config.json"), false).is_ok());
+}
+
+#[test]
+fn unknown_config_keys_are_rejected() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("config.json");
+    fs::write(&path, r#"{"user": "reader"}"#).unwrap();
+
+    assert!(Config::load(&path, true).is_err());
+}
+
+#[test]
+fn password_is_read_from_stdin() {
+    let dir = tempfile::tempdir().unwrap();
+
+    let credentials = Sources {
+        username: Some("reader".to_owned()),
+        password: Some("ignored".to_owned()),
+        password_stdin: true,
+        ..sources(dir.path())
+    }
+    .resolve(io::Cursor::new("hunter2\nmore\n"))
+    .unwrap();
+
+    assert_eq!(credentials, ("reader".to_owned(), "hunter2".to_owned()));
+}
+
+#[test]
+fn empty_stdin_is_not_a_password() {
+    let dir = tempfile::tempdir().unwrap();
+
+    let err = resolve(Sources {
+        username: Some("reader".to_owned()),
+        password_stdin: true,
+        ..sources(dir.path())
+    })
+    .unwrap_err();
+
+    assert!(err.to_string().contains("no password"), "{err:#}");
+}
+
+#[test]
+fn nothing_to_go_on_fails_without_prompting() {
+    let dir = tempfile::tempdir().unwrap();
+
+    let err = resolve(Sources {
+        password: Some("hunter2".to_owned()),
+        ..sources(dir.path())
+    })
+    .unwrap_err();
+
+    assert!(err.to_string().contains("No username"), "{err:#}");
+    assert!(resolve(sources(dir.path())).is_err());
+}
+
+#[cfg(unix)]
+#[test]
+fn password_command_prints_the_password() {
+    let dir = tempfile::tempdir().unwrap();
+    fs::write(
+        dir.path().join("config.json"),
+        r#"{"username": "reader", "password_command": "printf 'hunter2\\nsecond line'"}"#,
+    )
+    .unwrap();
+
+    assert_eq!(
+        resolve(sources(dir.path())).unwrap(),
+        ("reader".to_owned(), "hunter2".to_owned())
+    );
+
+    let err = resolve(Sources {
+        password_command: Some("exit 3".to_owned()),
+        ..sources(dir.path())
+    })
+    .unwrap_err();
+    assert!(err.to_string().contains("failed"), "{err:#}");
+}
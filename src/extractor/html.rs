@@ -0,0 +1,65 @@
+use anyhow::Context;
+use scraper::Html;
+
+use super::{Error, Metadata};
+use crate::scrape::{selector, text};
+
+/// Decodes `html`, which may be cut off in the middle of a character, since usually only the
+/// start of a download is read.
+fn decode(html: &[u8]) -> anyhow::Result<&str> {
+    match std::str::from_utf8(html) {
+        Ok(html) => Ok(html),
+        Err(e) if e.error_len().is_none() => Ok(std::str::from_utf8(&html[..e.valid_up_to()])?),
+        Err(e) => Err(e).context("HTML download is not valid UTF-8"),
+    }
+}
+
+/// Checks that an HTML download has the preface every AO3 download starts with.
+pub fn validate(html: &[u8]) -> anyhow::Result<()> {
+    let html = decode(html)?;
+    let document = Html::parse_document(html);
+    if document.select(&selector("#preface")).next().is_none() {
+        return Err(Error::HtmlPrefaceMissing.into());
+    }
+    Ok(())
+}
+
+/// Reads the title and authors from the preface of an AO3 HTML download.
+pub fn metadata(html: &[u8]) -> anyhow::Result<Metadata> {
+    let html = decode(html)?;
+    let document = Html::parse_document(html);
+    let first = |css: &str| document.select(&selector(css)).next();
+
+    log::trace!("Looking for title in HTML preface");
+
+    // The message above the preface ("<b>Title</b> Posted originally on the Archive of Our
+    // Own...") is a fallback for downloads without the usual metadata block
+    let title = first("#preface .meta h1")
+        .or_else(|| first("#preface p.message b"))
+        .map(text)
+        .filter(|title| !title.is_empty())
+        .ok_or(Error::HtmlTitleMissing)?;
+
+    let byline = first("#preface .meta .byline");
+    let author_link = selector("a[rel=author]");
+    let mut authors = byline
+        .iter()
+        .flat_map(|byline| byline.select(&author_link))
+        .map(text)
+        .collect::<Vec<_>>();
+    if authors.is_empty() {
+        // Anonymous works have a byline without links
+        authors.extend(
+            byline
+                .map(text)
+                .map(|byline| byline.trim_start_matches("by ").to_owned())
+                .filter(|byline| !byline.is_empty()),
+        );
+    }
+
+    Ok(Metadata {
+        title,
+        authors,
+        ..Metadata::default()
+    })
+}
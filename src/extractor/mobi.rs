@@ -0,0 +1,141 @@
+//! Reads metadata from MOBI and KF8 (AZW3) files.
+//!
+//! Both are PalmDB databases whose first record holds a PalmDOC header, a MOBI header and,
+//! usually, an EXTH header with the book's metadata. KF8 files use the same layout (with a newer
+//! MOBI header version), so one parser covers both.
+
+use anyhow::{Context, bail};
+use chrono::NaiveDate;
+
+use super::{Error, Metadata};
+
+const PALMDB_HEADER_LEN: usize = 78;
+const PALMDOC_HEADER_LEN: usize = 16;
+/// Set in the MOBI header's EXTH flags when an EXTH header follows it
+const HAS_EXTH: u32 = 0x40;
+const UTF8: u32 = 65001;
+
+// EXTH record types
+const EXTH_AUTHOR: u32 = 100;
+const EXTH_PUBLISHER: u32 = 101;
+const EXTH_PUBLISHING_DATE: u32 = 106;
+const EXTH_UPDATED_TITLE: u32 = 503;
+
+fn bytes_at(data: &[u8], offset: usize, len: usize) -> anyhow::Result<&[u8]> {
+    data.get(offset..offset + len)
+        .with_context(|| format!("File ends before byte {}", offset + len))
+}
+
+fn u16_at(data: &[u8], offset: usize) -> anyhow::Result<u16> {
+    Ok(u16::from_be_bytes(
+        bytes_at(data, offset, 2)?.try_into().unwrap(),
+    ))
+}
+
+fn u32_at(data: &[u8], offset: usize) -> anyhow::Result<u32> {
+    Ok(u32::from_be_bytes(
+        bytes_at(data, offset, 4)?.try_into().unwrap(),
+    ))
+}
+
+/// Decodes a string in the book's text encoding: UTF-8 or, in older files, CP1252.
+fn decode(bytes: &[u8], encoding: u32) -> String {
+    let text = if encoding == UTF8 {
+        String::from_utf8_lossy(bytes).into_owned()
+    } else {
+        // Close enough to CP1252 for titles and names
+        bytes.iter().map(|&b| char::from(b)).collect()
+    };
+    text.trim_end_matches('\0').trim().to_owned()
+}
+
+/// Checks for the PalmDB header and `BOOKMOBI` signature both MOBI and AZW3 files start with.
+pub fn validate(bytes: &[u8]) -> anyhow::Result<()> {
+    if bytes.len() < PALMDB_HEADER_LEN || bytes_at(bytes, 60, 8).ok() != Some(b"BOOKMOBI") {
+        return Err(Error::NotMobi.into());
+    }
+    if u16_at(bytes, 76)? == 0 {
+        bail!("PalmDB has no records");
+    }
+    Ok(())
+}
+
+/// Reads the title, authors, publisher and date from a MOBI or AZW3 file.
+pub fn metadata(bytes: &[u8]) -> anyhow::Result<Metadata> {
+    if bytes_at(bytes, 60, 8).ok() != Some(b"BOOKMOBI") {
+        return Err(Error::NotMobi.into());
+    }
+    let record_count = u16_at(bytes, 76)?;
+    if record_count == 0 {
+        bail!("PalmDB has no records");
+    }
+    let record0_start = u32_at(bytes, PALMDB_HEADER_LEN)? as usize;
+    let record0_end = if record_count > 1 {
+        u32_at(bytes, PALMDB_HEADER_LEN + 8)? as usize
+    } else {
+        bytes.len()
+    };
+    let record0 = bytes
+        .get(record0_start..record0_end)
+        .context("PalmDB record list points outside the file")?;
+
+    log::trace!("Parsing MOBI header");
+
+    if bytes_at(record0, PALMDOC_HEADER_LEN, 4)? != b"MOBI" {
+        return Err(Error::NotMobi.into());
+    }
+    let mobi_header_len = u32_at(record0, PALMDOC_HEADER_LEN + 4)? as usize;
+    let encoding = u32_at(record0, PALMDOC_HEADER_LEN + 12)?;
+    let version = u32_at(record0, PALMDOC_HEADER_LEN + 20)?;
+    let full_name_offset = u32_at(record0, 84)? as usize;
+    let full_name_len = u32_at(record0, 88)? as usize;
+    let exth_flags = u32_at(record0, 128)?;
+
+    log::trace!("Found MOBI header version {}", version);
+
+    let mut metadata = Metadata {
+        title: decode(
+            bytes_at(record0, full_name_offset, full_name_len)?,
+            encoding,
+        ),
+        ..Metadata::default()
+    };
+
+    if exth_flags & HAS_EXTH != 0 {
+        log::trace!("Parsing EXTH header");
+
+        let exth = PALMDOC_HEADER_LEN + mobi_header_len;
+        if bytes_at(record0, exth, 4)? != b"EXTH" {
+            bail!("Missing EXTH header, despite the MOBI header's flags");
+        }
+        let exth_record_count = u32_at(record0, exth + 8)?;
+        let mut offset = exth + 12;
+        for _ in 0..exth_record_count {
+            let record_type = u32_at(record0, offset)?;
+            let record_len = u32_at(record0, offset + 4)? as usize;
+            if record_len < 8 {
+                bail!("EXTH record at byte {} is too short", offset);
+            }
+            let data = decode(bytes_at(record0, offset + 8, record_len - 8)?, encoding);
+            match record_type {
+                EXTH_AUTHOR => metadata.authors.push(data),
+                EXTH_PUBLISHER => metadata.publisher = Some(data),
+                // AO3 stamps its downloads with the date the work was last updated
+                EXTH_PUBLISHING_DATE => {
+                    metadata.updated = data
+                        .get(..10)
+                        .and_then(|date| NaiveDate::parse_from_str(date, "%Y-%m-%d").ok());
+                }
+                EXTH_UPDATED_TITLE if !data.is_empty() => metadata.title = data,
+                _ => {}
+            }
+            offset += record_len;
+        }
+    }
+
+    if metadata.title.is_empty() {
+        return Err(Error::MobiTitleMissing.into());
+    }
+
+    Ok(metadata)
+}
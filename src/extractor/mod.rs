@@ -1,32 +1,104 @@
 use std::{
-    fmt,
+    fmt, fs,
     io::{self, Read, Seek},
     path,
 };
 
-use anyhow::Context;
+use anyhow::{Context, bail};
+use serde::Serialize;
 use zip::ZipArchive;
 
+use crate::Format;
+
+pub mod html;
+pub mod mobi;
+pub mod pdf;
+#[cfg(test)]
+mod tests;
+
 #[derive(Debug)]
 enum Error {
     TitleAttributeMissing,
+    HtmlTitleMissing,
+    NotMobi,
+    MobiTitleMissing,
+    NotPdf,
+    PdfTitleMissing,
+    PdfTrailerMissing,
+    EpubMimetypeWrong,
+    EpubOpfMissing,
+    HtmlPrefaceMissing,
+    HtmlInsteadOfBook,
 }
 
 impl fmt::Display for Error {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
-            Self::TitleAttributeMissing => {
-                return write!(f, "Missing 'dc:title' tag in content.opf");
+            Self::TitleAttributeMissing => write!(f, "Missing 'dc:title' tag in content.opf"),
+            Self::HtmlTitleMissing => write!(f, "Missing title in HTML preface"),
+            Self::NotMobi => write!(f, "Not a MOBI or AZW3 file"),
+            Self::MobiTitleMissing => write!(f, "Missing title in MOBI header"),
+            Self::NotPdf => write!(f, "Not a PDF file"),
+            Self::PdfTitleMissing => write!(f, "Missing title in PDF metadata"),
+            Self::PdfTrailerMissing => {
+                write!(f, "PDF has no trailer, so it was probably cut short")
             }
+            Self::EpubMimetypeWrong => {
+                write!(
+                    f,
+                    "EPUB has no 'mimetype' entry saying 'application/epub+zip'"
+                )
+            }
+            Self::EpubOpfMissing => write!(f, "EPUB has no OPF package document"),
+            Self::HtmlPrefaceMissing => write!(
+                f,
+                "HTML has no work preface, so it is probably an error, login or maintenance page"
+            ),
+            Self::HtmlInsteadOfBook => write!(
+                f,
+                "Got a web page instead, probably an error, login or maintenance page"
+            ),
         }
     }
 }
 
 impl std::error::Error for Error {}
 
-pub fn as_zip(bytes: &bytes::Bytes) -> anyhow::Result<ZipArchive<impl Read + Seek>> {
-    let wrapped = io::Cursor::new(bytes);
-    let zip = zip::ZipArchive::new(wrapped).context("Could not create ZipArchive from bytes")?;
+/// What a downloaded file says about the work inside it
+#[derive(Clone, Debug, Default, PartialEq, Serialize)]
+pub struct Metadata {
+    pub title: String,
+    pub authors: Vec<String>,
+    pub publisher: Option<String>,
+    /// When the work was last updated, according to the file
+    pub updated: Option<chrono::NaiveDate>,
+    /// When the file itself was made
+    pub created: Option<chrono::NaiveDate>,
+}
+
+/// How much of the start of an HTML, MOBI or AZW3 file is read for its metadata, which is all at
+/// the start
+pub const HEAD_LEN: u64 = 1024 * 1024;
+
+/// Reads up to the first [`HEAD_LEN`] bytes of `file`.
+pub fn head(file: &mut (impl Read + Seek)) -> io::Result<Vec<u8>> {
+    file.rewind()?;
+    let mut head = Vec::new();
+    file.by_ref().take(HEAD_LEN).read_to_end(&mut head)?;
+    Ok(head)
+}
+
+/// Reads up to the last `len` bytes of `file`.
+fn tail(file: &mut (impl Read + Seek), len: u64) -> io::Result<Vec<u8>> {
+    let file_len = file.seek(io::SeekFrom::End(0))?;
+    file.seek(io::SeekFrom::Start(file_len.saturating_sub(len)))?;
+    let mut tail = Vec::new();
+    file.read_to_end(&mut tail)?;
+    Ok(tail)
+}
+
+pub fn as_zip<R: Read + Seek>(file: R) -> anyhow::Result<ZipArchive<R>> {
+    let zip = zip::ZipArchive::new(file).context("Could not create ZipArchive from download")?;
     Ok(zip)
 }
 
@@ -65,6 +137,159 @@ pub fn title(zipped_epub: &mut ZipArchive<impl Read + Seek>) -> anyhow::Result<S
     }
 }
 
+/// Whether `path` looks like something ao3dl downloaded, going by its extension.
+pub fn is_download(path: &path::Path) -> bool {
+    path.extension()
+        .and_then(|extension| extension.to_str())
+        .is_some_and(|extension| {
+            ["epub", "html", "mobi", "azw3", "pdf"]
+                .contains(&extension.to_ascii_lowercase().as_str())
+        })
+}
+
+/// Reads whatever metadata can be found in the downloaded file at `path`, going by its extension.
+pub fn file_metadata(path: &path::Path) -> anyhow::Result<Metadata> {
+    let extension = path
+        .extension()
+        .and_then(|extension| extension.to_str())
+        .map(|extension| extension.to_ascii_lowercase());
+
+    if extension.as_deref() == Some("epub") && path.is_dir() {
+        bail!("Cannot inspect unzipped EPUBs");
+    }
+    let read_error = || format!("Cannot read '{}'", path.display());
+    let mut file = fs::File::open(path).with_context(read_error)?;
+
+    match extension.as_deref() {
+        Some("epub") => {
+            let mut zipped_epub = as_zip(file).context("Could not parse EPUB as ZIP")?;
+            Ok(Metadata {
+                title: title(&mut zipped_epub)?,
+                ..Metadata::default()
+            })
+        }
+        Some("html") => html::metadata(&head(&mut file).with_context(read_error)?),
+        Some("mobi" | "azw3") => mobi::metadata(&head(&mut file).with_context(read_error)?),
+        Some("pdf") => pdf::metadata(&mut file),
+        _ => bail!("Not a file type ao3dl downloads"),
+    }
+}
+
+/// Checks that a download really is a `format` file, rather than an error page, a login wall or
+/// the maintenance page that the archive can serve in its place.
+pub fn validate(format: Format, file: &mut (impl Read + Seek)) -> anyhow::Result<()> {
+    let head = head(file).context("Cannot read download")?;
+    if format != Format::HTML && looks_like_html(&head) {
+        return Err(Error::HtmlInsteadOfBook.into());
+    }
+
+    match format {
+        Format::EPUB => validate_epub(file),
+        Format::HTML => html::validate(&head),
+        Format::MOBI | Format::AZW3 => mobi::validate(&head),
+        Format::PDF => {
+            let tail = tail(file, pdf::TRAILER_SEARCH_LEN).context("Cannot read download")?;
+            pdf::validate(&head, &tail)
+        }
+    }
+}
+
+fn looks_like_html(bytes: &[u8]) -> bool {
+    let start = bytes
+        .strip_prefix(b"\xef\xbb\xbf")
+        .unwrap_or(bytes)
+        .trim_ascii_start();
+    let start = &start[..start.len().min(16)];
+    [b"<!doctype html".as_slice(), b"<html"]
+        .iter()
+        .any(|tag| start.len() >= tag.len() && start[..tag.len()].eq_ignore_ascii_case(tag))
+}
+
+/// Checks for the `mimetype` entry every EPUB starts with, and an OPF package document that
+/// parses.
+fn validate_epub(file: &mut (impl Read + Seek)) -> anyhow::Result<()> {
+    file.rewind().context("Cannot read download")?;
+    let mut zipped_epub = as_zip(file).context("Could not parse EPUB as ZIP")?;
+
+    let mut mimetype = String::new();
+    zipped_epub
+        .by_name("mimetype")
+        .ok()
+        .and_then(|mut entry| entry.read_to_string(&mut mimetype).ok())
+        .ok_or(Error::EpubMimetypeWrong)?;
+    if mimetype.trim() != "application/epub+zip" {
+        return Err(Error::EpubMimetypeWrong.into());
+    }
+
+    let opf_path = match container_rootfile(&mut zipped_epub)? {
+        Some(path) => path,
+        None => zipped_epub
+            .file_names()
+            .find(|name| name.ends_with(".opf"))
+            .ok_or(Error::EpubOpfMissing)?
+            .to_owned(),
+    };
+    let mut opf = String::new();
+    zipped_epub
+        .by_name(&opf_path)
+        .map_err(|_| Error::EpubOpfMissing)?
+        .read_to_string(&mut opf)
+        .with_context(|| format!("Cannot read '{}' from EPUB", opf_path))?;
+
+    let mut reader = quick_xml::Reader::from_str(&opf);
+    let mut seen_package = false;
+    loop {
+        match reader
+            .read_event()
+            .with_context(|| format!("Cannot parse '{}' in EPUB", opf_path))?
+        {
+            quick_xml::events::Event::Start(tag) | quick_xml::events::Event::Empty(tag) => {
+                seen_package |= tag.local_name().as_ref() == b"package";
+            }
+            quick_xml::events::Event::Eof => break,
+            _ => {}
+        }
+    }
+    if !seen_package {
+        return Err(Error::EpubOpfMissing.into());
+    }
+
+    Ok(())
+}
+
+/// Finds where `META-INF/container.xml` says the OPF package document is, if the EPUB has one.
+fn container_rootfile(
+    zipped_epub: &mut ZipArchive<impl Read + Seek>,
+) -> anyhow::Result<Option<String>> {
+    let mut container = String::new();
+    match zipped_epub.by_name("META-INF/container.xml") {
+        Ok(mut entry) => entry
+            .read_to_string(&mut container)
+            .context("Cannot read META-INF/container.xml from EPUB")?,
+        Err(_) => return Ok(None),
+    };
+
+    let mut reader = quick_xml::Reader::from_str(&container);
+    loop {
+        match reader
+            .read_event()
+            .context("Cannot parse META-INF/container.xml in EPUB")?
+        {
+            quick_xml::events::Event::Start(tag) | quick_xml::events::Event::Empty(tag)
+                if tag.local_name().as_ref() == b"rootfile" =>
+            {
+                let full_path = tag
+                    .try_get_attribute("full-path")?
+                    .map(|path| path.unescape_value().map(|path| path.into_owned()))
+                    .transpose()?;
+                return Ok(full_path);
+            }
+            quick_xml::events::Event::Eof => return Ok(None),
+            _ => {}
+        }
+    }
+}
+
 pub fn unzip_to<P: AsRef<path::Path>>(
     zipped_epub: &mut ZipArchive<impl Read + Seek>,
     dest: P,
@@ -0,0 +1,452 @@
+//! Reads metadata from PDF files, from the document information dictionary and, failing that,
+//! the XMP metadata packet.
+
+use std::io::{self, Read, Seek, SeekFrom};
+
+use anyhow::Context;
+use chrono::NaiveDate;
+use flate2::read::ZlibDecoder;
+use quick_xml::events::Event;
+use regex::bytes::Regex;
+
+use super::{Error, Metadata};
+
+/// How far from the end of a PDF its trailer has to start
+pub const TRAILER_SEARCH_LEN: u64 = 1024;
+
+/// How much of a PDF is searched for its metadata at a time
+const CHUNK_LEN: u64 = 1024 * 1024;
+
+/// How much of each chunk is searched again with the next, so that what is split between the two
+/// is still found. Much longer than anything searched for.
+const CHUNK_OVERLAP: usize = 4 * 1024;
+
+/// The most that is read of an object once it has been found
+const OBJECT_MAX_LEN: u64 = 64 * 1024;
+
+/// The most that is read of an object stream or XMP packet once it has been found, or decompressed
+/// from an object stream
+const STREAM_MAX_LEN: u64 = 16 * 1024 * 1024;
+
+/// Checks for the `%PDF-` header at the start of `head`, and a trailer (`startxref` and `%%EOF`)
+/// in `tail`, the last [`TRAILER_SEARCH_LEN`] bytes, which a PDF that was cut short won't have.
+pub fn validate(head: &[u8], tail: &[u8]) -> anyhow::Result<()> {
+    if !head.starts_with(b"%PDF-") {
+        return Err(Error::NotPdf.into());
+    }
+    let has = |needle: &[u8]| tail.windows(needle.len()).any(|window| window == needle);
+    if !has(b"startxref") || !has(b"%%EOF") {
+        return Err(Error::PdfTrailerMissing.into());
+    }
+    Ok(())
+}
+
+/// Reads the title, author and creation date from a PDF.
+///
+/// The metadata can be anywhere in the file, so it is searched a chunk at a time rather than read
+/// in whole.
+pub fn metadata(file: &mut (impl Read + Seek)) -> anyhow::Result<Metadata> {
+    let mut magic = [0; 5];
+    file.rewind().context("Cannot read PDF")?;
+    if file.read_exact(&mut magic).is_err() || &magic != b"%PDF-" {
+        return Err(Error::NotPdf.into());
+    }
+
+    let mut metadata = Metadata::default();
+
+    match info_dictionary(file).context("Cannot read PDF")? {
+        Some(info) => {
+            log::trace!("Found PDF document information dictionary");
+            let strings = string_entries(&info);
+            let get = |key: &str| {
+                strings
+                    .iter()
+                    .find(|(k, _)| k == key)
+                    .map(|(_, value)| value.trim().to_owned())
+                    .filter(|value| !value.is_empty())
+            };
+            metadata.title = get("Title").unwrap_or_default();
+            metadata.authors.extend(get("Author"));
+            metadata.created = get("CreationDate").as_deref().and_then(pdf_date);
+        }
+        None => log::trace!("Could not find PDF document information dictionary"),
+    }
+
+    let incomplete =
+        metadata.title.is_empty() || metadata.authors.is_empty() || metadata.created.is_none();
+    if incomplete && let Some(packet) = xmp_packet(file).context("Cannot read PDF")? {
+        log::trace!("Filling in PDF metadata from XMP");
+        let xmp = xmp(&packet).context("Cannot parse XMP metadata")?;
+        if metadata.title.is_empty() {
+            metadata.title = xmp.title.unwrap_or_default();
+        }
+        if metadata.authors.is_empty() {
+            metadata.authors = xmp.creators;
+        }
+        metadata.created = metadata.created.or(xmp.created);
+    }
+
+    if metadata.title.is_empty() {
+        return Err(Error::PdfTitleMissing.into());
+    }
+
+    Ok(metadata)
+}
+
+/// Where `pattern` matches in `file`, as the offsets of the start and end of each match.
+fn find_all(file: &mut (impl Read + Seek), pattern: &Regex) -> io::Result<Vec<(u64, u64)>> {
+    file.rewind()?;
+    let mut matches = Vec::new();
+    let mut window = Vec::new();
+    // Where `window` starts in the file
+    let mut offset = 0;
+    loop {
+        let carried = window.len();
+        if file.by_ref().take(CHUNK_LEN).read_to_end(&mut window)? == 0 {
+            break;
+        }
+        // Matches that end in what was carried over were found last time round
+        matches.extend(
+            pattern
+                .find_iter(&window)
+                .filter(|m| m.end() > carried)
+                .map(|m| (offset + m.start() as u64, offset + m.end() as u64)),
+        );
+        let carry_from = window.len().saturating_sub(CHUNK_OVERLAP);
+        window.drain(..carry_from);
+        offset += carry_from as u64;
+    }
+    Ok(matches)
+}
+
+/// Up to `len` bytes of `file`, starting at `offset`.
+fn read_at(file: &mut (impl Read + Seek), offset: u64, len: u64) -> io::Result<Vec<u8>> {
+    file.seek(SeekFrom::Start(offset))?;
+    let mut bytes = Vec::new();
+    file.by_ref().take(len).read_to_end(&mut bytes)?;
+    Ok(bytes)
+}
+
+/// The bytes of `file` from `offset` up to the next `end`, if it comes within `max_len` bytes.
+fn read_until(
+    file: &mut (impl Read + Seek),
+    offset: u64,
+    end: &[u8],
+    max_len: u64,
+) -> io::Result<Option<Vec<u8>>> {
+    let mut bytes = read_at(file, offset, max_len)?;
+    Ok(find(&bytes, end).map(|len| {
+        bytes.truncate(len);
+        bytes
+    }))
+}
+
+/// The body of the document information dictionary named in the (last) trailer.
+fn info_dictionary(file: &mut (impl Read + Seek)) -> io::Result<Option<Vec<u8>>> {
+    let info_ref = Regex::new(r"/Info\s*(\d+)\s+(\d+)\s+R").unwrap();
+    let Some((start, end)) = find_all(file, &info_ref)?.pop() else {
+        return Ok(None);
+    };
+    let reference = read_at(file, start, end - start)?;
+    let Some(captures) = info_ref.captures(&reference) else {
+        return Ok(None);
+    };
+    let parse = |i| std::str::from_utf8(&captures[i]).ok()?.parse().ok();
+    let (Some(number), Some(generation)) = (parse(1), parse(2)) else {
+        return Ok(None);
+    };
+
+    match object(file, number, generation)? {
+        Some(object) => Ok(Some(object)),
+        None => compressed_object(file, number),
+    }
+}
+
+/// The body of object `number`, if it is stored uncompressed.
+fn object(
+    file: &mut (impl Read + Seek),
+    number: u32,
+    generation: u32,
+) -> io::Result<Option<Vec<u8>>> {
+    let header = Regex::new(&format!(r"(?:^|[^0-9]){}\s+{}\s+obj", number, generation)).unwrap();
+    // Later definitions (from incremental updates) replace earlier ones
+    let Some((_, start)) = find_all(file, &header)?.pop() else {
+        return Ok(None);
+    };
+    read_until(file, start, b"endobj", OBJECT_MAX_LEN)
+}
+
+/// The body of object `number`, if it is stored in a compressed object stream (PDF 1.5+).
+fn compressed_object(file: &mut (impl Read + Seek), number: u32) -> io::Result<Option<Vec<u8>>> {
+    let stream_header = Regex::new(r"(?s)\d+\s+\d+\s+obj\s*<<(.*?)>>\s*stream\r?\n").unwrap();
+    let int_entry = |dict: &[u8], key: &str| -> Option<usize> {
+        let entry = Regex::new(&format!(r"/{}\s+(\d+)", key)).unwrap();
+        std::str::from_utf8(&entry.captures(dict)?[1])
+            .ok()?
+            .parse()
+            .ok()
+    };
+
+    for (start, end) in find_all(file, &stream_header)? {
+        let header = read_at(file, start, end - start)?;
+        let Some(dict) = stream_header
+            .captures(&header)
+            .and_then(|captures| captures.get(1))
+        else {
+            continue;
+        };
+        let dict = dict.as_bytes();
+        if find(dict, b"/ObjStm").is_none() {
+            continue;
+        }
+        let (Some(count), Some(first)) = (int_entry(dict, "N"), int_entry(dict, "First")) else {
+            continue;
+        };
+        let Some(compressed) = read_until(file, end, b"endstream", STREAM_MAX_LEN)? else {
+            continue;
+        };
+
+        let mut stream = Vec::new();
+        if ZlibDecoder::new(&compressed[..])
+            .take(STREAM_MAX_LEN)
+            .read_to_end(&mut stream)
+            .is_err()
+        {
+            log::trace!("Skipping object stream that isn't Flate-compressed");
+            continue;
+        }
+
+        // The stream starts with pairs of object numbers and offsets (relative to `first`)
+        let Some(index) = stream.get(..first) else {
+            continue;
+        };
+        let index = String::from_utf8_lossy(index)
+            .split_whitespace()
+            .filter_map(|n| n.parse::<usize>().ok())
+            .collect::<Vec<_>>();
+        let pairs = index.chunks_exact(2).take(count).collect::<Vec<_>>();
+        let Some(i) = pairs.iter().position(|pair| pair[0] == number as usize) else {
+            continue;
+        };
+        // The offsets come straight from the file, so may be anything
+        let start = first.checked_add(pairs[i][1]);
+        let end = match pairs.get(i + 1) {
+            Some(next) => first.checked_add(next[1]),
+            None => Some(stream.len()),
+        };
+        let (Some(start), Some(end)) = (start, end) else {
+            log::trace!("Skipping object stream with offsets out of range");
+            continue;
+        };
+        return Ok(stream.get(start..end).map(<[u8]>::to_vec));
+    }
+
+    Ok(None)
+}
+
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack
+        .windows(needle.len())
+        .position(|window| window == needle)
+}
+
+/// Every entry of `dict` whose value is a string, decoded.
+fn string_entries(dict: &[u8]) -> Vec<(String, String)> {
+    let mut entries = Vec::new();
+    let mut i = 0;
+    while i < dict.len() {
+        if dict[i] != b'/' {
+            i += 1;
+            continue;
+        }
+        let key_start = i + 1;
+        i = key_start;
+        while i < dict.len() && !b"/()<>[]{} \t\r\n\x0c\0%".contains(&dict[i]) {
+            i += 1;
+        }
+        let key = String::from_utf8_lossy(&dict[key_start..i]).into_owned();
+        while i < dict.len() && dict[i].is_ascii_whitespace() {
+            i += 1;
+        }
+        let value = match (dict.get(i), dict.get(i + 1)) {
+            (Some(b'('), _) => literal_string(dict, &mut i),
+            (Some(b'<'), Some(next)) if *next != b'<' => hex_string(dict, &mut i),
+            _ => continue,
+        };
+        entries.push((key, decode_text(&value)));
+    }
+    entries
+}
+
+/// Parses the literal string starting at `dict[*i]` (an opening parenthesis), leaving `*i` just
+/// after it.
+fn literal_string(dict: &[u8], i: &mut usize) -> Vec<u8> {
+    let mut value = Vec::new();
+    let mut depth = 0;
+    while *i < dict.len() {
+        let c = dict[*i];
+        *i += 1;
+        match c {
+            b'(' => {
+                depth += 1;
+                if depth == 1 {
+                    continue;
+                }
+            }
+            b')' => {
+                depth -= 1;
+                if depth == 0 {
+                    break;
+                }
+            }
+            b'\\' => {
+                let Some(&escaped) = dict.get(*i) else {
+                    break;
+                };
+                *i += 1;
+                match escaped {
+                    b'n' => value.push(b'\n'),
+                    b'r' => value.push(b'\r'),
+                    b't' => value.push(b'\t'),
+                    b'b' => value.push(0x08),
+                    b'f' => value.push(0x0c),
+                    b'0'..=b'7' => {
+                        let mut code = u32::from(escaped - b'0');
+                        for _ in 0..2 {
+                            match dict.get(*i) {
+                                Some(digit @ b'0'..=b'7') => {
+                                    code = code * 8 + u32::from(digit - b'0');
+                                    *i += 1;
+                                }
+                                _ => break,
+                            }
+                        }
+                        value.push(code as u8);
+                    }
+                    // A backslash at the end of a line continues the string on the next
+                    b'\r' => {
+                        if dict.get(*i) == Some(&b'\n') {
+                            *i += 1;
+                        }
+                    }
+                    b'\n' => {}
+                    other => value.push(other),
+                }
+                continue;
+            }
+            _ => {}
+        }
+        value.push(c);
+    }
+    value
+}
+
+/// Parses the hex string starting at `dict[*i]` (a `<`), leaving `*i` just after it.
+fn hex_string(dict: &[u8], i: &mut usize) -> Vec<u8> {
+    *i += 1;
+    let start = *i;
+    while *i < dict.len() && dict[*i] != b'>' {
+        *i += 1;
+    }
+    let mut digits = dict[start..*i]
+        .iter()
+        .filter(|c| c.is_ascii_hexdigit())
+        .copied()
+        .collect::<Vec<_>>();
+    *i += 1;
+    // A missing final digit is taken to be 0
+    if digits.len() % 2 == 1 {
+        digits.push(b'0');
+    }
+    digits
+        .chunks_exact(2)
+        .filter_map(|pair| u8::from_str_radix(std::str::from_utf8(pair).ok()?, 16).ok())
+        .collect()
+}
+
+/// Decodes a PDF text string: UTF-16BE with a byte order mark, UTF-8 with one (PDF 2.0), or
+/// PDFDocEncoding, which agrees with Latin-1 for everything a title is likely to contain.
+fn decode_text(bytes: &[u8]) -> String {
+    if let Some(utf16) = bytes.strip_prefix(b"\xfe\xff") {
+        let units = utf16
+            .chunks_exact(2)
+            .map(|pair| u16::from_be_bytes([pair[0], pair[1]]))
+            .collect::<Vec<_>>();
+        String::from_utf16_lossy(&units)
+    } else if let Some(utf8) = bytes.strip_prefix(b"\xef\xbb\xbf") {
+        String::from_utf8_lossy(utf8).into_owned()
+    } else {
+        bytes.iter().map(|&b| char::from(b)).collect()
+    }
+}
+
+/// Parses the date part of a PDF date, e.g. `D:20231112093000+00'00'`.
+fn pdf_date(date: &str) -> Option<NaiveDate> {
+    let date = date.strip_prefix("D:").unwrap_or(date);
+    NaiveDate::parse_from_str(date.get(..8)?, "%Y%m%d").ok()
+}
+
+#[derive(Default)]
+struct Xmp {
+    title: Option<String>,
+    creators: Vec<String>,
+    created: Option<NaiveDate>,
+}
+
+/// The XMP packet, if the PDF has an uncompressed one.
+fn xmp_packet(file: &mut (impl Read + Seek)) -> io::Result<Option<Vec<u8>>> {
+    let start_tag = Regex::new("<x:xmpmeta").unwrap();
+    let Some(&(start, _)) = find_all(file, &start_tag)?.first() else {
+        return Ok(None);
+    };
+    let end_tag = b"</x:xmpmeta>";
+    let packet = read_until(file, start, end_tag, STREAM_MAX_LEN)?.map(|mut packet| {
+        packet.extend(end_tag);
+        packet
+    });
+    Ok(packet)
+}
+
+/// Parses an XMP packet.
+fn xmp(packet: &[u8]) -> anyhow::Result<Xmp> {
+    let packet = String::from_utf8_lossy(packet);
+    let xmp_date = |date: &str| NaiveDate::parse_from_str(date.get(..10)?, "%Y-%m-%d").ok();
+
+    let mut xmp = Xmp::default();
+    let mut reader = quick_xml::Reader::from_str(&packet);
+    let mut path = Vec::<String>::new();
+    loop {
+        match reader.read_event()? {
+            Event::Eof => break,
+            Event::Start(tag) => {
+                path.push(String::from_utf8_lossy(tag.name().as_ref()).into_owned());
+                // Properties can also be written as attributes of rdf:Description
+                for attr in tag.attributes().flatten() {
+                    if attr.key.as_ref() == b"xmp:CreateDate" {
+                        xmp.created = xmp.created.or(xmp_date(&attr.unescape_value()?));
+                    }
+                }
+            }
+            Event::End(_) => {
+                path.pop();
+            }
+            Event::Text(text) => {
+                let text = text.unescape()?.trim().to_owned();
+                if text.is_empty() {
+                    continue;
+                }
+                let within = |property: &str| path.iter().any(|name| name == property);
+                if within("dc:title") && xmp.title.is_none() {
+                    xmp.title = Some(text);
+                } else if within("dc:creator") {
+                    xmp.creators.push(text);
+                } else if path.last().is_some_and(|name| name == "xmp:CreateDate") {
+                    xmp.created = xmp.created.or(xmp_date(&text));
+                }
+            }
+            _ => {}
+        }
+    }
+
+    Ok(xmp)
+}
@@ -0,0 +1,275 @@
+use super::*;
+
+fn validate_bytes(format: Format, bytes: &[u8]) -> anyhow::Result<()> {
+    validate(format, &mut io::Cursor::new(bytes))
+}
+
+fn pdf_metadata(bytes: &[u8]) -> anyhow::Result<Metadata> {
+    pdf::metadata(&mut io::Cursor::new(bytes))
+}
+
+#[test]
+fn html_download_title_and_authors_are_extracted() {
+    let html = br#"<html><head><title>Ignored</title></head><body>
+<div id="preface">
+  <p class="message"><b>Fish &amp; Chips</b><br/>Posted originally on the <a href="http://archiveofourown.org/">Archive of Our Own</a> at <a href="http://archiveofourown.org/works/1">http://archiveofourown.org/works/1</a>.</p>
+  <div class="meta">
+    <dl class="tags"><dt class="rating">Rating:</dt><dd class="rating">General Audiences</dd></dl>
+    <h1>Fish &amp;
+      Chips</h1>
+    <div class="byline">by <a rel="author" href="http://archiveofourown.org/users/one/pseuds/one">one</a>, <a rel="author" href="http://archiveofourown.org/users/two/pseuds/two">two</a></div>
+  </div>
+</div>
+</body></html>"#;
+
+    assert_eq!(
+        html::metadata(html).unwrap(),
+        Metadata {
+            title: "Fish & Chips".to_owned(),
+            authors: vec!["one".to_owned(), "two".to_owned()],
+            ..Metadata::default()
+        }
+    );
+}
+
+#[test]
+fn html_download_falls_back_to_the_preface_message() {
+    let html = br#"<html><body><div id="preface"><p class="message"><b>Only Here</b></p><div class="meta"><div class="byline">by Anonymous</div></div></div></body></html>"#;
+
+    let metadata = html::metadata(html).unwrap();
+
+    assert_eq!(metadata.title, "Only Here");
+    assert_eq!(metadata.authors, ["Anonymous"]);
+}
+
+#[test]
+fn html_without_a_preface_is_an_error() {
+    let html = b"<html><body><p>Sorry, you don't have permission to access the page you were trying to reach.</p></body></html>";
+
+    assert!(html::metadata(html).is_err());
+}
+
+#[test]
+fn mobi_and_azw3_metadata_is_extracted() {
+    for kf8 in [false, true] {
+        let bytes = crate::mock::mobi("A Very Long Title, Longer Than A PalmDB Name", "one", kf8);
+
+        assert_eq!(
+            mobi::metadata(&bytes).unwrap(),
+            Metadata {
+                title: "A Very Long Title, Longer Than A PalmDB Name".to_owned(),
+                authors: vec!["one".to_owned()],
+                publisher: Some("Archive of Our Own".to_owned()),
+                updated: chrono::NaiveDate::from_ymd_opt(2023, 11, 12),
+                created: None,
+            }
+        );
+    }
+}
+
+#[test]
+fn truncated_mobi_is_an_error() {
+    let bytes = crate::mock::mobi("Title", "one", false);
+
+    assert!(mobi::metadata(&bytes[..bytes.len() - 10]).is_err());
+    assert!(mobi::metadata(&bytes[..100]).is_err());
+    assert!(mobi::metadata(b"%PDF-1.4").is_err());
+}
+
+#[test]
+fn pdf_info_dictionary_is_read() {
+    let bytes = crate::mock::pdf("Fish (and) Chips", "one");
+
+    assert_eq!(
+        pdf_metadata(&bytes).unwrap(),
+        Metadata {
+            title: "Fish (and) Chips".to_owned(),
+            authors: vec!["one".to_owned()],
+            created: chrono::NaiveDate::from_ymd_opt(2023, 11, 12),
+            ..Metadata::default()
+        }
+    );
+}
+
+#[test]
+fn pdf_text_strings_are_decoded() {
+    // "Café" in UTF-16BE, and an octal escape for PDFDocEncoding's é
+    let bytes = b"%PDF-1.7\n1 0 obj\n<< /Author (Caf\\351) /Title <FEFF00430061006600E9> >>\nendobj\ntrailer\n<< /Info 1 0 R >>\n%%EOF\n";
+
+    let metadata = pdf_metadata(bytes).unwrap();
+
+    assert_eq!(metadata.title, "Café");
+    assert_eq!(metadata.authors, ["Café"]);
+}
+
+/// A PDF whose only objects are in a compressed object stream, listed by `header`.
+fn pdf_with_object_stream(header: &[u8], objects: &[u8]) -> Vec<u8> {
+    use std::io::Write;
+
+    let mut encoder = flate2::write::ZlibEncoder::new(Vec::new(), flate2::Compression::default());
+    encoder.write_all(header).unwrap();
+    encoder.write_all(objects).unwrap();
+    let stream = encoder.finish().unwrap();
+
+    let mut bytes = format!(
+        "%PDF-1.5\n3 0 obj\n<< /Type /ObjStm /N 2 /First {} /Filter /FlateDecode /Length ",
+        header.len()
+    )
+    .into_bytes();
+    bytes.extend(format!("{} >>\nstream\n", stream.len()).as_bytes());
+    bytes.extend(stream);
+    bytes.extend(b"\nendstream\nendobj\n4 0 obj\n<< /Type /XRef /Root 1 0 R /Info 2 0 R >>\nstream\n\nendstream\nendobj\n%%EOF\n");
+    bytes
+}
+
+#[test]
+fn pdf_info_in_a_compressed_object_stream_is_read() {
+    let objects = b"<< /Type /Catalog >> << /Title (Packed Away) /Author (two) >>";
+    let bytes = pdf_with_object_stream(b"1 0 2 21 ", objects);
+
+    let metadata = pdf_metadata(&bytes).unwrap();
+
+    assert_eq!(metadata.title, "Packed Away");
+    assert_eq!(metadata.authors, ["two"]);
+}
+
+#[test]
+fn pdf_object_stream_offsets_out_of_range_are_skipped() {
+    let objects = b"<< /Type /Catalog >> << /Title (Packed Away) >>";
+    let header = format!("1 0 2 {} ", usize::MAX);
+
+    assert!(pdf_metadata(&pdf_with_object_stream(header.as_bytes(), objects)).is_err());
+}
+
+#[test]
+fn pdf_xmp_fills_in_missing_info() {
+    let bytes = br#"%PDF-1.6
+1 0 obj
+<< /Producer (something) >>
+endobj
+2 0 obj
+<< /Type /Metadata /Subtype /XML >>
+stream
+<?xpacket begin="" id="W5M0MpCehiHzreSzNTczkc9d"?>
+<x:xmpmeta xmlns:x="adobe:ns:meta/">
+  <rdf:RDF xmlns:rdf="http://www.w3.org/1999/02/22-rdf-syntax-ns#">
+    <rdf:Description rdf:about="" xmlns:dc="http://purl.org/dc/elements/1.1/" xmlns:xmp="http://ns.adobe.com/xap/1.0/" xmp:CreateDate="2022-05-06T07:08:09Z">
+      <dc:title><rdf:Alt><rdf:li xml:lang="x-default">From &amp; XMP</rdf:li></rdf:Alt></dc:title>
+      <dc:creator><rdf:Seq><rdf:li>one</rdf:li><rdf:li>two</rdf:li></rdf:Seq></dc:creator>
+    </rdf:Description>
+  </rdf:RDF>
+</x:xmpmeta>
+<?xpacket end="w"?>
+endstream
+endobj
+trailer
+<< /Info 1 0 R >>
+%%EOF
+"#;
+
+    assert_eq!(
+        pdf_metadata(bytes).unwrap(),
+        Metadata {
+            title: "From & XMP".to_owned(),
+            authors: vec!["one".to_owned(), "two".to_owned()],
+            created: chrono::NaiveDate::from_ymd_opt(2022, 5, 6),
+            ..Metadata::default()
+        }
+    );
+}
+
+#[test]
+fn pdf_info_split_between_chunks_is_read() {
+    // Puts the object header right across the first chunk boundary
+    let mut bytes = b"%PDF-1.4\n%".to_vec();
+    bytes.resize(1024 * 1024 - 4, b'x');
+    bytes
+        .extend(b"\n12 0 obj\n<< /Title (Far In) >>\nendobj\ntrailer\n<< /Info 12 0 R >>\n%%EOF\n");
+
+    assert_eq!(pdf_metadata(&bytes).unwrap().title, "Far In");
+}
+
+#[test]
+fn pdf_without_a_title_is_an_error() {
+    assert!(pdf_metadata(b"%PDF-1.4\ntrailer\n<< /Root 1 0 R >>\n%%EOF\n").is_err());
+    assert!(pdf_metadata(b"<html></html>").is_err());
+}
+
+const OPF: &str = r#"<?xml version="1.0" encoding="UTF-8"?>
+<package xmlns="http://www.idpf.org/2007/opf" version="2.0"><metadata xmlns:dc="http://purl.org/dc/elements/1.1/"><dc:title>A</dc:title></metadata></package>"#;
+
+fn epub(entries: &[(&str, &str)]) -> Vec<u8> {
+    use std::io::Write;
+
+    let mut zip = zip::ZipWriter::new(io::Cursor::new(Vec::new()));
+    for (name, contents) in entries {
+        zip.start_file(*name, zip::write::SimpleFileOptions::default())
+            .unwrap();
+        zip.write_all(contents.as_bytes()).unwrap();
+    }
+    zip.finish().unwrap().into_inner()
+}
+
+#[test]
+fn real_downloads_are_valid() {
+    let html =
+        br#"<html><body><div id="preface"><div class="meta"><h1>A</h1></div></div></body></html>"#;
+
+    assert!(validate_bytes(Format::PDF, &crate::mock::pdf("A", "one")).is_ok());
+    assert!(validate_bytes(Format::MOBI, &crate::mock::mobi("A", "one", false)).is_ok());
+    assert!(validate_bytes(Format::AZW3, &crate::mock::mobi("A", "one", true)).is_ok());
+    assert!(validate_bytes(Format::HTML, html).is_ok());
+    assert!(
+        validate_bytes(
+            Format::EPUB,
+            &epub(&[("mimetype", "application/epub+zip"), ("content.opf", OPF)])
+        )
+        .is_ok()
+    );
+}
+
+#[test]
+fn web_pages_are_not_books() {
+    let page = b"\n<!DOCTYPE html>\n<html><body>Error 502</body></html>";
+
+    for format in [Format::EPUB, Format::PDF, Format::MOBI, Format::AZW3] {
+        let err = validate_bytes(format, page).unwrap_err();
+        assert!(format!("{err:#}").contains("web page instead"), "{err:#}");
+    }
+    let err = validate_bytes(Format::HTML, page).unwrap_err();
+    assert!(format!("{err:#}").contains("no work preface"), "{err:#}");
+}
+
+#[test]
+fn truncated_pdfs_are_invalid() {
+    let pdf = crate::mock::pdf("A", "one");
+
+    let err = validate_bytes(Format::PDF, &pdf[..pdf.len() - 40]).unwrap_err();
+
+    assert!(format!("{err:#}").contains("no trailer"), "{err:#}");
+}
+
+#[test]
+fn epubs_need_a_mimetype_and_package_document() {
+    let opf_only = epub(&[("content.opf", OPF)]);
+    let wrong_mimetype = epub(&[("mimetype", "text/plain"), ("content.opf", OPF)]);
+    let no_opf = epub(&[("mimetype", "application/epub+zip")]);
+    let broken_opf = epub(&[
+        ("mimetype", "application/epub+zip"),
+        ("content.opf", "<package><metadata></package>"),
+    ]);
+    let elsewhere = epub(&[
+        ("mimetype", "application/epub+zip"),
+        (
+            "META-INF/container.xml",
+            r#"<container><rootfiles><rootfile full-path="OEBPS/book.opf"/></rootfiles></container>"#,
+        ),
+        ("OEBPS/book.opf", OPF),
+    ]);
+
+    assert!(validate_bytes(Format::EPUB, &opf_only).is_err());
+    assert!(validate_bytes(Format::EPUB, &wrong_mimetype).is_err());
+    assert!(validate_bytes(Format::EPUB, &no_opf).is_err());
+    assert!(validate_bytes(Format::EPUB, &broken_opf).is_err());
+    assert!(validate_bytes(Format::EPUB, &elsewhere).is_ok());
+}
@@ -0,0 +1,179 @@
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fs, io,
+    path::{Path, PathBuf},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::Context;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::{Format, ao3::SeriesPosition, atomic};
+
+#[cfg(test)]
+mod tests;
+
+/// Name of the manifest kept in the output directory
+pub static LIBRARY_FILE_NAME: &str = ".ao3dl-library.json";
+
+/// Everything ao3dl remembers about the works it has already downloaded, so that later runs can
+/// skip works that haven't changed.
+#[derive(Serialize, Deserialize, Default, Clone)]
+pub struct Library {
+    works: BTreeMap<usize, WorkRecord>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct WorkRecord {
+    /// The work's `updated_at` timestamp when it was downloaded
+    pub updated_at: usize,
+    /// When the work was last downloaded, in seconds since the Unix epoch
+    pub downloaded_at: u64,
+    pub files: BTreeMap<Format, FileRecord>,
+    /// Set when the work was last downloaded as part of a series
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub series: Option<SeriesPosition>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct FileRecord {
+    /// Relative to the library's directory
+    pub path: PathBuf,
+    /// Hex-encoded SHA-256 of the file as downloaded
+    pub sha256: String,
+}
+
+impl Library {
+    /// Loads the library manifest from `dir`, or returns an empty library if there isn't one yet.
+    pub fn load(dir: &Path) -> anyhow::Result<Library> {
+        let path = dir.join(LIBRARY_FILE_NAME);
+        let contents = match fs::read_to_string(&path) {
+            Ok(contents) => contents,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                log::debug!("No library at '{}'; starting afresh", path.display());
+                return Ok(Library::default());
+            }
+            Err(e) => {
+                return Err(e)
+                    .with_context(|| format!("Cannot read library at '{}'", path.display()));
+            }
+        };
+
+        let library: Library = serde_json::from_str(&contents)
+            .with_context(|| format!("Cannot parse library at '{}'", path.display()))?;
+
+        log::debug!(
+            "Loaded library of {} work(s) from '{}'",
+            library.works.len(),
+            path.display()
+        );
+
+        Ok(library)
+    }
+
+    pub fn save(&self, dir: &Path) -> anyhow::Result<()> {
+        let path = dir.join(LIBRARY_FILE_NAME);
+        let contents = serde_json::to_string_pretty(self).context("Cannot serialize library")?;
+        atomic::write(&path, contents)
+            .with_context(|| format!("Cannot write library to '{}'", path.display()))?;
+
+        log::debug!(
+            "Saved library of {} work(s) to '{}'",
+            self.works.len(),
+            path.display()
+        );
+
+        Ok(())
+    }
+
+    pub fn len(&self) -> usize {
+        self.works.len()
+    }
+
+    pub fn get(&self, id: usize) -> Option<&WorkRecord> {
+        self.works.get(&id)
+    }
+
+    pub fn work_ids(&self) -> impl Iterator<Item = usize> + '_ {
+        self.works.keys().copied()
+    }
+
+    /// The directories the library's files are in, relative to the library's directory.
+    pub fn dirs(&self) -> BTreeSet<&Path> {
+        self.works
+            .values()
+            .flat_map(|record| record.files.values())
+            .filter_map(|file| file.path.parent())
+            .collect()
+    }
+
+    /// Whether every one of `formats` has already been downloaded from the version of the work
+    /// last updated at `updated_at`, and is still on disk under `dir`.
+    pub fn is_up_to_date(
+        &self,
+        id: usize,
+        updated_at: usize,
+        formats: &[Format],
+        dir: &Path,
+    ) -> bool {
+        let Some(record) = self.works.get(&id) else {
+            return false;
+        };
+        record.updated_at == updated_at
+            && formats.iter().all(|format| {
+                record
+                    .files
+                    .get(format)
+                    .is_some_and(|file| dir.join(&file.path).exists())
+            })
+    }
+
+    /// Notes that `work` has just been downloaded as `format` to `path` (relative to the library's
+    /// directory).
+    ///
+    /// Files recorded for an older version of the work are forgotten, since they are out of date.
+    pub fn record(
+        &mut self,
+        id: usize,
+        updated_at: usize,
+        format: Format,
+        path: PathBuf,
+        sha256: String,
+    ) {
+        let record = self.works.entry(id).or_insert_with(|| WorkRecord {
+            updated_at,
+            downloaded_at: 0,
+            files: BTreeMap::new(),
+            series: None,
+        });
+        if record.updated_at != updated_at {
+            record.updated_at = updated_at;
+            record.files.clear();
+        }
+        record.downloaded_at = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or_default();
+        record.files.insert(format, FileRecord { path, sha256 });
+    }
+
+    /// Notes that the already-recorded work with ID `id` is part of `series`.
+    pub fn set_series(&mut self, id: usize, series: SeriesPosition) {
+        if let Some(record) = self.works.get_mut(&id) {
+            record.series = Some(series);
+        }
+    }
+}
+
+/// Hex-encoded SHA-256 of `bytes`.
+pub fn sha256(bytes: &[u8]) -> String {
+    format!("{:x}", Sha256::digest(bytes))
+}
+
+/// Hex-encoded SHA-256 of everything `reader` has left, read a bit at a time.
+pub fn sha256_of(mut reader: impl io::Read) -> io::Result<String> {
+    let mut hasher = Sha256::new();
+    io::copy(&mut reader, &mut hasher)?;
+    Ok(format!("{:x}", hasher.finalize()))
+}
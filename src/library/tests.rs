@@ -0,0 +1,78 @@
+use std::fs;
+
+use super::*;
+
+#[test]
+fn missing_library_is_empty() {
+    let dir = tempfile::tempdir().unwrap();
+
+    let library = Library::load(dir.path()).unwrap();
+
+    assert!(library.works.is_empty());
+}
+
+#[test]
+fn library_round_trips_through_disk() {
+    let dir = tempfile::tempdir().unwrap();
+    let mut library = Library::default();
+    library.record(
+        1,
+        1700000000,
+        Format::EPUB,
+        "Title [ao3 1].epub".into(),
+        sha256(b"epub"),
+    );
+    library.record(
+        1,
+        1700000000,
+        Format::PDF,
+        "Title [ao3 1].pdf".into(),
+        sha256(b"pdf"),
+    );
+
+    library.save(dir.path()).unwrap();
+    let reloaded = Library::load(dir.path()).unwrap();
+
+    assert_eq!(reloaded.works.get(&1), library.works.get(&1));
+    let record = reloaded.works.get(&1).unwrap();
+    assert_eq!(record.updated_at, 1700000000);
+    assert_eq!(
+        record.files[&Format::EPUB].sha256,
+        "83e895b8f0af41ca0905b4e89c6979eab60b36a62bdb8efc4730dfd446aded7f"
+    );
+    assert!(
+        fs::read_to_string(dir.path().join(LIBRARY_FILE_NAME))
+            .unwrap()
+            .contains(r#""pdf": {"#)
+    );
+}
+
+#[test]
+fn up_to_date_needs_same_timestamp_and_every_file_on_disk() {
+    let dir = tempfile::tempdir().unwrap();
+    fs::write(dir.path().join("a.epub"), "epub").unwrap();
+    let mut library = Library::default();
+    library.record(1, 100, Format::EPUB, "a.epub".into(), sha256(b"epub"));
+    library.record(1, 100, Format::HTML, "a.html".into(), sha256(b"html"));
+
+    assert!(library.is_up_to_date(1, 100, &[Format::EPUB], dir.path()));
+    assert!(!library.is_up_to_date(1, 101, &[Format::EPUB], dir.path()));
+    assert!(!library.is_up_to_date(2, 100, &[Format::EPUB], dir.path()));
+    // Recorded, but deleted since
+    assert!(!library.is_up_to_date(1, 100, &[Format::HTML], dir.path()));
+    // Never downloaded as a PDF
+    assert!(!library.is_up_to_date(1, 100, &[Format::EPUB, Format::PDF], dir.path()));
+}
+
+#[test]
+fn newer_version_replaces_old_files() {
+    let mut library = Library::default();
+    library.record(1, 100, Format::EPUB, "old.epub".into(), sha256(b"old"));
+    library.record(1, 100, Format::HTML, "old.html".into(), sha256(b"old"));
+
+    library.record(1, 200, Format::EPUB, "new.epub".into(), sha256(b"new"));
+
+    let record = library.works.get(&1).unwrap();
+    assert_eq!(record.updated_at, 200);
+    assert_eq!(record.files.keys().collect::<Vec<_>>(), [&Format::EPUB]);
+}
@@ -1,31 +1,185 @@
 use std::{
-    collections::{HashMap, HashSet},
+    collections::{BTreeSet, HashMap, HashSet},
     env, fs,
     io::{IsTerminal, Write},
-    path::PathBuf,
+    path::{Path, PathBuf},
     process,
-    sync::{Mutex, OnceLock},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant, UNIX_EPOCH},
 };
 
-use anyhow::Context;
+use anyhow::{Context, bail};
 use clap::{Parser, ValueEnum};
-use regex::Regex;
+use reqwest::Url;
+use serde::{Deserialize, Serialize};
+use tokio::{sync::Semaphore, task::JoinSet};
 
-use crate::ao3::WorkId;
+use crate::{
+    ao3::{SeriesPosition, WorkId},
+    library::Library,
+};
 
 mod ao3;
+mod atomic;
+mod credentials;
 mod extractor;
+mod library;
+#[cfg(test)]
+mod mock;
+mod naming;
+mod scrape;
+mod sidecar;
+#[cfg(test)]
+mod tests;
 
 #[derive(Parser)]
 struct Cli {
-    works_file: PathBuf,
+    #[arg(required_unless_present_any = ["update", "bookmarks", "inspect"])]
+    works_file: Option<PathBuf>,
     #[arg(long = "format", value_enum, default_values_t = vec![Format::EPUB])]
     formats: Vec<Format>,
     #[arg(long)]
     unzip_epubs: bool,
+    /// Base URL of the archive to download from (e.g. a mirror, or a local test server)
+    #[arg(long, env = "AO3_BASE_URL", default_value = ao3::DEFAULT_BASE_URL)]
+    base_url: Url,
+    /// Number of works to download at the same time
+    #[arg(
+        long,
+        default_value_t = 1,
+        value_parser = clap::builder::RangedU64ValueParser::<usize>::new().range(1..)
+    )]
+    jobs: usize,
+    /// Download works even if the library says they are already up to date
+    #[arg(long)]
+    force: bool,
+    /// Check every work already in the library, and re-download the ones that have been updated
+    #[arg(long, conflicts_with = "force")]
+    update: bool,
+    /// Also download every work you have bookmarked, including private bookmarks
+    #[arg(long)]
+    bookmarks: bool,
+    /// Download without logging in, so no username or password is needed. Works only shown to
+    /// logged-in users are reported as restricted
+    #[arg(long, conflicts_with = "bookmarks")]
+    no_login: bool,
+    /// Read the password from the first line of standard input, e.g. piped from a password manager
+    #[arg(long, conflicts_with_all = ["no_login", "password_command"])]
+    password_stdin: bool,
+    /// Run this command with the shell and log in with the first line it prints, e.g. `pass show
+    /// ao3`
+    #[arg(long, value_name = "COMMAND", conflicts_with = "no_login")]
+    password_command: Option<String>,
+    /// JSON file with a `username` and a `password` or `password_command` to log in with
+    /// [default: ~/.config/ao3dl/config.json]
+    #[arg(long, value_name = "PATH")]
+    config: Option<PathBuf>,
+    /// Instead of downloading anything, write the works that would have been downloaded to this
+    /// file, in the same format as the works file
+    #[arg(long, value_name = "PATH")]
+    save_works_list: Option<PathBuf>,
+    /// Instead of downloading anything, print what can be read from these already-downloaded files
+    /// (or every download in these directories) as JSON lines, without going online
+    #[arg(long, value_name = "PATH", num_args = 1.., conflicts_with_all = ["update", "bookmarks"])]
+    inspect: Vec<PathBuf>,
+    /// Write a metadata file of this kind next to each downloaded work
+    #[arg(long = "sidecar", value_enum)]
+    sidecars: Vec<sidecar::Sidecar>,
+    /// Take at most this many works from each tag, search, collection or user listing in the works
+    /// file
+    #[arg(
+        long,
+        value_name = "N",
+        value_parser = clap::builder::RangedU64ValueParser::<usize>::new().range(1..)
+    )]
+    max_works: Option<usize>,
+    /// Fetch at most this many pages of each tag, search, collection or user listing in the works
+    /// file
+    #[arg(
+        long,
+        value_name = "N",
+        value_parser = clap::builder::RangedU64ValueParser::<usize>::new().range(1..)
+    )]
+    max_pages: Option<usize>,
+    /// Where to save each download, relative to the download directory. Can use {title},
+    /// {author}, {id}, {fandom}, {series}, {series_index}, {rating}, {updated:%Y-%m-%d} and
+    /// {ext}, and a `/` starts a subdirectory
+    #[arg(long, value_name = "TEMPLATE", default_value = naming::DEFAULT_TEMPLATE)]
+    name_template: naming::Template,
+    /// Which file systems to make file and directory names safe for
+    #[arg(long, value_enum, default_value_t = naming::Sanitizer::default())]
+    sanitize: naming::Sanitizer,
+    /// Directory to download into, and keep the library of downloaded works in (created if
+    /// missing)
+    #[arg(long, value_name = "DIR", default_value = ".")]
+    output_dir: PathBuf,
+    /// Put each format in its own subdirectory of the output directory, such as `epub/` and `pdf/`
+    #[arg(long)]
+    format_dirs: bool,
+    /// Where to list the works that failed to download [default: failed-works.txt in the output
+    /// directory]
+    #[arg(long, value_name = "PATH")]
+    failure_report: Option<PathBuf>,
+    /// Where to list the works that were skipped because only logged-in users can see them
+    /// [default: restricted-works.txt in the output directory]
+    #[arg(long, value_name = "PATH")]
+    restricted_report: Option<PathBuf>,
+    /// Where to keep the login session between runs, so that ao3dl only logs in once it has
+    /// expired [default: ~/.local/state/ao3dl/session.json]
+    #[arg(long, value_name = "PATH")]
+    session_file: Option<PathBuf>,
+    /// What to do when a download's file name is already taken
+    #[arg(long, value_enum, default_value_t = OnExisting::Overwrite)]
+    on_existing: OnExisting,
+    /// Give up on downloads bigger than this, in bytes or with a K, M or G suffix (e.g. `50M`)
+    #[arg(long, value_name = "SIZE", value_parser = parse_size)]
+    max_size: Option<u64>,
 }
 
-#[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, ValueEnum, Debug)]
+/// What to do with a file that is in the way of a download
+#[derive(Copy, Clone, PartialEq, Eq, ValueEnum, Debug)]
+enum OnExisting {
+    /// Keep the existing file, and don't download the work if its name can be told without. The
+    /// work is checked again on the next run, since the existing file may be of any version
+    Skip,
+    /// Replace the existing file
+    Overwrite,
+    /// Save the download as `{name} (2).{ext}`, or the first such name that is free
+    Rename,
+    /// If the work has been updated since, keep the existing file as
+    /// `{name}.{old_updated_at}.{ext}`
+    Version,
+}
+
+/// What happened to a single work during a run
+#[derive(Copy, Clone, PartialEq, Eq, Hash, Debug)]
+enum Outcome {
+    Downloaded,
+    /// Skipped, since the library says it hasn't been updated since it was last downloaded
+    Unchanged,
+    /// No longer on the archive
+    Vanished,
+    /// Only shown to logged-in users, e.g. with `--no-login`
+    Restricted,
+    /// Every file was already there, and kept by `--on-existing skip`
+    Kept,
+    Failed,
+}
+
+impl Outcome {
+    /// How a work that failed with `e` is reported.
+    fn of_error(e: &anyhow::Error) -> Outcome {
+        match e.downcast_ref() {
+            Some(ao3::Error::NotFound) => Outcome::Vanished,
+            Some(ao3::Error::Restricted) => Outcome::Restricted,
+            None => Outcome::Failed,
+        }
+    }
+}
+
+#[allow(clippy::upper_case_acronyms)]
+#[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, ValueEnum, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
 enum Format {
     // Sorted in terms of preference for extracting the title
     EPUB,
@@ -45,6 +199,16 @@ impl Format {
             Format::HTML => "html",
         }
     }
+
+    fn media_type(&self) -> &'static str {
+        match self {
+            Format::AZW3 => "application/x-mobi8-ebook",
+            Format::EPUB => "application/epub+zip",
+            Format::MOBI => "application/x-mobipocket-ebook",
+            Format::PDF => "application/pdf",
+            Format::HTML => "text/html",
+        }
+    }
 }
 
 struct ProgressBar {
@@ -63,7 +227,7 @@ impl ProgressBar {
         ProgressBar {
             isatty: std::io::stdout().is_terminal(),
             current: 0,
-            max: max,
+            max,
             error: false,
         }
     }
@@ -99,7 +263,6 @@ impl ProgressBar {
         }
         if std::io::stdout().flush().is_err() {
             self.isatty = false;
-            return;
         }
     }
 }
@@ -116,9 +279,9 @@ struct IndeterminateProgressBar {
 
 impl IndeterminateProgressBar {
     fn new() -> IndeterminateProgressBar {
-        return IndeterminateProgressBar {
+        IndeterminateProgressBar {
             isatty: std::io::stdout().is_terminal(),
-        };
+        }
     }
 
     fn begin(&mut self) {
@@ -145,7 +308,6 @@ impl IndeterminateProgressBar {
         }
         if std::io::stdout().flush().is_err() {
             self.isatty = false;
-            return;
         }
     }
 }
@@ -172,27 +334,187 @@ async fn main() -> anyhow::Result<()> {
         process::exit(64); // usage
     }
 
-    let _work_regex = Regex::new(r"https://archiveofourown\.org/works/(\d+)").unwrap();
-    let raw_work_ids = fs::read_to_string(args.works_file)
-        .context("Cannot read works file")?
-        .lines()
-        .filter_map(|line| {
-            if let Ok(work_id) = serde_json::from_str(line) {
-                Some(work_id)
-            } else if let Ok(id) = line.parse::<usize>() {
-                Some(ao3::WorkId::Bare(id))
-            } else if let Some(captures) = _work_regex.captures(line) {
-                captures
-                    .get(1)?
-                    .as_str()
-                    .parse::<usize>()
-                    .ok()
-                    .map(|id| ao3::WorkId::Bare(id))
-            } else {
-                None
+    // Only looked through once it turns out that logging in is needed
+    let sources = credential_sources(&args);
+    let credentials = || sources?.resolve(std::io::stdin().lock());
+    run(args, Path::new("."), ao3::Clock::Tokio, credentials).await
+}
+
+/// How often the library is saved while works are downloading, besides once they are all done
+const LIBRARY_SAVE_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Downloads every work in the works file into the output directory, resolving relative paths
+/// against `cwd`.
+///
+/// `main` only parses arguments; everything else happens here, with the clock and the source of
+/// credentials passed in so that tests can drive the whole flow against a mock archive.
+async fn run(
+    args: Cli,
+    cwd: &Path,
+    clock: ao3::Clock,
+    credentials: impl FnOnce() -> anyhow::Result<(String, String)>,
+) -> anyhow::Result<()> {
+    if !args.inspect.is_empty() {
+        return inspect(&args.inspect);
+    }
+
+    let dest = &cwd.join(&args.output_dir);
+    fs::create_dir_all(dest)
+        .with_context(|| format!("Cannot create output directory '{}'", dest.display()))?;
+    let library =
+        Library::load(dest).context("Cannot load library of previously downloaded works")?;
+    // Held until the run ends, so that another run in the same directory leaves its temporaries be
+    let _lock = match atomic::lock(dest) {
+        Some(lock) => {
+            clean_up(dest, &library, &args);
+            Some(lock)
+        }
+        None => {
+            log::warn!(
+                "Another run may be using '{}', so not cleaning up after interrupted runs",
+                dest.display()
+            );
+            None
+        }
+    };
+
+    let works_file = match &args.works_file {
+        Some(path) => fs::read_to_string(path).context("Cannot read works file")?,
+        None => String::new(),
+    };
+    let mut raw_work_ids = Vec::<ao3::WorkId>::new();
+    // Chapters, series and listings are expanded once logged in, since they can lead to
+    // restricted works
+    let mut chapter_ids = Vec::<usize>::new();
+    let mut series_ids = Vec::<usize>::new();
+    let mut listings = Vec::<ao3::WorkListing>::new();
+    for (index, line) in works_file.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        match ao3::WorkRef::parse(line, &args.base_url) {
+            Ok(ao3::WorkRef::Work(work_id)) => raw_work_ids.push(work_id),
+            Ok(ao3::WorkRef::Chapter(id)) => chapter_ids.push(id),
+            Ok(ao3::WorkRef::Series(id)) => series_ids.push(id),
+            Ok(ao3::WorkRef::Listing(listing)) => listings.push(listing),
+            Err(e) => {
+                eprintln!(
+                    "Ignoring line {} of the works file ({}): {:#}",
+                    index + 1,
+                    line,
+                    e
+                );
+            }
+        }
+    }
+
+    if args.update {
+        // Deliberately without timestamps, so that the current ones are fetched from the archive
+        log::info!(
+            "Checking {} work(s) in the library for updates",
+            library.len()
+        );
+        raw_work_ids.extend(library.work_ids().map(ao3::WorkId::Bare));
+    }
+
+    if raw_work_ids.is_empty()
+        && chapter_ids.is_empty()
+        && series_ids.is_empty()
+        && listings.is_empty()
+        && !args.bookmarks
+    {
+        log::info!("Exiting early since there is nothing to download");
+        return Ok(());
+    }
+
+    let client = ao3::make_client(&args.base_url, clock)
+        .context("Could not make client (this is not user error and should never happen)")?;
+
+    log::debug!("Successfully created client");
+
+    let username = if args.no_login {
+        log::info!("Not logging in; works only shown to logged-in users will be skipped");
+        None
+    } else {
+        let session_file = match &args.session_file {
+            Some(path) => cwd.join(path),
+            None => credentials::default_session_file().context(
+                "Cannot find a home directory to keep the session in, so use --session-file",
+            )?,
+        };
+        Some(log_in(&client, &session_file, credentials).await?)
+    };
+
+    if args.bookmarks
+        && let Some(username) = &username
+    {
+        raw_work_ids.extend(
+            ao3::bookmarks(&client, username)
+                .await
+                .context("Cannot fetch bookmarked works")?,
+        );
+    }
+
+    for id in chapter_ids {
+        match ao3::work_of_chapter(&client, id).await {
+            Ok(work_id) => raw_work_ids.push(ao3::WorkId::Bare(work_id)),
+            Err(e) => {
+                log::warn!(
+                    "Skipping chapter with ID {}, because {}",
+                    id,
+                    error_chain(&e)
+                );
+            }
+        }
+    }
+
+    // Series and listings that couldn't be expanded, as links for the failure report
+    let mut failed_sources = Vec::<String>::new();
+    let base_url = args.base_url.as_str().trim_end_matches('/');
+
+    // A work can be in several series; it is named after the first one listed
+    let mut series_positions = HashMap::<usize, SeriesPosition>::new();
+    for id in series_ids {
+        let series = match ao3::series(&client, id).await {
+            Ok(series) => series,
+            Err(e) => {
+                log::warn!(
+                    "Skipping series with ID {}, because {}",
+                    id,
+                    error_chain(&e)
+                );
+                failed_sources.push(format!("{}/series/{}", base_url, id));
+                continue;
             }
-        })
-        .collect::<Vec<_>>();
+        };
+        log::info!(
+            "Series '{}' has {} work(s)",
+            series.title,
+            series.works.len()
+        );
+        for (work, position) in series.positions() {
+            series_positions.entry(*work.id()).or_insert(position);
+        }
+        raw_work_ids.extend(series.works);
+    }
+
+    let limits = ao3::ListingLimits {
+        max_works: args.max_works,
+        max_pages: args.max_pages,
+    };
+    for listing in listings {
+        let works = match ao3::works_in(&client, &listing, limits).await {
+            Ok(works) => works,
+            Err(e) => {
+                log::warn!("Skipping {}, because {}", listing, error_chain(&e));
+                failed_sources.push(format!("{}{}", base_url, listing.path()));
+                continue;
+            }
+        };
+        log::info!("Found {} work(s) in {}", works.len(), listing);
+        raw_work_ids.extend(works);
+    }
 
     log::trace!("Detected {} works", raw_work_ids.len());
 
@@ -226,85 +548,449 @@ async fn main() -> anyhow::Result<()> {
 
     log::info!("Detected {} works", work_ids.len());
 
+    if let Some(path) = &args.save_works_list {
+        write_works_list(&work_ids, path)
+            .with_context(|| format!("Cannot write works list to {}", path.display()))?;
+        log::info!("Works list written to {}", path.display());
+        return Ok(());
+    }
+
     if work_ids.is_empty() {
         log::info!("Exiting early since there is nothing to download");
-        process::exit(0);
+        return Ok(());
+    }
+
+    let mut name_template = args.name_template.sanitized_for(args.sanitize);
+    if args.format_dirs {
+        name_template = name_template.in_format_dirs();
     }
+    let downloader = Arc::new(Downloader {
+        pb: Mutex::new(ProgressBar::new(work_ids.len() * args.formats.len())),
+        client,
+        formats: args.formats,
+        unzip_epubs: args.unzip_epubs,
+        dest: dest.to_path_buf(),
+        force: args.force,
+        library: Mutex::new(library),
+        series: series_positions,
+        sidecars: args.sidecars,
+        name_template,
+        on_existing: args.on_existing,
+        max_size: args.max_size,
+        extracted: Mutex::new(HashMap::new()),
+    });
+    let workers = Arc::new(Semaphore::new(args.jobs));
+
+    log::debug!("Downloading with {} worker(s)", args.jobs);
 
-    let username = match env::var("USERNAME") {
-        Ok(u) => u,
-        Err(env::VarError::NotPresent) => {
-            let mut tmp = String::new();
-            print!("Username? ");
-            std::io::stdout().flush().unwrap();
-            std::io::stdin().read_line(&mut tmp).unwrap();
-            tmp.pop(); // the newline
-            tmp
+    let mut failed_work_ids = HashSet::<usize>::new();
+    let mut outcomes = HashMap::<Outcome, Vec<usize>>::new();
+    let mut downloads = JoinSet::new();
+
+    {
+        let mut pb = downloader.pb.lock().unwrap();
+        pb.begin();
+        pb.next();
+    }
+    for work in work_ids {
+        // Only spawn once a worker is free, so there are never more than `--jobs` downloads
+        let permit = workers
+            .clone()
+            .acquire_owned()
+            .await
+            .context("Worker pool closed unexpectedly")?;
+        let downloader = downloader.clone();
+        downloads.spawn(async move {
+            let outcome = downloader.download_formats(&work).await;
+            drop(permit);
+            (*work.id(), outcome)
+        });
+    }
+    let mut last_save = Instant::now();
+    while let Some(result) = downloads.join_next().await {
+        let (id, outcome) = result.context("Download task panicked")?;
+        if matches!(outcome, Outcome::Vanished | Outcome::Failed) {
+            failed_work_ids.insert(id);
         }
-        Err(env::VarError::NotUnicode(_)) => {
-            log::error!("Found USERNAME env var, but the contents were not valid Unicode!");
-            process::exit(1);
+        outcomes.entry(outcome).or_default().push(id);
+
+        // So that a crash or Ctrl-C doesn't lose track of what was done. Not after every work,
+        // since the whole library is written out each time
+        if last_save.elapsed() >= LIBRARY_SAVE_INTERVAL {
+            if let Err(e) = downloader.save_library() {
+                log::warn!("{}", error_chain(&e));
+            }
+            last_save = Instant::now();
         }
-    };
+    }
+    downloader.pb.lock().unwrap().end();
+
+    if args.update {
+        let count = |outcome| outcomes.get(&outcome).map_or(0, Vec::len);
+        println!(
+            "{} updated, {} kept, {} unchanged, {} vanished, {} restricted, {} failed",
+            count(Outcome::Downloaded),
+            count(Outcome::Kept),
+            count(Outcome::Unchanged),
+            count(Outcome::Vanished),
+            count(Outcome::Restricted),
+            count(Outcome::Failed)
+        );
+        if let Some(vanished) = outcomes.get_mut(&Outcome::Vanished) {
+            vanished.sort();
+            let vanished = vanished
+                .iter()
+                .map(|id| id.to_string())
+                .collect::<Vec<_>>()
+                .join(", ");
+            println!("No longer on the archive: {}", vanished);
+        }
+    }
 
-    let password = match env::var("PASSWORD") {
-        Ok(p) => p,
-        Err(env::VarError::NotPresent) => rpassword::prompt_password("Password? ").unwrap(),
-        Err(env::VarError::NotUnicode(_)) => {
-            log::error!("Found PASSWORD env var, but the contents were not valid Unicode!");
-            process::exit(1);
+    if let Some(restricted) = outcomes.get_mut(&Outcome::Restricted) {
+        restricted.sort();
+        let listed = restricted
+            .iter()
+            .map(|id| id.to_string())
+            .collect::<Vec<_>>()
+            .join(", ");
+        if args.no_login {
+            println!(
+                "Only logged-in users can see these works, so run without --no-login to download them: {}",
+                listed
+            );
+        } else {
+            println!("Only logged-in users can see these works: {}", listed);
         }
-    };
 
-    log::debug!("Got username and password");
+        let restricted_report = match &args.restricted_report {
+            Some(path) => cwd.join(path),
+            None => dest.join("restricted-works.txt"),
+        };
+        create_parent_dir(&restricted_report)?;
+        let restricted = restricted.iter().copied().collect();
+        write_lines_sorted(&restricted, &[], &restricted_report).with_context(|| {
+            format!(
+                "Cannot write list of works only logged-in users can see to '{}'",
+                restricted_report.display()
+            )
+        })?;
+
+        log::info!(
+            "IDs of works only logged-in users can see written to '{}'",
+            restricted_report.display()
+        );
+    }
 
-    let client = ao3::make_client()
-        .context("Could not make client (this is not user error and should never happen)")?;
+    downloader.save_library()?;
 
-    log::debug!("Successfully created client");
+    if !failed_work_ids.is_empty() || !failed_sources.is_empty() {
+        if !failed_work_ids.is_empty() {
+            log::warn!(
+                "Failed to download a total of {} work(s)",
+                failed_work_ids.len()
+            );
+        }
+        if !failed_sources.is_empty() {
+            log::warn!(
+                "Failed to expand a total of {} series or listing(s)",
+                failed_sources.len()
+            );
+        }
 
-    log::debug!("Attempting to log in");
+        let failure_report = match &args.failure_report {
+            Some(path) => cwd.join(path),
+            None => dest.join("failed-works.txt"),
+        };
+        create_parent_dir(&failure_report)?;
+        // Sort failed works before writing so that the file is diffable if you rerun ao3dl on it.
+        // Series and listings that failed follow as links, so that rerunning on it retries them
+        write_lines_sorted(&failed_work_ids, &failed_sources, &failure_report).with_context(
+            || {
+                format!(
+                    "Cannot write list of works that failed to download to '{}'",
+                    failure_report.display()
+                )
+            },
+        )?;
+
+        log::info!(
+            "IDs of failing-to-download works written to '{}'",
+            failure_report.display()
+        );
+    }
+
+    Ok(())
+}
+
+/// Removes whatever interrupted runs left behind in the directories this run writes to: the output
+/// directory, the format directories and wherever the library's files are.
+fn clean_up(dest: &Path, library: &Library, args: &Cli) {
+    let mut dirs = BTreeSet::from([dest.to_path_buf()]);
+    if args.format_dirs {
+        dirs.extend(args.formats.iter().map(|f| dest.join(f.file_extension())));
+    }
+    dirs.extend(library.dirs().into_iter().map(|dir| dest.join(dir)));
+    for dir in dirs {
+        atomic::clean_up(&dir);
+    }
+}
 
-    let mut pb = IndeterminateProgressBar::new();
+/// Logs in, unless the session saved at `session_file` is still logged in, and saves the session
+/// for the next run. Returns who is logged in.
+async fn log_in(
+    client: &ao3::Client,
+    session_file: &Path,
+    credentials: impl FnOnce() -> anyhow::Result<(String, String)>,
+) -> anyhow::Result<String> {
+    let username = match resumed_session(client, session_file).await {
+        Some(username) => {
+            log::info!("Still logged in as {} from an earlier run", username);
+            username
+        }
+        None => {
+            let (username, password) = credentials()?;
 
-    pb.begin();
-    ao3::login(&client, &username, &password)
-        .await
-        .context("Could not log in. Check your username/password")?;
-    pb.end();
+            log::debug!("Got username and password");
 
-    log::info!("Successfully logged in");
+            log::debug!("Attempting to log in");
 
-    let mut pb = ProgressBar::new(work_ids.len() * args.formats.len());
+            let mut pb = IndeterminateProgressBar::new();
 
-    let mut failed_work_ids = HashSet::<usize>::new();
+            pb.begin();
+            ao3::login(client, &username, &password)
+                .await
+                .context("Could not log in. Check your username/password")?;
+            pb.end();
 
-    pb.begin();
-    pb.next();
-    for work in work_ids {
-        let mut formats_left = args.formats.len();
+            log::info!("Successfully logged in");
+
+            // The login may have been an email address, which bookmarks can't be found by
+            match ao3::logged_in_user(client).await {
+                Ok(Some(account)) => account,
+                Ok(None) => {
+                    log::warn!("Cannot tell which account {} logged in to", username);
+                    username
+                }
+                Err(e) => {
+                    log::warn!(
+                        "Cannot tell which account {} logged in to: {}",
+                        username,
+                        error_chain(&e)
+                    );
+                    username
+                }
+            }
+        }
+    };
+    create_parent_dir(session_file)?;
+    client
+        .save_session(session_file)
+        .context("Cannot save login session for the next run")?;
+
+    Ok(username)
+}
+
+/// Picks up the session saved at `session_file` by an earlier run, returning who it is logged in as
+/// if it hasn't expired yet.
+async fn resumed_session(client: &ao3::Client, session_file: &Path) -> Option<String> {
+    match client.load_session(session_file) {
+        Ok(true) => {}
+        Ok(false) => return None,
+        Err(e) => {
+            log::warn!("Ignoring saved session: {:#}", e);
+            return None;
+        }
+    }
+    match ao3::logged_in_user(client).await {
+        Ok(Some(username)) => Some(username),
+        Ok(None) => {
+            log::info!("Saved session has expired; logging in again");
+            None
+        }
+        Err(e) => {
+            log::warn!("Cannot check whether saved session is still valid: {:#}", e);
+            None
+        }
+    }
+}
+
+/// Everything the downloads of a run share
+struct Downloader {
+    client: ao3::Client,
+    formats: Vec<Format>,
+    unzip_epubs: bool,
+    dest: PathBuf,
+    /// Download works even if the library says they are already up to date
+    force: bool,
+    pb: Mutex<ProgressBar>,
+    library: Mutex<Library>,
+    /// Where the works that came from series URLs sit in their series, by work ID
+    series: HashMap<usize, SeriesPosition>,
+    sidecars: Vec<sidecar::Sidecar>,
+    name_template: naming::Template,
+    on_existing: OnExisting,
+    /// Give up on downloads bigger than this many bytes
+    max_size: Option<u64>,
+    /// What was extracted from each work's first download, by work ID, for naming its other formats
+    extracted: Mutex<HashMap<usize, extractor::Metadata>>,
+}
+
+impl Downloader {
+    /// Downloads `work` in each format in turn, stopping at the first format that fails, and
+    /// records what was downloaded in the library.
+    ///
+    /// Works that the library says are already up to date are skipped, unless `force` is set.
+    async fn download_formats(&self, work: &ao3::WorkId) -> Outcome {
+        let Downloader {
+            client,
+            formats,
+            unzip_epubs: _,
+            dest,
+            force,
+            pb,
+            library,
+            series,
+            sidecars: _,
+            name_template,
+            on_existing: _,
+            max_size: _,
+            extracted: _,
+        } = self;
+        let updated_at = match ao3::updated_at(client, work).await {
+            Ok(updated_at) => updated_at,
+            Err(e) => {
+                log::warn!(
+                    "Cannot determine when work with ID {} was last updated, because {}",
+                    work.id(),
+                    error_chain(&e)
+                );
+                let mut pb = pb.lock().unwrap();
+                for _ in formats {
+                    pb.error = true;
+                    pb.next();
+                }
+                return Outcome::of_error(&e);
+            }
+        };
+        // The series listing leaves out works it can't show, which shifts every later work, so
+        // "Part N of" on the work page (fetched for works without a timestamp) wins over it
+        let series = series.get(work.id()).map(|listed| {
+            ao3::cached_work_metadata(client, *work.id())
+                .and_then(|metadata| {
+                    metadata
+                        .series
+                        .into_iter()
+                        .find(|series| series.id == listed.id)
+                })
+                .unwrap_or_else(|| listed.clone())
+        });
 
-        for f in &args.formats {
-            let res = download_work(&client, &work, *f, args.unzip_epubs)
+        if !force
+            && library
+                .lock()
+                .unwrap()
+                .is_up_to_date(*work.id(), updated_at, formats, dest)
+        {
+            if let Some(series) = &series {
+                library
+                    .lock()
+                    .unwrap()
+                    .set_series(*work.id(), series.clone());
+            }
+            log::info!(
+                "Skipping work with ID {} since it hasn't been updated since it was last downloaded",
+                work.id()
+            );
+            {
+                let mut pb = pb.lock().unwrap();
+                for _ in formats {
+                    pb.error = false;
+                    pb.next();
+                }
+            }
+            // In case sidecars were only asked for after the work was downloaded
+            return match self.write_sidecars(*work.id(), false).await {
+                Ok(()) => Outcome::Unchanged,
+                Err(e) => {
+                    log::warn!("{}", error_chain(&e));
+                    Outcome::Failed
+                }
+            };
+        }
+
+        // Only works without a timestamp have had their work page fetched, so far
+        let metadata = if name_template.needs_work_page() {
+            match ao3::work_metadata(client, *work.id()).await {
+                Ok(metadata) => Some(metadata),
+                Err(e) => {
+                    log::warn!(
+                        "Cannot name work with ID {}, because {}",
+                        work.id(),
+                        error_chain(&e)
+                    );
+                    let mut pb = pb.lock().unwrap();
+                    for _ in formats {
+                        pb.error = true;
+                        pb.next();
+                    }
+                    return Outcome::of_error(&e);
+                }
+            }
+        } else {
+            ao3::cached_work_metadata(client, *work.id())
+        };
+        let mut fields = match &metadata {
+            Some(metadata) => naming::Fields::from_work(metadata),
+            None => naming::Fields {
+                id: *work.id(),
+                ..naming::Fields::default()
+            },
+        };
+        fields.updated_at = Some(updated_at);
+        if let Some(series) = &series {
+            fields.series = Some(series.clone());
+        }
+        let previous_updated_at = library
+            .lock()
+            .unwrap()
+            .get(*work.id())
+            .map(|record| record.updated_at);
+        let mut formats_left = formats.len();
+        let mut saved_any = false;
+
+        for f in formats {
+            let res = self
+                .download_work(work, &fields, *f, previous_updated_at)
                 .await
                 .with_context(|| {
                     format!("Cannot download work with ID {} as {:?}", &work.id(), *f)
                 });
 
             match res {
-                Ok(_) => {
+                Ok(saved) => {
+                    if let Some((file_path, sha256)) = saved {
+                        let file_path = file_path
+                            .strip_prefix(dest)
+                            .unwrap_or(&file_path)
+                            .to_path_buf();
+                        library.lock().unwrap().record(
+                            *work.id(),
+                            updated_at,
+                            *f,
+                            file_path,
+                            sha256,
+                        );
+                        saved_any = true;
+                    }
+
                     formats_left -= 1;
+                    let mut pb = pb.lock().unwrap();
                     pb.error = false;
                     pb.next();
                 }
                 Err(e) => {
-                    let msg = e
-                        .chain()
-                        .map(|link| link.to_string())
-                        .collect::<Vec<String>>()
-                        .join(", because ");
-                    log::warn!("{}", msg);
-                    failed_work_ids.insert(*work.id());
+                    log::warn!("{}", error_chain(&e));
 
                     match formats_left {
                         0 => {} // Can never happen
@@ -316,244 +1002,545 @@ async fn main() -> anyhow::Result<()> {
                             log::warn!("Skipping {} remaining formats", formats_left - 1);
                         }
                     }
+                    let mut pb = pb.lock().unwrap();
                     for _ in 0..formats_left {
                         pb.error = true;
                         pb.next();
                     }
-                    break;
+                    return Outcome::of_error(&e);
                 }
             };
         }
-    }
-    pb.end();
-
-    if !failed_work_ids.is_empty() {
-        log::warn!(
-            "Failed to download a total of {} work(s)",
-            failed_work_ids.len()
-        );
 
-        // Sort failed works before writing so that the file is diffable if you rerun ao3dl on it
-        write_lines_sorted(&failed_work_ids, "failed-works.txt")
-            .context("Cannot write list of works that failed to download to failed-works.txt")?;
+        // Kept files may be of any version, so the library and sidecars are left as they were
+        if !saved_any {
+            return Outcome::Kept;
+        }
 
-        log::info!("IDs of failing-to-download works written to failed-works.txt");
-    }
+        if let Some(series) = &series {
+            library
+                .lock()
+                .unwrap()
+                .set_series(*work.id(), series.clone());
+        }
 
-    Ok(())
-}
+        if let Err(e) = self.write_sidecars(*work.id(), true).await {
+            log::warn!("{}", error_chain(&e));
+            return Outcome::Failed;
+        }
 
-async fn download_work(
-    client: &reqwest::Client,
-    work: &ao3::WorkId,
-    format: Format,
-    unzip: bool,
-) -> anyhow::Result<()> {
-    log::debug!(
-        "Attempting to download work with ID {} as {:?}",
-        work.id(),
-        format
-    );
+        Outcome::Downloaded
+    }
 
-    static CACHE_MUTEX: OnceLock<Mutex<HashMap<usize, String>>> = OnceLock::new();
-    CACHE_MUTEX.get_or_init(|| Mutex::new(HashMap::new()));
-    let mut cache = CACHE_MUTEX
-        .get()
-        .with_context(|| "Cannot get filename cache")?
-        .lock()
-        .unwrap();
-
-    let bytes = ao3::download(&client, &work, format)
-        .await
-        .context("Could not download data")?;
-
-    log::info!(
-        "Successfully downloaded work with ID {} as {:?}",
-        work.id(),
-        format
-    );
+    /// Writes the library to disk, with everything downloaded so far. Only called from one place at
+    /// a time, since saves share a temporary file.
+    fn save_library(&self) -> anyhow::Result<()> {
+        // Written from a copy, so that downloads can carry on recording works in the meantime
+        let library = self.library.lock().unwrap().clone();
+        library
+            .save(&self.dest)
+            .context("Cannot save library of downloaded works")
+    }
 
-    match format {
-        Format::AZW3 => {
-            let file_name = match cache.get(work.id()) {
-                Some(name) => {
-                    log::trace!("Found file name in cache");
-                    name.to_owned()
-                }
-                None => {
-                    // Don't currently know how to extract a title from an AZW3
-                    log::trace!("Could not find file name in cache; defaulting to work ID");
-                    format!("[ao3 {}]", work.id())
-                }
-            };
-            let file_path = format!(
-                "{file_name}.{extension}",
-                extension = format.file_extension()
-            );
+    /// Writes every requested sidecar for the work with ID `id`, which must already be in the
+    /// library. Unless `overwrite` is set, sidecars are only written if any are missing.
+    async fn write_sidecars(&self, id: usize, overwrite: bool) -> anyhow::Result<()> {
+        if self.sidecars.is_empty() {
+            return Ok(());
+        }
 
-            log::debug!("Saving work to path '{}'", &file_path);
+        let record = self
+            .library
+            .lock()
+            .unwrap()
+            .get(id)
+            .cloned()
+            .with_context(|| format!("Work with ID {} is not in the library", id))?;
+        if !overwrite
+            && self.sidecars.iter().all(|kind| {
+                sidecar::path_for(*kind, &record, &self.dest).is_some_and(|path| path.exists())
+            })
+        {
+            return Ok(());
+        }
 
-            fs::write(&file_path, &bytes)?;
+        let metadata = ao3::work_metadata(&self.client, id)
+            .await
+            .with_context(|| format!("Cannot write sidecars for work with ID {}", id))?;
+        for kind in &self.sidecars {
+            sidecar::write(
+                *kind,
+                &metadata,
+                &self.client.work_url(id),
+                &record,
+                &self.dest,
+            )
+            .with_context(|| format!("Cannot write sidecars for work with ID {}", id))?;
+        }
 
-            log::info!("Successfully saved work to path '{}'", &file_path);
+        Ok(())
+    }
 
-            Ok(())
+    /// Downloads `work` as `format`, and saves it under a name filled in from `fields` (and whatever
+    /// the download itself says, if the work page wasn't needed). `previous_updated_at` is when the
+    /// library last saw the work updated, for versioning any existing file.
+    ///
+    /// Returns where the work was saved and the SHA-256 of what was saved, or nothing if an
+    /// existing file was kept instead.
+    async fn download_work(
+        &self,
+        work: &ao3::WorkId,
+        fields: &naming::Fields,
+        format: Format,
+        previous_updated_at: Option<usize>,
+    ) -> anyhow::Result<Option<(PathBuf, String)>> {
+        let Downloader {
+            client,
+            unzip_epubs: unzip,
+            dest,
+            name_template,
+            max_size,
+            extracted,
+            ..
+        } = self;
+
+        // Once the work page has given the title, the name doesn't depend on the download, so a
+        // file that would be kept anyway needn't be downloaded at all
+        if self.on_existing == OnExisting::Skip && !fields.title.is_empty() {
+            let file_path = dest.join(name_template.render(fields, format.file_extension()));
+            if fs::symlink_metadata(&file_path).is_ok() {
+                return Ok(kept(&file_path));
+            }
         }
-        Format::EPUB => {
-            log::debug!("Attempting to parse download as ZIP");
 
-            let mut zipped_epub = extractor::as_zip(&bytes)
-                .context("Could not parse download as ZIP (this may happen for hidden works)")?;
+        log::debug!(
+            "Attempting to download work with ID {} as {:?}",
+            work.id(),
+            format
+        );
 
-            log::info!("Successfully parsed download as ZIP");
+        // Streamed to a temporary file until the download is named, so that it never has to fit in
+        // memory
+        let mut download = atomic::AtomicFile::create(&dest.join(format!(
+            "{}.{}",
+            work.id(),
+            format.file_extension()
+        )))?;
+        ao3::download(client, work, format, *max_size, download.file_mut())
+            .await
+            .context("Could not download data")?;
+        let sha256 =
+            library::sha256_of(download.file_mut()).context("Cannot read download back")?;
+
+        log::info!(
+            "Successfully downloaded work with ID {} as {:?}",
+            work.id(),
+            format
+        );
 
-            log::debug!("Attempting to extract title of work with ID {}", work.id());
+        match format {
+            Format::AZW3 => {
+                let fields = extracted_fields(
+                    fields,
+                    || extractor::mobi::metadata(&extractor::head(download.file_mut())?),
+                    extracted,
+                );
+                let file_path = dest.join(name_template.render(&fields, format.file_extension()));
+                create_parent_dir(&file_path)?;
+                let Some(file_path) =
+                    self.make_room(&file_path, fields.updated_at, previous_updated_at)?
+                else {
+                    return Ok(kept(&file_path));
+                };
 
-            let mut file_name = match extractor::title(&mut zipped_epub) {
-                Ok(title) => {
-                    log::info!(
-                        "Extracted title '{}' for work with ID {}",
-                        &title,
-                        work.id()
-                    );
-                    format!("{} [ao3 {}]", title, work.id())
-                }
-                Err(e) => {
-                    let msg = e
-                        .chain()
-                        .map(|link| link.to_string())
-                        .collect::<Vec<String>>()
-                        .join(", because ");
-                    log::warn!(
-                        "Could not extract title for fic with ID {}, because {}",
-                        work.id(),
-                        msg
-                    );
-                    format!("[ao3 {}]", work.id())
-                }
-            };
+                log::debug!("Saving work to path '{}'", file_path.display());
 
-            let presanitized_len = file_name.len();
-            file_name.retain(|c| c != '\0' && c != '/');
-            let sanitized_len = file_name.len();
-            if sanitized_len < presanitized_len {
-                log::info!("Sanitizing destination file path");
-            }
-            log::trace!("Inserting file name into cache");
-            cache.insert(*work.id(), file_name.to_string());
-            let file_path = format!(
-                "{file_name}.{extension}",
-                extension = format.file_extension()
-            );
+                download.commit_as(&file_path)?;
 
-            if unzip {
-                log::debug!("Extracting work to path '{}'", &file_path);
+                log::info!("Successfully saved work to path '{}'", file_path.display());
 
-                extractor::unzip_to(&mut zipped_epub, &file_path)
+                Ok(Some((file_path, sha256)))
+            }
+            Format::EPUB => {
+                log::debug!("Attempting to parse download as ZIP");
+
+                let mut zipped_epub = extractor::as_zip(download.file_mut()).context(
+                    "Could not parse download as ZIP (this may happen for hidden works)",
+                )?;
+
+                log::info!("Successfully parsed download as ZIP");
+
+                log::debug!("Attempting to extract title of work with ID {}", work.id());
+
+                let fields = extracted_fields(
+                    fields,
+                    || {
+                        Ok(extractor::Metadata {
+                            title: extractor::title(&mut zipped_epub)?,
+                            ..extractor::Metadata::default()
+                        })
+                    },
+                    extracted,
+                );
+                let file_path = dest.join(name_template.render(&fields, format.file_extension()));
+                create_parent_dir(&file_path)?;
+                let Some(file_path) =
+                    self.make_room(&file_path, fields.updated_at, previous_updated_at)?
+                else {
+                    return Ok(kept(&file_path));
+                };
+
+                if *unzip {
+                    log::debug!("Extracting work to path '{}'", file_path.display());
+
+                    atomic::write_dir(&file_path, |temp| {
+                        extractor::unzip_to(&mut zipped_epub, temp)
+                    })
                     .context("Could not unzip EPUB")?;
 
-                log::info!("Successfully extracted work to path '{}'", &file_path);
-            } else {
-                log::debug!("Saving work to path '{}'", &file_path);
+                    log::info!(
+                        "Successfully extracted work to path '{}'",
+                        file_path.display()
+                    );
+                } else {
+                    log::debug!("Saving work to path '{}'", file_path.display());
 
-                fs::write(&file_path, &bytes)?;
+                    download.commit_as(&file_path)?;
 
-                log::info!("Successfully saved work to path '{}'", &file_path);
+                    log::info!("Successfully saved work to path '{}'", file_path.display());
+                }
+
+                Ok(Some((file_path, sha256)))
+            }
+            Format::HTML => {
+                let fields = extracted_fields(
+                    fields,
+                    || extractor::html::metadata(&extractor::head(download.file_mut())?),
+                    extracted,
+                );
+                let file_path = dest.join(name_template.render(&fields, format.file_extension()));
+                create_parent_dir(&file_path)?;
+                let Some(file_path) =
+                    self.make_room(&file_path, fields.updated_at, previous_updated_at)?
+                else {
+                    return Ok(kept(&file_path));
+                };
+
+                log::debug!("Saving work to path '{}'", file_path.display());
+
+                download.commit_as(&file_path)?;
+
+                log::info!("Successfully saved work to path '{}'", file_path.display());
+
+                Ok(Some((file_path, sha256)))
+            }
+            Format::MOBI => {
+                let fields = extracted_fields(
+                    fields,
+                    || extractor::mobi::metadata(&extractor::head(download.file_mut())?),
+                    extracted,
+                );
+                let file_path = dest.join(name_template.render(&fields, format.file_extension()));
+                create_parent_dir(&file_path)?;
+                let Some(file_path) =
+                    self.make_room(&file_path, fields.updated_at, previous_updated_at)?
+                else {
+                    return Ok(kept(&file_path));
+                };
+
+                log::debug!("Saving work to path '{}'", file_path.display());
+
+                download.commit_as(&file_path)?;
+
+                log::info!("Successfully saved work to path '{}'", file_path.display());
+
+                Ok(Some((file_path, sha256)))
+            }
+            Format::PDF => {
+                let fields = extracted_fields(
+                    fields,
+                    || extractor::pdf::metadata(download.file_mut()),
+                    extracted,
+                );
+                let file_path = dest.join(name_template.render(&fields, format.file_extension()));
+                create_parent_dir(&file_path)?;
+                let Some(file_path) =
+                    self.make_room(&file_path, fields.updated_at, previous_updated_at)?
+                else {
+                    return Ok(kept(&file_path));
+                };
+
+                log::debug!("Saving work to path '{}'", file_path.display());
+
+                download.commit_as(&file_path)?;
+
+                log::info!("Successfully saved work to path '{}'", file_path.display());
+
+                Ok(Some((file_path, sha256)))
             }
+        }
+    }
 
-            Ok(())
+    /// Clears the way for a download bound for `path`, following `--on-existing`. Returns where the
+    /// download should go instead, or nothing if the existing file should be kept.
+    ///
+    /// `updated_at` is when the download was last updated, and `previous_updated_at` when the
+    /// library last saw the work updated.
+    fn make_room(
+        &self,
+        path: &Path,
+        updated_at: Option<usize>,
+        previous_updated_at: Option<usize>,
+    ) -> anyhow::Result<Option<PathBuf>> {
+        if fs::symlink_metadata(path).is_err() {
+            return Ok(Some(path.to_path_buf()));
         }
-        Format::HTML => {
-            let file_name = match cache.get(work.id()) {
-                Some(name) => {
-                    log::trace!("Found file name in cache");
-                    name.to_owned()
-                }
-                None => {
-                    // TODO: extract title from HTML
-                    // body > div#preface > p.message > b
-                    // body > div#preface > div.meta > h1
-                    log::trace!("Could not find file name in cache; defaulting to work ID");
-                    format!("[ao3 {}]", work.id())
+
+        match self.on_existing {
+            OnExisting::Skip => Ok(None),
+            OnExisting::Overwrite => Ok(Some(path.to_path_buf())),
+            OnExisting::Rename => {
+                let free = (2..)
+                    .map(|n| naming::with_stem_suffix(path, &format!(" ({})", n)))
+                    .find(|candidate| fs::symlink_metadata(candidate).is_err())
+                    .unwrap();
+                log::info!(
+                    "'{}' is taken, so saving to '{}' instead",
+                    path.display(),
+                    free.display()
+                );
+                Ok(Some(free))
+            }
+            OnExisting::Version => {
+                // Files the library doesn't know about are versioned by when they were last changed
+                let old_updated_at = match previous_updated_at {
+                    Some(previous_updated_at) => previous_updated_at,
+                    None => fs::metadata(path)
+                        .and_then(|metadata| metadata.modified())
+                        .ok()
+                        .and_then(|modified| modified.duration_since(UNIX_EPOCH).ok())
+                        .map_or(0, |age| age.as_secs() as usize),
+                };
+                if updated_at == Some(old_updated_at) {
+                    return Ok(Some(path.to_path_buf()));
                 }
-            };
-            let file_path = format!(
-                "{file_name}.{extension}",
-                extension = format.file_extension()
-            );
 
-            log::debug!("Saving work to path '{}'", &file_path);
+                let versioned = naming::with_stem_suffix(path, &format!(".{}", old_updated_at));
+                if fs::symlink_metadata(&versioned).is_ok() {
+                    log::debug!(
+                        "'{}' already exists, so not keeping '{}' again",
+                        versioned.display(),
+                        path.display()
+                    );
+                } else {
+                    log::info!(
+                        "Keeping previous version of '{}' as '{}'",
+                        path.display(),
+                        versioned.display()
+                    );
+                    fs::rename(path, &versioned).with_context(|| {
+                        format!(
+                            "Cannot keep previous version of '{}' as '{}'",
+                            path.display(),
+                            versioned.display()
+                        )
+                    })?;
+                }
+                Ok(Some(path.to_path_buf()))
+            }
+        }
+    }
+}
 
-            fs::write(&file_path, &bytes)?;
+/// Leaves the file already at `path` alone. It isn't recorded in the library, since there's no
+/// telling which version of the work it is.
+fn kept(path: &Path) -> Option<(PathBuf, String)> {
+    log::info!("Keeping existing file at path '{}'", path.display());
+    None
+}
 
-            log::info!("Successfully saved work to path '{}'", &file_path);
+/// Describes `e` and everything that caused it, on one line.
+fn error_chain(e: &anyhow::Error) -> String {
+    e.chain()
+        .map(|link| link.to_string())
+        .collect::<Vec<String>>()
+        .join(", because ")
+}
 
-            Ok(())
+/// Where to look for the username and password, going by the arguments and environment variables.
+fn credential_sources(args: &Cli) -> anyhow::Result<credentials::Sources> {
+    let var = |name| match env::var(name) {
+        Ok(value) => Ok(Some(value)),
+        Err(env::VarError::NotPresent) => Ok(None),
+        Err(env::VarError::NotUnicode(_)) => {
+            bail!(
+                "Found {} env var, but the contents were not valid Unicode!",
+                name
+            )
         }
-        Format::MOBI => {
-            let file_name = match cache.get(work.id()) {
-                Some(name) => {
-                    log::trace!("Found file name in cache");
-                    name.to_owned()
-                }
-                None => {
-                    // TODO: extract title from MOBI
-                    log::trace!("Could not find file name in cache; defaulting to work ID");
-                    format!("[ao3 {}]", work.id())
-                }
-            };
-            let file_path = format!(
-                "{file_name}.{extension}",
-                extension = format.file_extension()
-            );
-
-            log::debug!("Saving work to path '{}'", &file_path);
+    };
+    Ok(credentials::Sources {
+        host: args.base_url.host_str().unwrap_or_default().to_owned(),
+        username: var("USERNAME")?,
+        password: var("PASSWORD")?,
+        password_stdin: args.password_stdin,
+        password_command: args.password_command.clone(),
+        config_file: args
+            .config
+            .clone()
+            .or_else(credentials::default_config_file),
+        config_file_required: args.config.is_some(),
+        netrc_file: credentials::default_netrc_file(),
+        interactive: std::io::stdin().is_terminal() && std::io::stdout().is_terminal(),
+    })
+}
 
-            fs::write(&file_path, &bytes)?;
+/// Prints the metadata of every download in `paths` (recursing into directories) as JSON lines.
+///
+/// Files that can't be read are reported, but don't stop the rest from being inspected.
+fn inspect(paths: &[PathBuf]) -> anyhow::Result<()> {
+    #[derive(Serialize)]
+    struct Inspected<'a> {
+        path: &'a Path,
+        #[serde(flatten)]
+        metadata: extractor::Metadata,
+    }
 
-            log::info!("Successfully saved work to path '{}'", &file_path);
+    let mut stdout = std::io::stdout().lock();
+    for path in paths {
+        if path.is_dir() {
+            let mut entries = fs::read_dir(path)
+                .with_context(|| format!("Cannot list directory '{}'", path.display()))?
+                .map(|entry| entry.map(|entry| entry.path()))
+                .collect::<Result<Vec<_>, _>>()
+                .with_context(|| format!("Cannot list directory '{}'", path.display()))?;
+            entries.sort();
+            // Only recognised downloads, so sidecars and the library are skipped
+            entries.retain(|entry| entry.is_dir() || extractor::is_download(entry));
+            inspect(&entries)?;
+            continue;
+        }
 
-            Ok(())
+        match extractor::file_metadata(path) {
+            Ok(metadata) => {
+                serde_json::to_writer(&mut stdout, &Inspected { path, metadata })
+                    .context("Cannot write metadata")?;
+                writeln!(stdout).context("Cannot write metadata")?;
+            }
+            Err(e) => eprintln!("Cannot inspect '{}': {}", path.display(), error_chain(&e)),
         }
-        Format::PDF => {
-            let file_name = match cache.get(work.id()) {
-                Some(name) => {
-                    log::trace!("Found file name in cache");
-                    name.to_owned()
-                }
-                None => {
-                    // Don't currently know how to extract a title from an PDF
-                    log::trace!("Could not find file name in cache; defaulting to work ID");
-                    format!("[ao3 {}]", work.id())
-                }
-            };
-            let file_path = format!(
-                "{file_name}.{extension}",
-                extension = format.file_extension()
-            );
+    }
+    Ok(())
+}
 
-            log::debug!("Saving work to path '{}'", &file_path);
+/// Fills in the title and authors of `fields` from what was `extracted` from one of the work's
+/// downloads, unless the work page already gave them. What was extracted is remembered in `cache`
+/// for the work's other formats.
+fn extracted_fields(
+    fields: &naming::Fields,
+    extracted: impl FnOnce() -> anyhow::Result<extractor::Metadata>,
+    cache: &Mutex<HashMap<usize, extractor::Metadata>>,
+) -> naming::Fields {
+    let mut fields = fields.clone();
+    if !fields.title.is_empty() {
+        return fields;
+    }
+
+    // Only locked to look up and remember, so that other downloads can extract in the meantime
+    let cached = cache.lock().unwrap().get(&fields.id).cloned();
+    let extracted = match cached {
+        Some(metadata) => {
+            log::trace!("Found title in cache");
+            metadata
+        }
+        None => match extracted() {
+            Ok(metadata) => {
+                log::info!(
+                    "Extracted title '{}' for work with ID {}",
+                    &metadata.title,
+                    fields.id
+                );
+                log::trace!("Inserting title into cache");
+                cache.lock().unwrap().insert(fields.id, metadata.clone());
+                metadata
+            }
+            Err(e) => {
+                log::warn!(
+                    "Could not extract title for fic with ID {}, because {}",
+                    fields.id,
+                    error_chain(&e)
+                );
+                return fields;
+            }
+        },
+    };
+    fields.title = extracted.title;
+    if fields.authors.is_empty() {
+        fields.authors = extracted.authors;
+    }
+    fields
+}
 
-            fs::write(&file_path, &bytes)?;
+/// Creates the directory `path` goes in, in case it is a new one.
+fn create_parent_dir(path: &Path) -> anyhow::Result<()> {
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)
+            .with_context(|| format!("Cannot create directory '{}'", parent.display()))?;
+    }
+    Ok(())
+}
 
-            log::info!("Successfully saved work to path '{}'", &file_path);
+/// Parses a size in bytes, such as `1024`, `512K`, `50M` or `2G` (in powers of 1024).
+fn parse_size(size: &str) -> Result<u64, String> {
+    let (number, scale) = match size.char_indices().last() {
+        Some((i, 'k' | 'K')) => (&size[..i], 1 << 10),
+        Some((i, 'm' | 'M')) => (&size[..i], 1 << 20),
+        Some((i, 'g' | 'G')) => (&size[..i], 1 << 30),
+        _ => (size, 1),
+    };
+    number
+        .parse::<u64>()
+        .ok()
+        .and_then(|number| number.checked_mul(scale))
+        .filter(|size| *size > 0)
+        .ok_or_else(|| format!("'{}' is not a size like 1024, 512K, 50M or 2G", size))
+}
 
-            Ok(())
-        }
+/// Writes `works` to `path` as JSON lines, which can be read back in as a works file.
+fn write_works_list(works: &[ao3::WorkId], path: &Path) -> anyhow::Result<()> {
+    let file = atomic::AtomicFile::create(path)
+        .with_context(|| format!("Cannot create file at path {}", path.display()))?;
+    let mut writer = std::io::BufWriter::new(file);
+    for work in works {
+        serde_json::to_writer(&mut writer, work).context("Failed to serialize work")?;
+        writeln!(writer).context("Failed to write line to file")?;
     }
+    writer.flush().context("Failed to flush file")?;
+    writer
+        .into_inner()
+        .context("Failed to flush file")?
+        .commit()
 }
 
-fn write_lines_sorted(set: &HashSet<usize>, path: &'static str) -> anyhow::Result<()> {
+/// Writes the IDs in `set` in order, one per line, followed by the lines in `after`.
+fn write_lines_sorted(set: &HashSet<usize>, after: &[String], path: &Path) -> anyhow::Result<()> {
     let mut arr = set.iter().collect::<Vec<&usize>>();
     arr.sort();
-    let file = std::fs::File::create(path)
-        .context(format!("Cannot create file at path {}", path))?;
+    let file = atomic::AtomicFile::create(path)
+        .context(format!("Cannot create file at path {}", path.display()))?;
     let mut writer = std::io::BufWriter::new(file);
-    for item in arr {
+    for item in arr
+        .iter()
+        .map(ToString::to_string)
+        .chain(after.iter().cloned())
+    {
         writeln!(writer, "{}", item)
             .context("Failed to write line to file")?;
     }
     writer.flush()
         .context("Failed to flush file")?;
-    Ok(())
+    writer
+        .into_inner()
+        .context("Failed to flush file")?
+        .commit()
 }
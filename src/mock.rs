@@ -0,0 +1,609 @@
+//! An in-process stand-in for AO3, for tests.
+//!
+//! It serves just enough of the archive for ao3dl to run end-to-end: the authenticity token
+//! dispenser, the login form, work pages, bookmarks, series and downloads. Individual requests can be scripted to
+//! fail (HTTP 429s, 5xx storms, ...) and every request is recorded so tests can check what was
+//! actually fetched.
+
+use std::{
+    collections::{HashMap, VecDeque},
+    io::Write,
+    sync::{Arc, Mutex},
+};
+
+use axum::{
+    body::{Body, Bytes},
+    extract::State,
+    http::{HeaderMap, HeaderValue, Method, StatusCode, Uri, header},
+    response::{IntoResponse, Response},
+};
+use reqwest::Url;
+
+pub const USERNAME: &str = "reader";
+/// The archive also lets users log in with their email address
+pub const EMAIL: &str = "reader@example.com";
+pub const PASSWORD: &str = "correct horse battery staple";
+
+const TOKEN: &str = "mock-authenticity-token";
+
+#[derive(Clone)]
+pub struct MockWork {
+    pub title: String,
+    pub updated_at: usize,
+    /// Hidden works still have a work page, but their downloads are an HTML error page
+    pub hidden: bool,
+    /// Adult works' pages are a content warning, unless `view_adult=true` is asked for
+    pub adult: bool,
+    /// Restricted works send anyone not logged in to the login form
+    pub restricted: bool,
+}
+
+impl MockWork {
+    pub fn new(title: &str, updated_at: usize) -> MockWork {
+        MockWork {
+            title: title.to_owned(),
+            updated_at,
+            hidden: false,
+            adult: false,
+            restricted: false,
+        }
+    }
+
+    pub fn hidden(mut self) -> MockWork {
+        self.hidden = true;
+        self
+    }
+
+    pub fn adult(mut self) -> MockWork {
+        self.adult = true;
+        self
+    }
+
+    pub fn restricted(mut self) -> MockWork {
+        self.restricted = true;
+        self
+    }
+}
+
+/// A canned response, served instead of the real one.
+#[derive(Clone)]
+pub enum Scripted {
+    TooManyRequests {
+        retry_after: Option<&'static str>,
+    },
+    Status(StatusCode),
+    /// A successful response with this body, e.g. a maintenance page standing in for a download
+    Body(&'static str),
+}
+
+/// How many entries the mock puts on each page of a listing (AO3 uses 20)
+pub const PAGE_SIZE: usize = 2;
+
+/// The session cookie the mock hands out on logging in, until sessions are expired
+const SESSION_COOKIE: &str = "_otwarchive_session=logged-in";
+
+#[derive(Default)]
+struct MockState {
+    works: HashMap<usize, MockWork>,
+    /// The logged-in user's bookmarks, as links and whether they are private
+    bookmarks: Vec<(String, bool)>,
+    /// Each series' title and works, in series order
+    series: HashMap<usize, (String, Vec<usize>)>,
+    /// Any other listings of works (a user's works, gifts, ...), by path
+    listings: HashMap<String, Vec<usize>>,
+    /// The work each chapter belongs to, by chapter ID
+    chapters: HashMap<usize, usize>,
+    scripted: HashMap<String, VecDeque<Scripted>>,
+    requests: Vec<String>,
+    /// How many times sessions have been expired, which tells sessions apart
+    session_generation: usize,
+}
+
+impl MockState {
+    fn session_cookie(&self) -> String {
+        format!("{SESSION_COOKIE}-{}", self.session_generation)
+    }
+}
+
+pub struct MockArchive {
+    pub base_url: Url,
+    state: Arc<Mutex<MockState>>,
+    server: tokio::task::JoinHandle<()>,
+}
+
+impl MockArchive {
+    pub async fn start() -> MockArchive {
+        let state = Arc::new(Mutex::new(MockState::default()));
+        let app = axum::Router::new()
+            .fallback(handle)
+            .with_state(state.clone());
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0")
+            .await
+            .expect("Cannot bind mock archive to a local port");
+        let addr = listener.local_addr().unwrap();
+        let server = tokio::spawn(async move {
+            axum::serve(listener, app).await.unwrap();
+        });
+
+        MockArchive {
+            base_url: format!("http://{addr}").parse().unwrap(),
+            state,
+            server,
+        }
+    }
+
+    pub fn add_work(&self, id: usize, work: MockWork) {
+        self.state.lock().unwrap().works.insert(id, work);
+    }
+
+    /// Bookmarks `href` (e.g. `/works/1` or `/series/2`) as the mock's only user.
+    pub fn add_bookmark(&self, href: &str, private: bool) {
+        self.state
+            .lock()
+            .unwrap()
+            .bookmarks
+            .push((href.to_owned(), private));
+    }
+
+    pub fn add_series(&self, id: usize, title: &str, works: &[usize]) {
+        self.state
+            .lock()
+            .unwrap()
+            .series
+            .insert(id, (title.to_owned(), works.to_vec()));
+    }
+
+    /// Lists `works` at `path`, e.g. `/users/someone/works`.
+    pub fn add_listing(&self, path: &str, works: &[usize]) {
+        self.state
+            .lock()
+            .unwrap()
+            .listings
+            .insert(path.to_owned(), works.to_vec());
+    }
+
+    pub fn add_chapter(&self, id: usize, work_id: usize) {
+        self.state.lock().unwrap().chapters.insert(id, work_id);
+    }
+
+    pub fn remove_work(&self, id: usize) {
+        self.state.lock().unwrap().works.remove(&id);
+    }
+
+    /// Serves `responses`, in order, to the next requests for `path` (which excludes the query
+    /// string), before going back to normal.
+    pub fn script(&self, path: &str, responses: impl IntoIterator<Item = Scripted>) {
+        self.state
+            .lock()
+            .unwrap()
+            .scripted
+            .entry(path.to_owned())
+            .or_default()
+            .extend(responses);
+    }
+
+    /// Logs out everyone, as if their sessions had expired.
+    pub fn expire_sessions(&self) {
+        self.state.lock().unwrap().session_generation += 1;
+    }
+
+    /// Every request made so far, as `"METHOD /path?query"`.
+    pub fn requests(&self) -> Vec<String> {
+        self.state.lock().unwrap().requests.clone()
+    }
+
+    /// How many requests have been made for `path`, ignoring the query string.
+    pub fn hits(&self, path: &str) -> usize {
+        self.requests()
+            .iter()
+            .filter(|req| {
+                let (_, target) = req.split_once(' ').unwrap();
+                target.split('?').next() == Some(path)
+            })
+            .count()
+    }
+}
+
+impl Drop for MockArchive {
+    fn drop(&mut self) {
+        self.server.abort();
+    }
+}
+
+async fn handle(
+    State(state): State<Arc<Mutex<MockState>>>,
+    method: Method,
+    uri: Uri,
+    headers: HeaderMap,
+    body: Bytes,
+) -> Response {
+    let page = uri
+        .query()
+        .unwrap_or_default()
+        .split('&')
+        .find_map(|param| param.strip_prefix("page=")?.parse().ok())
+        .unwrap_or(1);
+    let view_adult = uri
+        .query()
+        .unwrap_or_default()
+        .split('&')
+        .any(|param| param == "view_adult=true");
+
+    let mut state = state.lock().unwrap();
+    let session_cookie = state.session_cookie();
+    let logged_in = headers
+        .get_all(header::COOKIE)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .any(|value| value.split("; ").any(|cookie| cookie == session_cookie));
+    state.requests.push(format!(
+        "{method} {}",
+        uri.path_and_query().map(|pq| pq.as_str()).unwrap_or("/")
+    ));
+
+    if let Some(scripted) = state
+        .scripted
+        .get_mut(uri.path())
+        .and_then(|queue| queue.pop_front())
+    {
+        return match scripted {
+            Scripted::TooManyRequests { retry_after } => {
+                let mut resp = StatusCode::TOO_MANY_REQUESTS.into_response();
+                if let Some(retry_after) = retry_after {
+                    resp.headers_mut()
+                        .insert(header::RETRY_AFTER, HeaderValue::from_static(retry_after));
+                }
+                resp
+            }
+            Scripted::Status(code) => code.into_response(),
+            Scripted::Body(body) => body.into_response(),
+        };
+    }
+
+    let segments = uri
+        .path()
+        .trim_start_matches('/')
+        .split('/')
+        .collect::<Vec<_>>();
+    match (method, segments.as_slice()) {
+        (Method::GET, ["token_dispenser.json"]) => json(format!(r#"{{"token":"{TOKEN}"}}"#)),
+        (Method::GET, [""]) => html(front_page(logged_in)),
+        (Method::GET, ["users", "login"]) => html(
+            r#"<html><body><form id="new_user" action="/users/login" method="post"></form></body></html>"#.to_owned(),
+        ),
+        (Method::POST, ["users", "login"]) => login(&body, &session_cookie),
+        (Method::GET, ["users", USERNAME, "bookmarks"]) => {
+            let hrefs = state
+                .bookmarks
+                .iter()
+                .filter(|(_, private)| logged_in || !private)
+                .map(|(href, _)| href.clone())
+                .collect::<Vec<_>>();
+            html(listing_page(None, &hrefs, page))
+        }
+        (Method::GET, ["series", id]) => match id.parse().ok().and_then(|id| state.series.get(&id))
+        {
+            Some((title, works)) => {
+                // Like the archive, leaves out works that only logged-in users can see
+                let hrefs = works
+                    .iter()
+                    .filter(|id| {
+                        logged_in || !state.works.get(id).is_some_and(|work| work.restricted)
+                    })
+                    .map(|id| format!("/works/{id}"))
+                    .collect::<Vec<_>>();
+                html(listing_page(Some(title), &hrefs, page))
+            }
+            None => StatusCode::NOT_FOUND.into_response(),
+        },
+        (Method::GET, ["works", id]) => match id
+            .parse()
+            .ok()
+            .and_then(|id| state.works.get(&id).map(|w| (id, w)))
+        {
+            Some((_, work)) if work.restricted && !logged_in => to_login_form(),
+            Some((id, work)) if work.adult && !view_adult => html(adult_warning(id)),
+            Some((id, work)) => html(work_page(id, work, &state.series)),
+            None => StatusCode::NOT_FOUND.into_response(),
+        },
+        (Method::GET, ["chapters", id]) => match id
+            .parse()
+            .ok()
+            .and_then(|id| state.chapters.get(&id).map(|w| (id, w)))
+        {
+            Some((id, work_id)) => (
+                StatusCode::FOUND,
+                [(header::LOCATION, format!("/works/{work_id}/chapters/{id}"))],
+            )
+                .into_response(),
+            None => StatusCode::NOT_FOUND.into_response(),
+        },
+        (Method::GET, ["works", id, "chapters", _]) => match id
+            .parse()
+            .ok()
+            .and_then(|id| state.works.get(&id).map(|w| (id, w)))
+        {
+            Some((_, work)) if work.restricted && !logged_in => to_login_form(),
+            Some((id, work)) if work.adult && !view_adult => html(adult_warning(id)),
+            Some((id, work)) => html(work_page(id, work, &state.series)),
+            None => StatusCode::NOT_FOUND.into_response(),
+        },
+        (Method::GET, ["downloads", id, file_name]) => {
+            match id.parse().ok().and_then(|id| state.works.get(&id).map(|w| (id, w))) {
+                Some((_, work)) if work.restricted && !logged_in => to_login_form(),
+                Some((_, work)) if work.hidden => html(
+                    "<html><body><p>Sorry, you don't have permission to access the page you were trying to reach.</p></body></html>".to_owned(),
+                ),
+                Some((id, work)) => download(id, work, file_name),
+                None => StatusCode::NOT_FOUND.into_response(),
+            }
+        }
+        (Method::GET, _) if state.listings.contains_key(uri.path()) => {
+            let hrefs = state.listings[uri.path()]
+                .iter()
+                .map(|id| format!("/works/{id}"))
+                .collect::<Vec<_>>();
+            html(listing_page(None, &hrefs, page))
+        }
+        _ => StatusCode::NOT_FOUND.into_response(),
+    }
+}
+
+/// Where the archive sends anyone who isn't logged in when they ask for a restricted work
+fn to_login_form() -> Response {
+    (
+        StatusCode::FOUND,
+        [(header::LOCATION, "/users/login?restricted=true")],
+    )
+        .into_response()
+}
+
+fn adult_warning(id: usize) -> String {
+    format!(
+        r#"<html><body><div id="main"><p class="caution">This work could have adult content. If you continue, you have agreed that you are willing to see such content.</p><ul class="actions"><li><a href="/works/{id}?view_adult=true">Yes, Continue</a></li></ul></div></body></html>"#
+    )
+}
+
+fn front_page(logged_in: bool) -> String {
+    let greeting = if logged_in {
+        format!(
+            r#"<ul class="user navigation actions" id="greeting"><li class="dropdown"><a class="dropdown-toggle" href="/users/{USERNAME}">Hi, {USERNAME}!</a></li><li><a href="/users/logout">Log Out</a></li></ul>"#
+        )
+    } else {
+        r#"<ul class="user navigation actions" id="login"><li><a href="/users/login">Log In</a></li></ul>"#.to_owned()
+    };
+    format!("<html><body><div id=\"header\">{greeting}</div></body></html>")
+}
+
+fn login(body: &[u8], session_cookie: &str) -> Response {
+    let body = String::from_utf8_lossy(body);
+    let field =
+        |name: &str, value: &str| body.contains(&format!("name=\"{name}\"\r\n\r\n{value}\r\n"));
+
+    if field("authenticity_token", TOKEN)
+        && (field("user[login]", USERNAME) || field("user[login]", EMAIL))
+        && field("user[password]", PASSWORD)
+    {
+        let mut resp =
+            html(r#"<html><body><a href="/users/logout">Log Out</a></body></html>"#.to_owned());
+        resp.headers_mut().insert(
+            header::SET_COOKIE,
+            HeaderValue::from_str(&format!("{session_cookie}; path=/")).unwrap(),
+        );
+        resp
+    } else {
+        html(r#"<html><body><div class="flash error">The password or user name you entered doesn't match our records.</div></body></html>"#.to_owned())
+    }
+}
+
+fn work_page(id: usize, work: &MockWork, series: &HashMap<usize, (String, Vec<usize>)>) -> String {
+    let mut positions = series
+        .iter()
+        .filter_map(|(series_id, (title, works))| {
+            let position = works.iter().position(|work_id| *work_id == id)? + 1;
+            Some((*series_id, position, title))
+        })
+        .collect::<Vec<_>>();
+    positions.sort();
+    let series = if positions.is_empty() {
+        String::new()
+    } else {
+        let spans = positions
+            .iter()
+            .map(|(series_id, position, title)| {
+                format!(
+                    r#"<span class="series"><span class="position">Part {position} of <a href="/series/{series_id}">{title}</a></span></span>"#
+                )
+            })
+            .collect::<String>();
+        format!(r#"<dt class="series">Series:</dt><dd class="series">{spans}</dd>"#)
+    };
+    let slug = work.title.replace(' ', "_");
+    let links = ["AZW3", "EPUB", "MOBI", "PDF", "HTML"]
+        .iter()
+        .map(|format| {
+            format!(
+                r#"
+    <li><a href="/downloads/{id}/{slug}.{ext}?updated_at={ts}">{format}</a></li>"#,
+                ext = format.to_lowercase(),
+                ts = work.updated_at
+            )
+        })
+        .collect::<String>();
+
+    format!(
+        r#"<html><body><div id="main"><dl class="work meta group"><dt class="fandom tags">Fandom:</dt><dd class="fandom tags"><ul class="commas"><li><a class="tag" href="/tags/Original%20Work/works">Original Work</a></li></ul></dd>{series}</dl><h2 class="title heading">{title}</h2><h3 class="byline heading"><a rel="author" href="/users/someone/pseuds/someone">someone</a></h3><li class="download"><ul class="expandable secondary">{links}</ul></li></div></body></html>"#,
+        title = work.title
+    )
+}
+
+/// Renders page `page` (counting from 1) of a listing of `hrefs`, the way AO3 lays out bookmarks,
+/// series and works indexes.
+fn listing_page(heading: Option<&str>, hrefs: &[String], page: usize) -> String {
+    let heading = heading
+        .map(|heading| format!("<h2 class=\"heading\">\n  {heading}\n</h2>"))
+        .unwrap_or_default();
+    let pages = hrefs.len().div_ceil(PAGE_SIZE).max(1);
+    let blurbs = hrefs
+        .chunks(PAGE_SIZE)
+        .nth(page - 1)
+        .unwrap_or_default()
+        .iter()
+        .map(|href| {
+            format!(
+                r#"
+<li class="blurb group" role="article">
+  <div class="header module">
+    <h4 class="heading"><a href="{href}">Some Title</a> by <a rel="author" href="/users/someone/pseuds/someone">someone</a></h4>
+  </div>
+</li>"#
+            )
+        })
+        .collect::<String>();
+    let next = if page < pages {
+        format!(r#"<a rel="next" href="?page={}">Next →</a>"#, page + 1)
+    } else {
+        r#"<span class="disabled">Next →</span>"#.to_owned()
+    };
+
+    format!(
+        r#"<html><body><div id="main">{heading}<ol class="index group">{blurbs}</ol><ol class="pagination actions" role="navigation"><li class="next" title="next">{next}</li></ol></div></body></html>"#
+    )
+}
+
+fn download(id: usize, work: &MockWork, file_name: &str) -> Response {
+    match file_name.rsplit_once('.').map(|(_, ext)| ext) {
+        Some("epub") => {
+            let mut zip = zip::ZipWriter::new(std::io::Cursor::new(Vec::new()));
+            let options = zip::write::SimpleFileOptions::default();
+            zip.start_file("mimetype", options).unwrap();
+            zip.write_all(b"application/epub+zip").unwrap();
+            zip.start_file("content.opf", options).unwrap();
+            write!(
+                zip,
+                r#"<?xml version="1.0" encoding="utf-8"?><package xmlns="http://www.idpf.org/2007/opf" version="2.0"><metadata xmlns:dc="http://purl.org/dc/elements/1.1/"><dc:title>{}</dc:title><dc:identifier>ao3:{id}</dc:identifier></metadata></package>"#,
+                work.title
+            )
+            .unwrap();
+            let bytes = zip.finish().unwrap().into_inner();
+            ([(header::CONTENT_TYPE, "application/epub+zip")], bytes).into_response()
+        }
+        Some("html") => html(format!(
+            r#"<html><body><div id="preface"><p class="message"><b>{title}</b><br/>Posted originally on the <a href="/">Archive of Our Own</a>.</p><div class="meta"><h1>{title}</h1><div class="byline">by <a rel="author" href="/users/someone/pseuds/someone">someone</a></div></div></div></body></html>"#,
+            title = work.title
+        )),
+        Some(ext @ ("mobi" | "azw3")) => (
+            [(header::CONTENT_TYPE, "application/x-mobipocket-ebook")],
+            mobi(&work.title, "someone", ext == "azw3"),
+        )
+            .into_response(),
+        Some("pdf") => (
+            [(header::CONTENT_TYPE, "application/pdf")],
+            pdf(&work.title, "someone"),
+        )
+            .into_response(),
+        Some(ext) => (
+            [(header::CONTENT_TYPE, "application/octet-stream")],
+            Body::from(format!("{ext} for work {id}")),
+        )
+            .into_response(),
+        None => StatusCode::NOT_FOUND.into_response(),
+    }
+}
+
+/// Builds a minimal MOBI (or, if `kf8`, AZW3) file: a PalmDB with a single record holding the
+/// PalmDOC, MOBI and EXTH headers, and no text.
+pub fn mobi(title: &str, author: &str, kf8: bool) -> Vec<u8> {
+    const MOBI_HEADER_LEN: usize = 232;
+
+    let exth_records = [
+        (100, author),
+        (101, "Archive of Our Own"),
+        (106, "2023-11-12T00:00:00+00:00"),
+    ];
+    let mut exth = Vec::new();
+    for (record_type, data) in exth_records {
+        exth.extend((record_type as u32).to_be_bytes());
+        exth.extend((8 + data.len() as u32).to_be_bytes());
+        exth.extend(data.as_bytes());
+    }
+    let exth_len = 12 + exth.len();
+    let exth_padding = exth_len.next_multiple_of(4) - exth_len;
+
+    let mut record0 = vec![0; 16 + MOBI_HEADER_LEN];
+    let full_name_offset = record0.len() + exth_len + exth_padding;
+    record0[16..20].copy_from_slice(b"MOBI");
+    record0[20..24].copy_from_slice(&(MOBI_HEADER_LEN as u32).to_be_bytes());
+    record0[24..28].copy_from_slice(&2u32.to_be_bytes());
+    record0[28..32].copy_from_slice(&65001u32.to_be_bytes());
+    record0[36..40].copy_from_slice(&(if kf8 { 8u32 } else { 6 }).to_be_bytes());
+    record0[84..88].copy_from_slice(&(full_name_offset as u32).to_be_bytes());
+    record0[88..92].copy_from_slice(&(title.len() as u32).to_be_bytes());
+    record0[128..132].copy_from_slice(&0x40u32.to_be_bytes());
+    record0.extend(b"EXTH");
+    record0.extend((exth_len as u32).to_be_bytes());
+    record0.extend((exth_records.len() as u32).to_be_bytes());
+    record0.extend(exth);
+    record0.extend(vec![0; exth_padding]);
+    record0.extend(title.as_bytes());
+    record0.extend([0, 0]);
+
+    let mut palmdb = vec![0; 78];
+    palmdb[..title.len().min(31)].copy_from_slice(&title.as_bytes()[..title.len().min(31)]);
+    palmdb[60..68].copy_from_slice(b"BOOKMOBI");
+    palmdb[76..78].copy_from_slice(&1u16.to_be_bytes());
+    // One record, followed by the customary two bytes of padding
+    palmdb.extend(88u32.to_be_bytes());
+    palmdb.extend([0; 4]);
+    palmdb.extend([0, 0]);
+    palmdb.extend(record0);
+    palmdb
+}
+
+/// Builds a minimal one-page PDF, with `title` and `author` in its document information
+/// dictionary.
+pub fn pdf(title: &str, author: &str) -> Vec<u8> {
+    let escape = |text: &str| {
+        text.replace('\\', "\\\\")
+            .replace('(', "\\(")
+            .replace(')', "\\)")
+    };
+    let objects = [
+        "<< /Type /Catalog /Pages 2 0 R >>".to_owned(),
+        "<< /Type /Pages /Kids [3 0 R] /Count 1 >>".to_owned(),
+        "<< /Type /Page /Parent 2 0 R /MediaBox [0 0 612 792] >>".to_owned(),
+        format!(
+            "<< /Title ({}) /Author ({}) /Producer (mock) /CreationDate (D:20231112093000+00'00') >>",
+            escape(title),
+            escape(author)
+        ),
+    ];
+
+    let mut pdf = b"%PDF-1.4\n".to_vec();
+    let mut offsets = Vec::new();
+    for (i, object) in objects.iter().enumerate() {
+        offsets.push(pdf.len());
+        pdf.extend(format!("{} 0 obj\n{}\nendobj\n", i + 1, object).as_bytes());
+    }
+    let xref = pdf.len();
+    pdf.extend(format!("xref\n0 {}\n0000000000 65535 f \n", objects.len() + 1).as_bytes());
+    for offset in offsets {
+        pdf.extend(format!("{offset:010} 00000 n \n").as_bytes());
+    }
+    pdf.extend(
+        format!(
+            "trailer\n<< /Size {} /Root 1 0 R /Info 4 0 R >>\nstartxref\n{xref}\n%%EOF\n",
+            objects.len() + 1
+        )
+        .as_bytes(),
+    );
+    pdf
+}
+
+fn html(body: String) -> Response {
+    ([(header::CONTENT_TYPE, "text/html; charset=utf-8")], body).into_response()
+}
+
+fn json(body: String) -> Response {
+    ([(header::CONTENT_TYPE, "application/json")], body).into_response()
+}
@@ -0,0 +1,377 @@
+//! Where each download goes, relative to the download directory, as set by `--name-template`.
+
+use std::{
+    fmt,
+    path::{Path, PathBuf},
+    str::FromStr,
+};
+
+use anyhow::{Context, bail};
+use unicode_normalization::UnicodeNormalization;
+
+use crate::ao3::{self, SeriesPosition};
+
+mod sanitize;
+#[cfg(test)]
+mod tests;
+
+pub use sanitize::Sanitizer;
+
+/// How downloads were always named, before templates
+pub const DEFAULT_TEMPLATE: &str = "{title} [ao3 {id}].{ext}";
+
+/// What `{updated}` looks like without a format of its own
+const DEFAULT_DATE_FORMAT: &str = "%Y-%m-%d";
+
+#[derive(Clone, Debug, PartialEq)]
+enum Placeholder {
+    Title,
+    /// Every author, separated by commas
+    Author,
+    Id,
+    /// Every fandom, separated by commas
+    Fandom,
+    Series,
+    SeriesIndex,
+    Rating,
+    /// When the work was last updated, as a `strftime`-style format
+    Updated(String),
+    Ext,
+}
+
+impl Placeholder {
+    fn parse(placeholder: &str) -> anyhow::Result<Placeholder> {
+        let (name, format) = match placeholder.split_once(':') {
+            Some((name, format)) => (name, Some(format)),
+            None => (placeholder, None),
+        };
+        let placeholder = match name {
+            "title" => Placeholder::Title,
+            "author" => Placeholder::Author,
+            "id" => Placeholder::Id,
+            "fandom" => Placeholder::Fandom,
+            "series" => Placeholder::Series,
+            "series_index" => Placeholder::SeriesIndex,
+            "rating" => Placeholder::Rating,
+            "updated" => {
+                let format = format.unwrap_or(DEFAULT_DATE_FORMAT);
+                let valid = !format.is_empty()
+                    && chrono::format::StrftimeItems::new(format)
+                        .all(|item| item != chrono::format::Item::Error);
+                if !valid {
+                    bail!("'{}' is not a valid date format", format);
+                }
+                return Ok(Placeholder::Updated(format.to_owned()));
+            }
+            "ext" => Placeholder::Ext,
+            _ => bail!("Unknown placeholder '{{{}}}'", name),
+        };
+        if format.is_some() {
+            bail!("Only '{{updated}}' can be given a format");
+        }
+        Ok(placeholder)
+    }
+
+    /// Whether this can be shortened to fit a long name into the file system's limit
+    fn can_shorten(&self) -> bool {
+        !matches!(self, Placeholder::Id | Placeholder::Ext)
+    }
+
+    /// Whether filling this in takes the work page, rather than just a download
+    fn needs_work_page(&self) -> bool {
+        matches!(
+            self,
+            Placeholder::Author
+                | Placeholder::Fandom
+                | Placeholder::Series
+                | Placeholder::SeriesIndex
+                | Placeholder::Rating
+        )
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum Part {
+    Literal(String),
+    Placeholder(Placeholder),
+}
+
+/// A parsed `--name-template`, such as `{fandom}/{author}/{title} [ao3 {id}].{ext}`.
+///
+/// A `/` in the template separates directories, while one in a filled-in placeholder is dropped.
+/// Templates without an `{ext}` have `.{ext}` added, so that formats don't overwrite each other.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Template {
+    source: String,
+    /// The parts of each path component, outermost directory first
+    components: Vec<Vec<Part>>,
+    sanitizer: Sanitizer,
+}
+
+impl Template {
+    /// Puts each format in its own directory, named after its extension, ahead of the rest of the
+    /// template.
+    pub fn in_format_dirs(mut self) -> Template {
+        self.components
+            .insert(0, vec![Part::Placeholder(Placeholder::Ext)]);
+        self
+    }
+
+    /// Makes the names this template renders safe for `sanitizer`'s file systems.
+    pub fn sanitized_for(self, sanitizer: Sanitizer) -> Template {
+        Template { sanitizer, ..self }
+    }
+
+    /// Whether filling this template in takes the work page, rather than just a download
+    pub fn needs_work_page(&self) -> bool {
+        self.placeholders().any(Placeholder::needs_work_page)
+    }
+
+    fn placeholders(&self) -> impl Iterator<Item = &Placeholder> {
+        self.components
+            .iter()
+            .flatten()
+            .filter_map(|part| match part {
+                Part::Placeholder(placeholder) => Some(placeholder),
+                Part::Literal(_) => None,
+            })
+    }
+
+    /// Fills in the template for `fields` and `extension`, giving a path relative to the download
+    /// directory that is safe for the template's sanitizer.
+    ///
+    /// Directories that come out empty, such as `{series}` for a work in no series, are left out.
+    /// Names too long for the file system have their placeholders shortened, longest first, so that
+    /// `{id}`, `{ext}` and the rest of the template always survive.
+    pub fn render(&self, fields: &Fields, extension: &str) -> PathBuf {
+        let sanitizer = self.sanitizer;
+        let mut path = PathBuf::new();
+        for component in &self.components {
+            let mut values = component
+                .iter()
+                .map(|part| match part {
+                    Part::Literal(_) => None,
+                    Part::Placeholder(placeholder) => {
+                        let value = fields.value(placeholder, extension);
+                        let sanitized = sanitizer.value(&value);
+                        if sanitized != value {
+                            log::info!("Sanitizing destination file path");
+                        }
+                        Some(sanitized)
+                    }
+                })
+                .collect::<Vec<_>>();
+
+            let len = component
+                .iter()
+                .zip(&values)
+                .map(|(part, value)| match (part, value) {
+                    (Part::Literal(literal), _) => literal.len(),
+                    (_, value) => value.as_ref().map_or(0, String::len),
+                })
+                .sum::<usize>();
+            let mut excess = len.saturating_sub(sanitize::MAX_NAME_BYTES);
+            while excess > 0 {
+                let longest = component
+                    .iter()
+                    .zip(&mut values)
+                    .filter_map(|(part, value)| match part {
+                        Part::Placeholder(placeholder) if placeholder.can_shorten() => {
+                            value.as_mut()
+                        }
+                        _ => None,
+                    })
+                    .max_by_key(|value| value.len());
+                let Some(longest) = longest.filter(|longest| !longest.is_empty()) else {
+                    log::warn!("Cannot shorten file name to fit the file system's limit");
+                    break;
+                };
+                let before = longest.len();
+                sanitize::truncate(longest, before.saturating_sub(excess));
+                // Cutting at a character boundary can take more than was needed
+                excess = excess.saturating_sub(before - longest.len());
+            }
+
+            let rendered = component
+                .iter()
+                .zip(values)
+                .map(|(part, value)| match part {
+                    Part::Literal(literal) => literal.clone(),
+                    Part::Placeholder(_) => value.unwrap_or_default(),
+                })
+                .collect::<String>();
+            match sanitizer.name(&rendered).trim() {
+                "" => continue,
+                "." | ".." => path.push("_"),
+                rendered => path.push(rendered),
+            }
+        }
+        path
+    }
+}
+
+impl FromStr for Template {
+    type Err = anyhow::Error;
+
+    fn from_str(source: &str) -> anyhow::Result<Template> {
+        if source.starts_with('/') {
+            bail!("Name templates must be relative to the download directory");
+        }
+
+        let normalized = source.nfc().collect::<String>();
+        let mut components = vec![Vec::new()];
+        let mut literal = String::new();
+        let mut chars = normalized.chars();
+        while let Some(c) = chars.next() {
+            match c {
+                '{' if chars.as_str().starts_with('{') => {
+                    chars.next();
+                    literal.push('{');
+                }
+                '}' if chars.as_str().starts_with('}') => {
+                    chars.next();
+                    literal.push('}');
+                }
+                '{' => {
+                    let (placeholder, rest) = chars
+                        .as_str()
+                        .split_once('}')
+                        .context("Unclosed '{' (write '{{' for a literal one)")?;
+                    let placeholder = Placeholder::parse(placeholder)?;
+                    chars = rest.chars();
+                    let current = components.last_mut().unwrap();
+                    if !literal.is_empty() {
+                        current.push(Part::Literal(std::mem::take(&mut literal)));
+                    }
+                    current.push(Part::Placeholder(placeholder));
+                }
+                '}' => bail!("Unopened '}}' (write '}}}}' for a literal one)"),
+                '/' => {
+                    let current = components.last_mut().unwrap();
+                    if !literal.is_empty() {
+                        current.push(Part::Literal(std::mem::take(&mut literal)));
+                    }
+                    components.push(Vec::new());
+                }
+                c => literal.push(c),
+            }
+        }
+        let current = components.last_mut().unwrap();
+        if !literal.is_empty() {
+            current.push(Part::Literal(literal));
+        }
+
+        for component in &components {
+            match component.as_slice() {
+                [] => bail!("Name templates cannot have empty directory or file names"),
+                [Part::Literal(literal)] if literal == "." || literal == ".." => {
+                    bail!("Name templates cannot refer to '{}'", literal)
+                }
+                _ => {}
+            }
+        }
+
+        let mut template = Template {
+            source: source.to_owned(),
+            components,
+            sanitizer: Sanitizer::default(),
+        };
+        if !template.placeholders().any(|p| *p == Placeholder::Ext) {
+            let file_name = template.components.last_mut().unwrap();
+            file_name.push(Part::Literal(".".to_owned()));
+            file_name.push(Part::Placeholder(Placeholder::Ext));
+        }
+        Ok(template)
+    }
+}
+
+impl Default for Template {
+    fn default() -> Template {
+        DEFAULT_TEMPLATE.parse().unwrap()
+    }
+}
+
+impl fmt::Display for Template {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.source)
+    }
+}
+
+/// Everything a template can be filled in with
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Fields {
+    pub id: usize,
+    pub title: String,
+    pub authors: Vec<String>,
+    pub fandoms: Vec<String>,
+    pub series: Option<SeriesPosition>,
+    pub rating: Option<String>,
+    /// The timestamp the archive uses to version downloads
+    pub updated_at: Option<usize>,
+}
+
+impl Fields {
+    /// Everything the work page says about `work`, putting it in the first of its series
+    pub fn from_work(work: &ao3::Work) -> Fields {
+        Fields {
+            id: work.id,
+            title: work.title.clone(),
+            authors: work.authors.clone(),
+            fandoms: work.fandoms.clone(),
+            series: work.series.first().cloned(),
+            rating: work.rating.clone(),
+            updated_at: Some(work.updated_at),
+        }
+    }
+
+    fn value(&self, placeholder: &Placeholder, extension: &str) -> String {
+        match placeholder {
+            Placeholder::Title => self.title.clone(),
+            Placeholder::Author => self.authors.join(", "),
+            Placeholder::Id => self.id.to_string(),
+            Placeholder::Fandom => self.fandoms.join(", "),
+            Placeholder::Series => self
+                .series
+                .as_ref()
+                .map(|series| series.title.clone())
+                .unwrap_or_default(),
+            Placeholder::SeriesIndex => self
+                .series
+                .as_ref()
+                .map(|series| series.position.to_string())
+                .unwrap_or_default(),
+            Placeholder::Rating => self.rating.clone().unwrap_or_default(),
+            Placeholder::Updated(format) => self
+                .updated_at
+                .and_then(|updated_at| i64::try_from(updated_at).ok())
+                .and_then(|updated_at| chrono::DateTime::from_timestamp(updated_at, 0))
+                .map(|updated_at| updated_at.format(format).to_string())
+                .unwrap_or_default(),
+            Placeholder::Ext => extension.to_owned(),
+        }
+    }
+}
+
+/// `path`, with `suffix` added to its name ahead of the extension, shortening the rest of the name
+/// to keep it within the file system's limit.
+pub fn with_stem_suffix(path: &Path, suffix: &str) -> PathBuf {
+    let mut stem = path
+        .file_stem()
+        .unwrap_or_default()
+        .to_string_lossy()
+        .into_owned();
+    let extension = path
+        .extension()
+        .map(|extension| format!(".{}", extension.to_string_lossy()))
+        .unwrap_or_default();
+    let room = sanitize::MAX_NAME_BYTES.saturating_sub(suffix.len() + extension.len());
+    if stem.len() > room {
+        log::info!(
+            "Shortening '{}' to make room for '{}'",
+            path.display(),
+            suffix
+        );
+        sanitize::truncate(&mut stem, room);
+    }
+    path.with_file_name(format!("{stem}{suffix}{extension}"))
+}
@@ -0,0 +1,102 @@
+//! Making filled-in templates safe to use as file names, on whichever systems the library ends up.
+
+use clap::ValueEnum;
+use unicode_normalization::UnicodeNormalization;
+
+/// The most bytes most file systems allow in a single file or directory name
+pub const MAX_NAME_BYTES: usize = 255;
+
+/// Characters Windows (and the FAT and exFAT cards Android devices use) can't have in a file name
+const WINDOWS_FORBIDDEN: &[char] = &['<', '>', ':', '"', '/', '\\', '|', '?', '*'];
+
+/// Names Windows reserves for devices, even with an extension after them
+const WINDOWS_RESERVED: &[&str] = &[
+    "CON", "PRN", "AUX", "NUL", "COM1", "COM2", "COM3", "COM4", "COM5", "COM6", "COM7", "COM8",
+    "COM9", "LPT1", "LPT2", "LPT3", "LPT4", "LPT5", "LPT6", "LPT7", "LPT8", "LPT9",
+];
+
+/// Which file systems file names are made safe for
+#[derive(Copy, Clone, PartialEq, Eq, ValueEnum, Debug, Default)]
+pub enum Sanitizer {
+    /// Only drop the characters no file name can have, `/` and NUL
+    #[default]
+    Posix,
+    /// Also drop characters Windows forbids, trailing dots and spaces, and rename reserved names
+    /// such as `CON`
+    Windows,
+    /// Windows, and also no leading dots or dashes, control characters or runs of whitespace, so
+    /// that names behave the same everywhere
+    Portable,
+    /// Portable, with everything transliterated to ASCII
+    Ascii,
+}
+
+impl Sanitizer {
+    /// Cleans up what a placeholder was filled in with, so that it can't start a directory or
+    /// contain anything this profile doesn't allow.
+    pub fn value(&self, value: &str) -> String {
+        let value = value.nfc().collect::<String>();
+        let value = match self {
+            Sanitizer::Ascii => deunicode::deunicode(&value),
+            _ => value,
+        };
+        self.allowed(&value)
+    }
+
+    /// Cleans up a whole file or directory name, once every placeholder in it is filled in.
+    pub fn name(&self, name: &str) -> String {
+        let mut name = self.allowed(name);
+        if *self != Sanitizer::Posix {
+            name.truncate(name.trim_end_matches(['.', ' ']).len());
+            let stem = name.split('.').next().unwrap_or_default().trim_end();
+            if WINDOWS_RESERVED
+                .iter()
+                .any(|reserved| stem.eq_ignore_ascii_case(reserved))
+            {
+                name.insert(stem.len(), '_');
+            }
+        }
+        if matches!(self, Sanitizer::Portable | Sanitizer::Ascii) {
+            name = name
+                .trim_start_matches(|c: char| c == '.' || c == '-' || c.is_whitespace())
+                .split_whitespace()
+                .collect::<Vec<_>>()
+                .join(" ");
+        }
+        name
+    }
+
+    /// Drops the characters this profile doesn't allow, keeping line breaks and tabs as spaces where
+    /// whitespace gets tidied anyway.
+    fn allowed(&self, name: &str) -> String {
+        name.chars()
+            .map(|c| match self {
+                Sanitizer::Portable | Sanitizer::Ascii if c.is_whitespace() => ' ',
+                _ => c,
+            })
+            .filter(|c| self.allows(*c))
+            .collect()
+    }
+
+    fn allows(&self, c: char) -> bool {
+        match self {
+            Sanitizer::Posix => c != '\0' && c != '/',
+            Sanitizer::Windows => !c.is_ascii_control() && !WINDOWS_FORBIDDEN.contains(&c),
+            Sanitizer::Portable | Sanitizer::Ascii => {
+                !c.is_control() && !WINDOWS_FORBIDDEN.contains(&c)
+            }
+        }
+    }
+}
+
+/// Shortens `value` to at most `max` bytes, without splitting a character.
+pub fn truncate(value: &mut String, max: usize) {
+    if value.len() <= max {
+        return;
+    }
+    let mut end = max;
+    while !value.is_char_boundary(end) {
+        end -= 1;
+    }
+    value.truncate(end);
+}
@@ -0,0 +1,288 @@
+use std::path::Path;
+
+use super::*;
+
+fn fields() -> Fields {
+    Fields {
+        id: 1,
+        title: "Fish & Chips".to_owned(),
+        authors: vec!["one".to_owned(), "two".to_owned()],
+        fandoms: vec!["Original Work".to_owned()],
+        series: Some(SeriesPosition {
+            id: 7,
+            title: "Dinners".to_owned(),
+            position: 3,
+        }),
+        rating: Some("General Audiences".to_owned()),
+        // 2023-11-14T22:13:20Z
+        updated_at: Some(1700000000),
+    }
+}
+
+fn render(template: &str, fields: &Fields) -> PathBuf {
+    template.parse::<Template>().unwrap().render(fields, "epub")
+}
+
+#[test]
+fn default_template_keeps_the_old_names() {
+    assert_eq!(
+        Template::default().render(&fields(), "epub"),
+        Path::new("Fish & Chips [ao3 1].epub")
+    );
+    assert!(!Template::default().needs_work_page());
+}
+
+#[test]
+fn every_placeholder_is_filled_in() {
+    assert_eq!(
+        render(
+            "{fandom}/{author}/{series} {series_index} - {title} ({rating}, {updated}) {id}.{ext}",
+            &fields()
+        ),
+        Path::new(
+            "Original Work/one, two/Dinners 3 - Fish & Chips (General Audiences, 2023-11-14) 1.epub"
+        )
+    );
+}
+
+#[test]
+fn updated_can_be_formatted() {
+    assert_eq!(
+        render("{updated:%Y}/{updated:%b %-d} {title}", &fields()),
+        Path::new("2023/Nov 14 Fish & Chips.epub")
+    );
+}
+
+#[test]
+fn extension_is_added_when_missing() {
+    assert_eq!(render("{title}", &fields()), Path::new("Fish & Chips.epub"));
+    assert_eq!(
+        render("{ext}/{title}", &fields()),
+        Path::new("epub/Fish & Chips")
+    );
+}
+
+#[test]
+fn placeholders_cannot_make_directories() {
+    let fields = Fields {
+        title: "Either/Or".to_owned(),
+        authors: vec!["..".to_owned()],
+        ..fields()
+    };
+
+    assert_eq!(
+        render("{author}/{title}", &fields),
+        Path::new("_/EitherOr.epub")
+    );
+}
+
+#[test]
+fn empty_directories_are_left_out() {
+    let fields = Fields {
+        series: None,
+        ..fields()
+    };
+
+    assert_eq!(
+        render("{fandom}/{series}/{title}", &fields),
+        Path::new("Original Work/Fish & Chips.epub")
+    );
+}
+
+#[test]
+fn braces_can_be_escaped() {
+    assert_eq!(
+        render("{{{id}}} {title}", &fields()),
+        Path::new("{1} Fish & Chips.epub")
+    );
+}
+
+#[test]
+fn invalid_templates_are_rejected() {
+    for template in [
+        "{nope}",
+        "{title",
+        "title}",
+        "/{title}",
+        "../{title}",
+        "{fandom}//{title}",
+        "{title:%Y}",
+        "{updated:%Q}",
+    ] {
+        assert!(
+            template.parse::<Template>().is_err(),
+            "'{}' should be rejected",
+            template
+        );
+    }
+}
+
+#[test]
+fn work_page_is_only_needed_for_its_metadata() {
+    assert!(
+        !"{updated}/{title} {id}"
+            .parse::<Template>()
+            .unwrap()
+            .needs_work_page()
+    );
+    assert!(
+        "{author}/{title}"
+            .parse::<Template>()
+            .unwrap()
+            .needs_work_page()
+    );
+}
+
+fn sanitized(template: &str, sanitizer: Sanitizer, title: &str) -> PathBuf {
+    let fields = Fields {
+        title: title.to_owned(),
+        ..fields()
+    };
+    template
+        .parse::<Template>()
+        .unwrap()
+        .sanitized_for(sanitizer)
+        .render(&fields, "epub")
+}
+
+#[test]
+fn posix_only_drops_slashes_and_nul() {
+    assert_eq!(
+        sanitized("{title}", Sanitizer::Posix, "Why? <Because>: \"no\"\0."),
+        Path::new("Why? <Because>: \"no\"..epub")
+    );
+}
+
+#[test]
+fn windows_drops_forbidden_characters() {
+    assert_eq!(
+        sanitized(
+            "{title}",
+            Sanitizer::Windows,
+            "Why? <Because>: \"a|b*c\\d\""
+        ),
+        Path::new("Why Because abcd.epub")
+    );
+}
+
+#[test]
+fn windows_drops_trailing_dots_and_spaces() {
+    assert_eq!(
+        sanitized("{ext}/{title}", Sanitizer::Windows, "And then... "),
+        Path::new("epub/And then")
+    );
+}
+
+#[test]
+fn windows_renames_reserved_names() {
+    assert_eq!(
+        sanitized("{title}", Sanitizer::Windows, "con"),
+        Path::new("con_.epub")
+    );
+    assert_eq!(
+        sanitized("{title}/{id}", Sanitizer::Windows, "LPT1"),
+        Path::new("LPT1_/1.epub")
+    );
+    assert_eq!(
+        sanitized("{title}", Sanitizer::Windows, "Console"),
+        Path::new("Console.epub")
+    );
+}
+
+#[test]
+fn portable_tidies_leading_dots_and_whitespace() {
+    assert_eq!(
+        sanitized(
+            "{title} [ao3 {id}]",
+            Sanitizer::Portable,
+            "..-Hidden\t\tin  plain\nsight"
+        ),
+        Path::new("Hidden in plain sight [ao3 1].epub")
+    );
+}
+
+#[test]
+fn ascii_transliterates() {
+    assert_eq!(
+        sanitized("{title} [ao3 {id}]", Sanitizer::Ascii, "Café: 北京"),
+        Path::new("Cafe Bei Jing [ao3 1].epub")
+    );
+}
+
+#[test]
+fn names_are_nfc_normalized() {
+    assert_eq!(
+        sanitized("{title}", Sanitizer::Posix, "Cafe\u{301}"),
+        Path::new("Caf\u{e9}.epub")
+    );
+}
+
+#[test]
+fn long_names_keep_the_id() {
+    let title = "é".repeat(200);
+
+    let path = sanitized("{title} [ao3 {id}]", Sanitizer::Posix, &title);
+
+    let name = path.to_str().unwrap();
+    assert!(name.len() <= 255, "{} bytes is too long", name.len());
+    assert!(name.ends_with("é [ao3 1].epub"), "{}", name);
+    assert!(
+        name.len() > 250,
+        "{} bytes is shorter than needed",
+        name.len()
+    );
+}
+
+#[test]
+fn long_names_can_lose_more_than_needed_to_fit_whole_characters() {
+    // One byte too many to cut off an exact number of "é"s
+    let fields = Fields {
+        id: 12,
+        title: "é".repeat(200),
+        ..fields()
+    };
+
+    let path = DEFAULT_TEMPLATE
+        .parse::<Template>()
+        .unwrap()
+        .render(&fields, "epub");
+
+    let name = path.to_str().unwrap();
+    assert!(name.len() <= 255, "{} bytes is too long", name.len());
+    assert!(name.ends_with("é [ao3 12].epub"), "{}", name);
+    assert_eq!(name.len(), 254);
+}
+
+#[test]
+fn long_names_shorten_the_longest_placeholder() {
+    let fields = Fields {
+        title: "t".repeat(300),
+        authors: vec!["a".repeat(100)],
+        ..fields()
+    };
+
+    let path = "{author} - {title}"
+        .parse::<Template>()
+        .unwrap()
+        .render(&fields, "epub");
+
+    assert_eq!(
+        path,
+        Path::new(&format!("{} - {}.epub", "a".repeat(100), "t".repeat(147)))
+    );
+}
+
+#[test]
+fn stem_suffixes_fit_long_names() {
+    let dir = Path::new("works");
+    let short = with_stem_suffix(&dir.join("Taken.html"), " (2)");
+    let long = with_stem_suffix(
+        &dir.join(format!("{}.html", "é".repeat(125))),
+        ".1700000000",
+    );
+
+    assert_eq!(short, dir.join("Taken (2).html"));
+    let name = long.file_name().unwrap().to_str().unwrap();
+    assert!(name.len() <= 255, "{} bytes is too long", name.len());
+    assert!(name.ends_with("é.1700000000.html"), "{}", name);
+}
@@ -0,0 +1,21 @@
+//! Small helpers shared by everything that picks apart the archive's HTML.
+
+use scraper::{ElementRef, Selector};
+
+#[cfg(test)]
+mod tests;
+
+/// Parses one of our own CSS selectors, which are known to be valid.
+pub fn selector(selector: &str) -> Selector {
+    Selector::parse(selector).expect("Hardcoded selectors are valid")
+}
+
+/// The text of `element`, with runs of whitespace collapsed to a single space.
+pub fn text(element: ElementRef) -> String {
+    element
+        .text()
+        .collect::<String>()
+        .split_whitespace()
+        .collect::<Vec<_>>()
+        .join(" ")
+}
@@ -0,0 +1,11 @@
+use scraper::Html;
+
+use super::*;
+
+#[test]
+fn text_collapses_whitespace_across_elements() {
+    let document = Html::parse_fragment("<h2>\n  A <em>Very</em>\n\tLong   Title </h2>");
+    let heading = document.select(&selector("h2")).next().unwrap();
+
+    assert_eq!(text(heading), "A Very Long Title");
+}
@@ -0,0 +1,206 @@
+//! Metadata files written next to each downloaded work, for catalogue tools that shouldn't have
+//! to dig through the downloads themselves.
+
+use std::{
+    collections::BTreeMap,
+    io::Write,
+    path::{Path, PathBuf},
+};
+
+use anyhow::Context;
+use clap::ValueEnum;
+use quick_xml::{
+    Writer,
+    events::{BytesDecl, BytesText, Event},
+};
+use serde::Serialize;
+
+use crate::{
+    Format, ao3, atomic,
+    library::{FileRecord, WorkRecord},
+};
+
+#[cfg(test)]
+mod tests;
+
+#[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, ValueEnum, Debug)]
+pub enum Sidecar {
+    /// `{name}.json`, with everything ao3dl knows about the work
+    Json,
+    /// `{name}.opf`, an OPF package document that Calibre can import
+    Opf,
+}
+
+impl Sidecar {
+    fn file_extension(&self) -> &'static str {
+        match self {
+            Sidecar::Json => "json",
+            Sidecar::Opf => "opf",
+        }
+    }
+}
+
+/// What goes in a JSON sidecar
+#[derive(Serialize)]
+struct JsonSidecar<'a> {
+    #[serde(flatten)]
+    work: &'a ao3::Work,
+    source_url: &'a str,
+    files: &'a BTreeMap<Format, FileRecord>,
+}
+
+/// Writes `sidecar` for `work`, whose downloads are recorded in `record`, next to its downloads
+/// under `dir`. Returns the path written to.
+pub fn write(
+    sidecar: Sidecar,
+    work: &ao3::Work,
+    source_url: &str,
+    record: &WorkRecord,
+    dir: &Path,
+) -> anyhow::Result<PathBuf> {
+    let path = path_for(sidecar, record, dir).with_context(|| {
+        format!(
+            "Cannot name sidecar, since work with ID {} has no downloads",
+            work.id
+        )
+    })?;
+
+    let contents = match sidecar {
+        Sidecar::Json => serde_json::to_vec_pretty(&JsonSidecar {
+            work,
+            source_url,
+            files: &record.files,
+        })
+        .context("Cannot serialize JSON sidecar")?,
+        Sidecar::Opf => opf(work, source_url, &record.files).context("Cannot build OPF sidecar")?,
+    };
+    atomic::write(&path, contents)
+        .with_context(|| format!("Cannot write sidecar to '{}'", path.display()))?;
+
+    log::debug!("Wrote {:?} sidecar to '{}'", sidecar, path.display());
+
+    Ok(path)
+}
+
+/// Where `sidecar` goes for the work recorded in `record`: next to its downloads, named after them.
+pub fn path_for(sidecar: Sidecar, record: &WorkRecord, dir: &Path) -> Option<PathBuf> {
+    let file = record.files.values().next()?;
+    Some(dir.join(file.path.with_extension(sidecar.file_extension())))
+}
+
+/// Builds an OPF 2.0 package document holding `work`'s metadata, laid out the way Calibre writes
+/// its own `metadata.opf` files.
+fn opf(
+    work: &ao3::Work,
+    source_url: &str,
+    files: &BTreeMap<Format, FileRecord>,
+) -> anyhow::Result<Vec<u8>> {
+    let mut writer = Writer::new_with_indent(Vec::new(), b' ', 2);
+    writer.write_event(Event::Decl(BytesDecl::new("1.0", Some("utf-8"), None)))?;
+    writer
+        .create_element("package")
+        .with_attributes([
+            ("xmlns", "http://www.idpf.org/2007/opf"),
+            ("unique-identifier", "ao3_id"),
+            ("version", "2.0"),
+        ])
+        .write_inner_content(|writer| {
+            writer
+                .create_element("metadata")
+                .with_attributes([
+                    ("xmlns:dc", "http://purl.org/dc/elements/1.1/"),
+                    ("xmlns:opf", "http://www.idpf.org/2007/opf"),
+                ])
+                .write_inner_content(|writer| {
+                    let dc = |writer: &mut Writer<Vec<u8>>, name: &str, text: &str| {
+                        writer
+                            .create_element(name)
+                            .write_text_content(BytesText::new(text))
+                            .map(|_| ())
+                    };
+                    let meta = |writer: &mut Writer<Vec<u8>>, name: &str, content: &str| {
+                        writer
+                            .create_element("meta")
+                            .with_attributes([("name", name), ("content", content)])
+                            .write_empty()
+                            .map(|_| ())
+                    };
+
+                    writer
+                        .create_element("dc:identifier")
+                        .with_attributes([("id", "ao3_id"), ("opf:scheme", "ao3")])
+                        .write_text_content(BytesText::new(&work.id.to_string()))?;
+                    writer
+                        .create_element("dc:identifier")
+                        .with_attribute(("opf:scheme", "URL"))
+                        .write_text_content(BytesText::new(source_url))?;
+                    dc(writer, "dc:title", &work.title)?;
+                    for author in &work.authors {
+                        writer
+                            .create_element("dc:creator")
+                            .with_attribute(("opf:role", "aut"))
+                            .write_text_content(BytesText::new(author))?;
+                    }
+                    dc(writer, "dc:publisher", "Archive of Our Own")?;
+                    if let Some(published) = work.published {
+                        dc(writer, "dc:date", &published.to_string())?;
+                    }
+                    if let Some(language) = &work.language {
+                        dc(writer, "dc:language", language)?;
+                    }
+                    if let Some(summary) = &work.summary {
+                        dc(writer, "dc:description", summary)?;
+                    }
+                    for subject in work
+                        .rating
+                        .iter()
+                        .chain(&work.warnings)
+                        .chain(&work.categories)
+                        .chain(&work.fandoms)
+                        .chain(&work.relationships)
+                        .chain(&work.characters)
+                        .chain(&work.freeform_tags)
+                    {
+                        dc(writer, "dc:subject", subject)?;
+                    }
+                    if let Some(series) = work.series.first() {
+                        meta(writer, "calibre:series", &series.title)?;
+                        meta(writer, "calibre:series_index", &series.position.to_string())?;
+                    }
+                    meta(writer, "ao3dl:updated_at", &work.updated_at.to_string())?;
+                    for (format, file) in files {
+                        meta(
+                            writer,
+                            &format!("ao3dl:sha256:{}", format.file_extension()),
+                            &file.sha256,
+                        )?;
+                    }
+                    Ok(())
+                })?;
+            writer
+                .create_element("manifest")
+                .write_inner_content(|writer| {
+                    for (format, file) in files {
+                        let href = file
+                            .path
+                            .file_name()
+                            .unwrap_or(file.path.as_os_str())
+                            .to_string_lossy();
+                        writer
+                            .create_element("item")
+                            .with_attributes([
+                                ("id", format.file_extension()),
+                                ("href", &href),
+                                ("media-type", format.media_type()),
+                            ])
+                            .write_empty()?;
+                    }
+                    Ok(())
+                })?;
+            Ok(())
+        })?;
+
+    let mut contents = writer.into_inner();
+    writeln!(contents)?;
+    Ok(contents)
+}
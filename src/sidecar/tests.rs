@@ -0,0 +1,122 @@
+use std::fs;
+
+use super::*;
+use crate::library::{Library, sha256};
+
+fn work() -> ao3::Work {
+    ao3::Work {
+        id: 1,
+        title: "Fish & Chips".to_owned(),
+        authors: vec!["one".to_owned(), "two".to_owned()],
+        rating: Some("General Audiences".to_owned()),
+        warnings: vec!["No Archive Warnings Apply".to_owned()],
+        categories: vec!["Gen".to_owned()],
+        fandoms: vec!["Original Work".to_owned()],
+        relationships: Vec::new(),
+        characters: vec!["A".to_owned()],
+        freeform_tags: vec!["Fluff".to_owned()],
+        language: Some("English".to_owned()),
+        series: vec![ao3::SeriesPosition {
+            id: 7,
+            title: "Dinners".to_owned(),
+            position: 3,
+        }],
+        summary: Some("A <short> one.".to_owned()),
+        words: Some(1000),
+        chapters: 1,
+        total_chapters: Some(1),
+        kudos: None,
+        hits: None,
+        published: chrono::NaiveDate::from_ymd_opt(2024, 1, 2),
+        updated: chrono::NaiveDate::from_ymd_opt(2024, 1, 2),
+        updated_at: 1700000000,
+    }
+}
+
+fn record() -> WorkRecord {
+    let mut library = Library::default();
+    library.record(
+        1,
+        1700000000,
+        Format::EPUB,
+        "Fish & Chips [ao3 1].epub".into(),
+        sha256(b"epub"),
+    );
+    library.record(
+        1,
+        1700000000,
+        Format::PDF,
+        "Fish & Chips [ao3 1].pdf".into(),
+        sha256(b"pdf"),
+    );
+    library.get(1).unwrap().clone()
+}
+
+#[test]
+fn json_sidecar_holds_metadata_and_hashes() {
+    let dir = tempfile::tempdir().unwrap();
+
+    let path = write(
+        Sidecar::Json,
+        &work(),
+        "https://archiveofourown.org/works/1",
+        &record(),
+        dir.path(),
+    )
+    .unwrap();
+
+    assert_eq!(path, dir.path().join("Fish & Chips [ao3 1].json"));
+    let json: serde_json::Value = serde_json::from_slice(&fs::read(&path).unwrap()).unwrap();
+    assert_eq!(json["title"], "Fish & Chips");
+    assert_eq!(json["updated_at"], 1700000000);
+    assert_eq!(json["published"], "2024-01-02");
+    assert_eq!(json["source_url"], "https://archiveofourown.org/works/1");
+    assert_eq!(json["series"][0]["position"], 3);
+    assert_eq!(json["files"]["pdf"]["sha256"], sha256(b"pdf"));
+    assert_eq!(json["files"]["epub"]["path"], "Fish & Chips [ao3 1].epub");
+}
+
+#[test]
+fn opf_sidecar_is_calibre_metadata() {
+    let dir = tempfile::tempdir().unwrap();
+
+    let path = write(
+        Sidecar::Opf,
+        &work(),
+        "https://archiveofourown.org/works/1",
+        &record(),
+        dir.path(),
+    )
+    .unwrap();
+
+    assert_eq!(path, dir.path().join("Fish & Chips [ao3 1].opf"));
+    let opf = fs::read_to_string(&path).unwrap();
+    for expected in [
+        r#"<dc:identifier id="ao3_id" opf:scheme="ao3">1</dc:identifier>"#,
+        "<dc:title>Fish &amp; Chips</dc:title>",
+        r#"<dc:creator opf:role="aut">two</dc:creator>"#,
+        "<dc:description>A &lt;short&gt; one.</dc:description>",
+        "<dc:subject>Fluff</dc:subject>",
+        r#"<meta name="calibre:series" content="Dinners"/>"#,
+        r#"<meta name="calibre:series_index" content="3"/>"#,
+        r#"<item id="pdf" href="Fish &amp; Chips [ao3 1].pdf" media-type="application/pdf"/>"#,
+    ] {
+        assert!(opf.contains(expected), "{expected} not in {opf}");
+    }
+    assert!(opf.contains(&format!(
+        r#"<meta name="ao3dl:sha256:epub" content="{}"/>"#,
+        sha256(b"epub")
+    )));
+}
+
+#[test]
+fn works_without_downloads_have_no_sidecar() {
+    let record = WorkRecord {
+        updated_at: 1,
+        downloaded_at: 1,
+        files: BTreeMap::new(),
+        series: None,
+    };
+
+    assert_eq!(path_for(Sidecar::Json, &record, Path::new(".")), None);
+}
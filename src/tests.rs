@@ -0,0 +1,1014 @@
+//! End-to-end tests of the whole CLI flow, run against a [`MockArchive`].
+
+use std::{fs, path::Path};
+
+use clap::Parser;
+
+use super::*;
+use crate::mock::{self, MockArchive, MockWork};
+
+/// Where runs keep their session, instead of the real home directory
+const SESSION_FILE: &str = ".session.json";
+
+fn credentials() -> anyhow::Result<(String, String)> {
+    Ok((mock::USERNAME.to_owned(), mock::PASSWORD.to_owned()))
+}
+
+async fn run_cli(
+    archive: &MockArchive,
+    dest: &Path,
+    works: &str,
+    extra_args: &[&str],
+) -> anyhow::Result<()> {
+    let works_file = dest.join("works.txt");
+    fs::write(&works_file, works).unwrap();
+
+    let mut args = Cli::try_parse_from(
+        [
+            "ao3dl",
+            works_file.to_str().unwrap(),
+            "--base-url",
+            archive.base_url.as_str(),
+            "--session-file",
+            dest.join(SESSION_FILE).to_str().unwrap(),
+        ]
+        .iter()
+        .chain(extra_args),
+    )
+    .unwrap();
+    args.formats.sort();
+
+    let (clock, _) = ao3::Clock::manual();
+    run(args, dest, clock, credentials).await
+}
+
+fn files_in(dir: &Path) -> Vec<String> {
+    let mut names = fs::read_dir(dir)
+        .unwrap()
+        .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+        .filter(|name| {
+            name != "works.txt"
+                && name != library::LIBRARY_FILE_NAME
+                && name != SESSION_FILE
+                && name != atomic::LOCK_FILE_NAME
+        })
+        .collect::<Vec<_>>();
+    names.sort();
+    names
+}
+
+#[tokio::test]
+async fn downloads_every_work_in_every_format() {
+    let archive = MockArchive::start().await;
+    archive.add_work(101, MockWork::new("First Work", 1700000000));
+    archive.add_work(102, MockWork::new("Second Work", 1700000001));
+    let dest = tempfile::tempdir().unwrap();
+
+    let works = format!(
+        "101\n{{\"id\": 102, \"timestamp\": 1700000001}}\n{}works/101\n",
+        archive.base_url
+    );
+    run_cli(
+        &archive,
+        dest.path(),
+        &works,
+        &["--format", "html", "--format", "epub"],
+    )
+    .await
+    .unwrap();
+
+    assert_eq!(
+        files_in(dest.path()),
+        [
+            "First Work [ao3 101].epub",
+            "First Work [ao3 101].html",
+            "Second Work [ao3 102].epub",
+            "Second Work [ao3 102].html",
+        ]
+    );
+    // Only the work without a timestamp needed its work page fetched, and only once
+    assert_eq!(archive.hits("/works/101"), 1);
+    assert_eq!(archive.hits("/works/102"), 0);
+}
+
+#[tokio::test]
+async fn failed_works_are_written_out() {
+    let archive = MockArchive::start().await;
+    archive.add_work(201, MockWork::new("Fine", 1700000000));
+    archive.add_work(203, MockWork::new("Hidden", 1700000000).hidden());
+    let dest = tempfile::tempdir().unwrap();
+
+    run_cli(&archive, dest.path(), "203\n201\n202\n", &[])
+        .await
+        .unwrap();
+
+    assert_eq!(
+        files_in(dest.path()),
+        ["Fine [ao3 201].epub", "failed-works.txt"]
+    );
+    assert_eq!(
+        fs::read_to_string(dest.path().join("failed-works.txt")).unwrap(),
+        "202\n203\n"
+    );
+}
+
+#[tokio::test]
+async fn bad_credentials_stop_the_run() {
+    let archive = MockArchive::start().await;
+    archive.add_work(301, MockWork::new("Unreachable", 1700000000));
+    let dest = tempfile::tempdir().unwrap();
+
+    let works_file = dest.path().join("works.txt");
+    fs::write(&works_file, "301\n").unwrap();
+    let args = Cli::try_parse_from([
+        "ao3dl",
+        works_file.to_str().unwrap(),
+        "--base-url",
+        archive.base_url.as_str(),
+        "--session-file",
+        dest.path().join(SESSION_FILE).to_str().unwrap(),
+    ])
+    .unwrap();
+    let (clock, _) = ao3::Clock::manual();
+    let err = run(args, dest.path(), clock, || {
+        Ok((mock::USERNAME.to_owned(), "wrong".to_owned()))
+    })
+    .await
+    .unwrap_err();
+
+    assert!(format!("{err:#}").contains("Could not log in"), "{err:#}");
+    assert_eq!(archive.hits("/downloads/301/x.epub"), 0);
+    assert!(files_in(dest.path()).is_empty());
+}
+
+#[tokio::test]
+async fn empty_works_file_never_logs_in() {
+    let archive = MockArchive::start().await;
+    let dest = tempfile::tempdir().unwrap();
+
+    run_cli(&archive, dest.path(), "not a work\n", &[])
+        .await
+        .unwrap();
+
+    assert!(archive.requests().is_empty());
+}
+
+#[tokio::test]
+async fn concurrent_downloads_report_failures_correctly() {
+    let archive = MockArchive::start().await;
+    for id in 401..=408 {
+        let work = MockWork::new(&format!("Work {id}"), 1700000000);
+        archive.add_work(id, if id % 3 == 0 { work.hidden() } else { work });
+    }
+    archive.script(
+        "/works/401",
+        [mock::Scripted::TooManyRequests {
+            retry_after: Some("10"),
+        }],
+    );
+    let dest = tempfile::tempdir().unwrap();
+
+    let works = (401..=409).map(|id| format!("{id}\n")).collect::<String>();
+    run_cli(
+        &archive,
+        dest.path(),
+        &works,
+        &["--jobs", "4", "--format", "epub", "--format", "pdf"],
+    )
+    .await
+    .unwrap();
+
+    assert_eq!(
+        fs::read_to_string(dest.path().join("failed-works.txt")).unwrap(),
+        "402\n405\n408\n409\n"
+    );
+    for id in [401, 403, 404, 406, 407] {
+        assert!(
+            dest.path()
+                .join(format!("Work {id} [ao3 {id}].pdf"))
+                .exists()
+        );
+    }
+}
+
+#[test]
+fn jobs_must_be_positive() {
+    assert!(Cli::try_parse_from(["ao3dl", "works.txt", "--jobs", "0"]).is_err());
+}
+
+#[tokio::test]
+async fn unchanged_works_are_skipped_on_later_runs() {
+    let archive = MockArchive::start().await;
+    archive.add_work(501, MockWork::new("Stable", 1700000000));
+    archive.add_work(502, MockWork::new("Changing", 1700000000));
+    let dest = tempfile::tempdir().unwrap();
+
+    run_cli(&archive, dest.path(), "501\n502\n", &[])
+        .await
+        .unwrap();
+    assert_eq!(archive.hits("/downloads/501/x.epub"), 1);
+    assert_eq!(archive.hits("/downloads/502/x.epub"), 1);
+
+    archive.add_work(502, MockWork::new("Changing", 1700000500));
+    run_cli(&archive, dest.path(), "501\n502\n", &[])
+        .await
+        .unwrap();
+    assert_eq!(archive.hits("/downloads/501/x.epub"), 1);
+    assert_eq!(archive.hits("/downloads/502/x.epub"), 2);
+
+    let library = library::Library::load(dest.path()).unwrap();
+    assert!(library.is_up_to_date(501, 1700000000, &[Format::EPUB], dest.path()));
+    assert!(library.is_up_to_date(502, 1700000500, &[Format::EPUB], dest.path()));
+
+    run_cli(&archive, dest.path(), "501\n502\n", &["--force"])
+        .await
+        .unwrap();
+    assert_eq!(archive.hits("/downloads/501/x.epub"), 2);
+}
+
+#[tokio::test]
+async fn later_runs_only_log_in_once_the_session_expires() {
+    let archive = MockArchive::start().await;
+    archive.add_work(511, MockWork::new("Stable", 1700000000));
+    let dest = tempfile::tempdir().unwrap();
+    let session_file = dest.path().join(SESSION_FILE);
+
+    run_cli(&archive, dest.path(), "511\n", &[]).await.unwrap();
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        let mode = fs::metadata(&session_file).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+    }
+
+    run_cli(&archive, dest.path(), "511\n", &["--force"])
+        .await
+        .unwrap();
+    assert_eq!(archive.hits("/users/login"), 1);
+    assert_eq!(archive.hits("/downloads/511/x.epub"), 2);
+
+    archive.expire_sessions();
+    run_cli(&archive, dest.path(), "511\n", &["--force"])
+        .await
+        .unwrap();
+    assert_eq!(archive.hits("/users/login"), 2);
+    assert_eq!(archive.hits("/downloads/511/x.epub"), 3);
+}
+
+#[tokio::test]
+async fn anonymous_runs_report_restricted_works() {
+    let archive = MockArchive::start().await;
+    archive.add_work(521, MockWork::new("Public", 1700000000));
+    archive.add_work(522, MockWork::new("Explicit", 1700000000).adult());
+    archive.add_work(523, MockWork::new("Members Only", 1700000000).restricted());
+    archive.add_work(
+        524,
+        MockWork::new("Also Members Only", 1700000000).restricted(),
+    );
+    let dest = tempfile::tempdir().unwrap();
+
+    run_cli(
+        &archive,
+        dest.path(),
+        "521\n522\n523\n{\"id\": 524, \"timestamp\": 1700000000}\n",
+        &["--no-login"],
+    )
+    .await
+    .unwrap();
+
+    assert_eq!(archive.hits("/users/login"), 2);
+    assert!(!archive.requests().iter().any(|req| req.starts_with("POST")));
+    assert_eq!(
+        files_in(dest.path()),
+        [
+            "Explicit [ao3 522].epub",
+            "Public [ao3 521].epub",
+            "restricted-works.txt"
+        ]
+    );
+    assert_eq!(
+        fs::read_to_string(dest.path().join("restricted-works.txt")).unwrap(),
+        "523\n524\n"
+    );
+    assert!(!dest.path().join(SESSION_FILE).exists());
+}
+
+#[test]
+fn bookmarks_need_logging_in() {
+    assert!(Cli::try_parse_from(["ao3dl", "--bookmarks", "--no-login"]).is_err());
+}
+
+#[tokio::test]
+async fn update_redownloads_only_changed_works() {
+    let archive = MockArchive::start().await;
+    for id in 601..=603 {
+        archive.add_work(id, MockWork::new(&format!("Work {id}"), 1700000000));
+    }
+    let dest = tempfile::tempdir().unwrap();
+    run_cli(&archive, dest.path(), "601\n602\n603\n", &[])
+        .await
+        .unwrap();
+
+    archive.add_work(602, MockWork::new("Work 602", 1700000900));
+    archive.remove_work(603);
+    let (clock, _) = ao3::Clock::manual();
+    let session_file = dest.path().join(SESSION_FILE);
+    let args = Cli::try_parse_from([
+        "ao3dl",
+        "--update",
+        "--base-url",
+        archive.base_url.as_str(),
+        "--session-file",
+        session_file.to_str().unwrap(),
+    ])
+    .unwrap();
+    run(args, dest.path(), clock, credentials).await.unwrap();
+
+    // Every known work was checked against the archive...
+    assert_eq!(archive.hits("/works/601"), 2);
+    assert_eq!(archive.hits("/works/603"), 2);
+    // ...but only the updated one was downloaded again
+    assert_eq!(archive.hits("/downloads/601/x.epub"), 1);
+    assert_eq!(archive.hits("/downloads/602/x.epub"), 2);
+    assert_eq!(
+        fs::read_to_string(dest.path().join("failed-works.txt")).unwrap(),
+        "603\n"
+    );
+}
+
+#[test]
+fn works_file_is_only_optional_when_updating() {
+    assert!(Cli::try_parse_from(["ao3dl"]).is_err());
+    assert!(Cli::try_parse_from(["ao3dl", "--update"]).is_ok());
+    assert!(Cli::try_parse_from(["ao3dl", "--update", "--force"]).is_err());
+}
+
+#[tokio::test]
+async fn bookmarks_can_be_saved_as_a_works_list() {
+    let archive = MockArchive::start().await;
+    archive.add_bookmark("/works/701", true);
+    archive.add_bookmark("/works/702", false);
+    let dest = tempfile::tempdir().unwrap();
+    let list = dest.path().join("list.jsonl");
+
+    run_cli(
+        &archive,
+        dest.path(),
+        "{\"id\": 700, \"timestamp\": 1700000000}\n702\n",
+        &["--bookmarks", "--save-works-list", list.to_str().unwrap()],
+    )
+    .await
+    .unwrap();
+
+    assert_eq!(
+        fs::read_to_string(&list).unwrap(),
+        "{\"id\":700,\"timestamp\":1700000000}\n702\n701\n"
+    );
+    assert_eq!(archive.hits("/downloads/701/x.epub"), 0);
+}
+
+#[tokio::test]
+async fn bookmarks_are_downloaded_without_a_works_file() {
+    let archive = MockArchive::start().await;
+    archive.add_work(711, MockWork::new("Bookmarked", 1700000000));
+    archive.add_bookmark("/works/711", true);
+    let dest = tempfile::tempdir().unwrap();
+
+    let args = Cli::try_parse_from([
+        "ao3dl",
+        "--bookmarks",
+        "--base-url",
+        archive.base_url.as_str(),
+        "--session-file",
+        dest.path().join(SESSION_FILE).to_str().unwrap(),
+    ])
+    .unwrap();
+    let (clock, _) = ao3::Clock::manual();
+    run(args, dest.path(), clock, credentials).await.unwrap();
+
+    assert_eq!(files_in(dest.path()), ["Bookmarked [ao3 711].epub"]);
+}
+
+#[tokio::test]
+async fn bookmarks_are_found_after_logging_in_by_email() {
+    let archive = MockArchive::start().await;
+    archive.add_work(712, MockWork::new("Bookmarked", 1700000000));
+    archive.add_bookmark("/works/712", true);
+    let dest = tempfile::tempdir().unwrap();
+
+    let args = Cli::try_parse_from([
+        "ao3dl",
+        "--bookmarks",
+        "--base-url",
+        archive.base_url.as_str(),
+        "--session-file",
+        dest.path().join(SESSION_FILE).to_str().unwrap(),
+    ])
+    .unwrap();
+    let (clock, _) = ao3::Clock::manual();
+    run(args, dest.path(), clock, || {
+        Ok((mock::EMAIL.to_owned(), mock::PASSWORD.to_owned()))
+    })
+    .await
+    .unwrap();
+
+    assert_eq!(files_in(dest.path()), ["Bookmarked [ao3 712].epub"]);
+}
+
+#[tokio::test]
+async fn series_urls_are_expanded_into_their_works() {
+    let archive = MockArchive::start().await;
+    for id in 801..=803 {
+        archive.add_work(id, MockWork::new(&format!("Part {id}"), 1700000000));
+    }
+    archive.add_series(80, "Trilogy", &[802, 801, 803]);
+    let dest = tempfile::tempdir().unwrap();
+
+    let works = format!("801\n{}series/80\n", archive.base_url);
+    run_cli(&archive, dest.path(), &works, &[]).await.unwrap();
+
+    assert_eq!(
+        files_in(dest.path()),
+        [
+            "Part 801 [ao3 801].epub",
+            "Part 802 [ao3 802].epub",
+            "Part 803 [ao3 803].epub",
+        ]
+    );
+    assert_eq!(archive.hits("/downloads/801/x.epub"), 1);
+    let library: serde_json::Value = serde_json::from_str(
+        &fs::read_to_string(dest.path().join(library::LIBRARY_FILE_NAME)).unwrap(),
+    )
+    .unwrap();
+    assert_eq!(
+        library["works"]["801"]["series"],
+        serde_json::json!({"id": 80, "title": "Trilogy", "position": 2})
+    );
+}
+
+#[tokio::test]
+async fn missing_series_are_reported_without_stopping_the_run() {
+    let archive = MockArchive::start().await;
+    archive.add_work(821, MockWork::new("Standalone", 1700000000));
+    let dest = tempfile::tempdir().unwrap();
+
+    let works = format!(
+        "{}series/82
+821
+",
+        archive.base_url
+    );
+    run_cli(&archive, dest.path(), &works, &[]).await.unwrap();
+
+    assert_eq!(
+        files_in(dest.path()),
+        ["Standalone [ao3 821].epub", "failed-works.txt"]
+    );
+    assert_eq!(
+        fs::read_to_string(dest.path().join("failed-works.txt")).unwrap(),
+        format!("{}series/82\n", archive.base_url)
+    );
+}
+
+#[tokio::test]
+async fn series_positions_survive_works_left_out_of_the_listing() {
+    let archive = MockArchive::start().await;
+    archive.add_work(811, MockWork::new("First", 1700000000));
+    archive.add_work(812, MockWork::new("Second", 1700000000).restricted());
+    archive.add_work(813, MockWork::new("Third", 1700000000));
+    archive.add_series(81, "Trilogy", &[811, 812, 813]);
+    let dest = tempfile::tempdir().unwrap();
+
+    let works = format!("{}series/81\n", archive.base_url);
+    run_cli(
+        &archive,
+        dest.path(),
+        &works,
+        &["--no-login", "--name-template", "{series_index} {title}"],
+    )
+    .await
+    .unwrap();
+
+    assert_eq!(files_in(dest.path()), ["1 First.epub", "3 Third.epub"]);
+}
+
+#[tokio::test]
+async fn author_and_gift_listings_are_expanded_into_their_works() {
+    let archive = MockArchive::start().await;
+    for id in 901..=904 {
+        archive.add_work(id, MockWork::new(&format!("Work {id}"), 1700000000));
+    }
+    archive.add_listing("/users/someone/works", &[901, 902, 903]);
+    archive.add_listing("/users/someone/pseuds/alias/works", &[903]);
+    archive.add_listing("/users/someone/gifts", &[904]);
+    let dest = tempfile::tempdir().unwrap();
+
+    let works = [
+        "users/someone/works",
+        "users/someone/pseuds/alias/works?page=2",
+        "users/someone/gifts",
+    ]
+    .map(|path| format!("{}{path}\n", archive.base_url))
+    .concat();
+    run_cli(&archive, dest.path(), &works, &[]).await.unwrap();
+
+    assert_eq!(
+        files_in(dest.path()),
+        (901..=904)
+            .map(|id| format!("Work {id} [ao3 {id}].epub"))
+            .collect::<Vec<_>>()
+    );
+    assert_eq!(archive.hits("/users/someone/works"), 2);
+    assert_eq!(archive.hits("/downloads/903/x.epub"), 1);
+}
+
+#[tokio::test]
+async fn missing_listings_are_reported_without_stopping_the_run() {
+    let archive = MockArchive::start().await;
+    archive.add_work(911, MockWork::new("Still Here", 1700000000));
+    archive.add_listing("/users/someone/works", &[911]);
+    let dest = tempfile::tempdir().unwrap();
+
+    let works = format!(
+        "{0}users/renamed/works
+{0}users/someone/works
+",
+        archive.base_url
+    );
+    run_cli(&archive, dest.path(), &works, &[]).await.unwrap();
+
+    assert_eq!(
+        files_in(dest.path()),
+        ["Still Here [ao3 911].epub", "failed-works.txt"]
+    );
+    assert_eq!(
+        fs::read_to_string(dest.path().join("failed-works.txt")).unwrap(),
+        format!("{}users/renamed/works\n", archive.base_url)
+    );
+}
+
+#[tokio::test]
+async fn collection_listings_honour_max_works() {
+    let archive = MockArchive::start().await;
+    for id in 1001..=1005 {
+        archive.add_work(id, MockWork::new(&format!("Fest {id}"), 1700000000));
+    }
+    archive.add_listing(
+        "/collections/SomeFest/works",
+        &[1005, 1004, 1003, 1002, 1001],
+    );
+    let dest = tempfile::tempdir().unwrap();
+
+    let works = format!("{}collections/SomeFest/works\n", archive.base_url);
+    run_cli(&archive, dest.path(), &works, &["--max-works", "3"])
+        .await
+        .unwrap();
+
+    assert_eq!(
+        files_in(dest.path()),
+        [
+            "Fest 1003 [ao3 1003].epub",
+            "Fest 1004 [ao3 1004].epub",
+            "Fest 1005 [ao3 1005].epub",
+        ]
+    );
+}
+
+#[tokio::test]
+async fn every_link_shape_is_downloaded_and_bad_lines_are_skipped() {
+    let archive = MockArchive::start().await;
+    for id in 1101..=1104 {
+        archive.add_work(id, MockWork::new(&format!("Work {id}"), 1700000000));
+    }
+    archive.add_chapter(110402, 1104);
+    let dest = tempfile::tempdir().unwrap();
+
+    let works = [
+        "works/1101/chapters/110102",
+        "works/1102?view_full_work=true",
+        "collections/SomeFest/works/1103",
+        "chapters/110402",
+        "chapters/999999",
+    ]
+    .map(|path| format!("{}{path}\n", archive.base_url))
+    .concat()
+        + "# a comment\n\nhttps://example.com/works/1\n";
+    run_cli(&archive, dest.path(), &works, &[]).await.unwrap();
+
+    assert_eq!(
+        files_in(dest.path()),
+        (1101..=1104)
+            .map(|id| format!("Work {id} [ao3 {id}].epub"))
+            .collect::<Vec<_>>()
+    );
+}
+
+#[tokio::test]
+async fn sidecars_are_written_next_to_downloads() {
+    let archive = MockArchive::start().await;
+    archive.add_work(1201, MockWork::new("Annotated", 1700000000));
+    archive.add_work(1202, MockWork::new("Timestamped", 1700000000));
+    let dest = tempfile::tempdir().unwrap();
+
+    let works = "1201\n{\"id\": 1202, \"timestamp\": 1700000000}\n";
+    run_cli(&archive, dest.path(), works, &["--format", "html"])
+        .await
+        .unwrap();
+    run_cli(
+        &archive,
+        dest.path(),
+        works,
+        &["--format", "html", "--sidecar", "json", "--sidecar", "opf"],
+    )
+    .await
+    .unwrap();
+
+    // Written for works that were already up to date too
+    assert_eq!(
+        files_in(dest.path()),
+        [
+            "Annotated [ao3 1201].html",
+            "Annotated [ao3 1201].json",
+            "Annotated [ao3 1201].opf",
+            "Timestamped [ao3 1202].html",
+            "Timestamped [ao3 1202].json",
+            "Timestamped [ao3 1202].opf",
+        ]
+    );
+    assert_eq!(archive.hits("/downloads/1201/x.html"), 1);
+    let json: serde_json::Value = serde_json::from_str(
+        &fs::read_to_string(dest.path().join("Timestamped [ao3 1202].json")).unwrap(),
+    )
+    .unwrap();
+    assert_eq!(json["title"], "Timestamped");
+    assert_eq!(
+        json["source_url"],
+        format!("{}works/1202", archive.base_url)
+    );
+    assert_eq!(
+        json["files"]["html"]["sha256"],
+        library::sha256(&fs::read(dest.path().join("Timestamped [ao3 1202].html")).unwrap())
+    );
+}
+
+#[tokio::test]
+async fn html_only_downloads_are_named_after_the_work() {
+    let archive = MockArchive::start().await;
+    archive.add_work(1301, MockWork::new("Plain Text", 1700000000));
+    let dest = tempfile::tempdir().unwrap();
+
+    run_cli(
+        &archive,
+        dest.path(),
+        "{\"id\": 1301, \"timestamp\": 1700000000}\n",
+        &["--format", "html", "--format", "pdf"],
+    )
+    .await
+    .unwrap();
+
+    assert_eq!(
+        files_in(dest.path()),
+        ["Plain Text [ao3 1301].html", "Plain Text [ao3 1301].pdf"]
+    );
+    assert_eq!(archive.hits("/works/1301"), 0);
+}
+
+#[tokio::test]
+async fn kindle_only_downloads_are_named_after_the_work() {
+    let archive = MockArchive::start().await;
+    archive.add_work(1401, MockWork::new("For Kindles", 1700000000));
+    let dest = tempfile::tempdir().unwrap();
+
+    run_cli(
+        &archive,
+        dest.path(),
+        "{\"id\": 1401, \"timestamp\": 1700000000}\n",
+        &["--format", "azw3", "--format", "mobi"],
+    )
+    .await
+    .unwrap();
+
+    assert_eq!(
+        files_in(dest.path()),
+        ["For Kindles [ao3 1401].azw3", "For Kindles [ao3 1401].mobi"]
+    );
+}
+
+#[tokio::test]
+async fn pdf_only_downloads_are_named_after_the_work() {
+    let archive = MockArchive::start().await;
+    archive.add_work(1501, MockWork::new("On Paper", 1700000000));
+    let dest = tempfile::tempdir().unwrap();
+
+    run_cli(
+        &archive,
+        dest.path(),
+        "{\"id\": 1501, \"timestamp\": 1700000000}\n",
+        &["--format", "pdf"],
+    )
+    .await
+    .unwrap();
+
+    assert_eq!(files_in(dest.path()), ["On Paper [ao3 1501].pdf"]);
+}
+
+#[tokio::test]
+async fn inspecting_never_goes_online() {
+    let dest = tempfile::tempdir().unwrap();
+    fs::write(dest.path().join("a.pdf"), mock::pdf("A", "one")).unwrap();
+    fs::write(dest.path().join("b.mobi"), b"not really").unwrap();
+    fs::write(dest.path().join("a.json"), b"{}").unwrap();
+
+    let args = Cli::try_parse_from([
+        "ao3dl",
+        "--inspect",
+        dest.path().to_str().unwrap(),
+        // Unreachable, so going online would fail
+        "--base-url",
+        "http://127.0.0.1:1",
+    ])
+    .unwrap();
+    let (clock, _) = ao3::Clock::manual();
+    run(args, dest.path(), clock, || panic!("Asked for credentials"))
+        .await
+        .unwrap();
+
+    assert!(extractor::is_download(Path::new("x/Title [ao3 1].AZW3")));
+    assert!(!extractor::is_download(Path::new("Title [ao3 1].opf")));
+}
+
+#[tokio::test]
+async fn name_template_sorts_downloads_into_directories() {
+    let archive = MockArchive::start().await;
+    archive.add_work(1601, MockWork::new("Sorted", 1700000000));
+    archive.add_work(1602, MockWork::new("Also/Sorted", 1700000000));
+    let dest = tempfile::tempdir().unwrap();
+
+    run_cli(
+        &archive,
+        dest.path(),
+        "{\"id\": 1601, \"timestamp\": 1700000000}\n1602\n",
+        &[
+            "--format",
+            "epub",
+            "--format",
+            "html",
+            "--name-template",
+            "{fandom}/{author}/{title} ({updated:%Y}) [ao3 {id}]",
+        ],
+    )
+    .await
+    .unwrap();
+
+    let dir = dest.path().join("Original Work").join("someone");
+    assert_eq!(
+        files_in(&dir),
+        [
+            "AlsoSorted (2023) [ao3 1602].epub",
+            "AlsoSorted (2023) [ao3 1602].html",
+            "Sorted (2023) [ao3 1601].epub",
+            "Sorted (2023) [ao3 1601].html",
+        ]
+    );
+    let library = fs::read_to_string(dest.path().join(library::LIBRARY_FILE_NAME)).unwrap();
+    assert!(library.contains("Original Work/someone/Sorted (2023) [ao3 1601].epub"));
+}
+
+#[test]
+fn invalid_name_templates_are_rejected_up_front() {
+    assert!(Cli::try_parse_from(["ao3dl", "works.txt", "--name-template", "{nope}"]).is_err());
+}
+
+#[tokio::test]
+async fn names_can_be_made_safe_for_windows() {
+    let archive = MockArchive::start().await;
+    archive.add_work(1701, MockWork::new("Why: A Story*", 1700000000));
+    let dest = tempfile::tempdir().unwrap();
+
+    run_cli(
+        &archive,
+        dest.path(),
+        "1701\n",
+        &["--format", "html", "--sanitize", "windows"],
+    )
+    .await
+    .unwrap();
+
+    assert_eq!(files_in(dest.path()), ["Why A Story [ao3 1701].html"]);
+}
+
+#[tokio::test]
+async fn downloads_go_to_the_output_dir() {
+    let archive = MockArchive::start().await;
+    archive.add_work(1801, MockWork::new("Elsewhere", 1700000000));
+    archive.add_work(1802, MockWork::new("Hidden", 1700000000).hidden());
+    let dest = tempfile::tempdir().unwrap();
+
+    run_cli(
+        &archive,
+        dest.path(),
+        "1801\n1802\n",
+        &[
+            "--format",
+            "epub",
+            "--format",
+            "pdf",
+            "--output-dir",
+            "library/fics",
+            "--format-dirs",
+            "--failure-report",
+            "reports/failed.txt",
+        ],
+    )
+    .await
+    .unwrap();
+
+    let output_dir = dest.path().join("library").join("fics");
+    assert_eq!(files_in(&output_dir), ["epub", "pdf"]);
+    assert_eq!(
+        files_in(&output_dir.join("epub")),
+        ["Elsewhere [ao3 1801].epub"]
+    );
+    assert_eq!(
+        files_in(&output_dir.join("pdf")),
+        ["Elsewhere [ao3 1801].pdf"]
+    );
+    assert!(output_dir.join(library::LIBRARY_FILE_NAME).exists());
+    assert_eq!(files_in(dest.path()), ["library", "reports"]);
+    assert_eq!(
+        fs::read_to_string(dest.path().join("reports").join("failed.txt")).unwrap(),
+        "1802\n"
+    );
+}
+
+#[tokio::test]
+async fn extracted_titles_are_not_remembered_between_runs() {
+    let archive = MockArchive::start().await;
+    archive.add_work(1951, MockWork::new("Before", 1700000000));
+    let dest = tempfile::tempdir().unwrap();
+    let works = "{\"id\": 1951, \"timestamp\": 1700000000}\n";
+
+    run_cli(&archive, dest.path(), works, &["--format", "html"])
+        .await
+        .unwrap();
+    archive.add_work(1951, MockWork::new("After", 1700000000));
+    run_cli(
+        &archive,
+        dest.path(),
+        works,
+        &["--format", "html", "--force"],
+    )
+    .await
+    .unwrap();
+
+    assert_eq!(
+        files_in(dest.path()),
+        ["After [ao3 1951].html", "Before [ao3 1951].html"]
+    );
+}
+
+#[tokio::test]
+async fn interrupted_runs_are_cleaned_up() {
+    let archive = MockArchive::start().await;
+    archive.add_work(1901, MockWork::new("Whole", 1700000000));
+    let dest = tempfile::tempdir().unwrap();
+    fs::write(dest.path().join(".Whole [ao3 1901].epub.ao3dl-tmp"), "half").unwrap();
+    fs::create_dir(dest.path().join(".Torn [ao3 1902].epub.ao3dl-tmp")).unwrap();
+    // Not somewhere ao3dl writes to, so left alone
+    let unrelated = dest.path().join("Unrelated");
+    fs::create_dir(&unrelated).unwrap();
+    fs::write(unrelated.join(".x.epub.ao3dl-tmp"), "someone else's").unwrap();
+
+    run_cli(
+        &archive,
+        dest.path(),
+        "{\"id\": 1901, \"timestamp\": 1700000000}\n",
+        &["--unzip-epubs"],
+    )
+    .await
+    .unwrap();
+
+    assert_eq!(
+        files_in(dest.path()),
+        ["Unrelated", "Whole [ao3 1901].epub"]
+    );
+    assert_eq!(files_in(&unrelated), [".x.epub.ao3dl-tmp"]);
+    let unzipped = dest.path().join("Whole [ao3 1901].epub");
+    assert!(unzipped.join("content.opf").is_file());
+
+    // Unzipping again replaces the directory wholesale
+    fs::write(unzipped.join("stale.xhtml"), "old").unwrap();
+    run_cli(
+        &archive,
+        dest.path(),
+        "{\"id\": 1901, \"timestamp\": 1700000000}\n",
+        &["--unzip-epubs", "--force"],
+    )
+    .await
+    .unwrap();
+
+    assert_eq!(
+        files_in(dest.path()),
+        ["Unrelated", "Whole [ao3 1901].epub"]
+    );
+    assert!(!unzipped.join("stale.xhtml").exists());
+}
+
+#[tokio::test]
+async fn updated_works_can_keep_their_old_version() {
+    let archive = MockArchive::start().await;
+    archive.add_work(2001, MockWork::new("Rewritten", 1700000000));
+    let dest = tempfile::tempdir().unwrap();
+    let args = ["--format", "html", "--on-existing", "version"];
+
+    run_cli(&archive, dest.path(), "2001\n", &args)
+        .await
+        .unwrap();
+    archive.add_work(2001, MockWork::new("Rewritten", 1700000500));
+    run_cli(&archive, dest.path(), "2001\n", &args)
+        .await
+        .unwrap();
+    // Nothing changed, so there's nothing more to keep
+    run_cli(
+        &archive,
+        dest.path(),
+        "2001\n",
+        &[&args[..], &["--force"]].concat(),
+    )
+    .await
+    .unwrap();
+
+    assert_eq!(
+        files_in(dest.path()),
+        [
+            "Rewritten [ao3 2001].1700000000.html",
+            "Rewritten [ao3 2001].html"
+        ]
+    );
+}
+
+#[tokio::test]
+async fn existing_files_can_be_skipped_or_renamed_around() {
+    let archive = MockArchive::start().await;
+    archive.add_work(2002, MockWork::new("Taken", 1700000000));
+    let dest = tempfile::tempdir().unwrap();
+    let taken = dest.path().join("Taken [ao3 2002].html");
+    fs::write(&taken, "mine").unwrap();
+
+    run_cli(
+        &archive,
+        dest.path(),
+        "2002\n",
+        &["--format", "html", "--on-existing", "skip"],
+    )
+    .await
+    .unwrap();
+
+    assert_eq!(files_in(dest.path()), ["Taken [ao3 2002].html"]);
+    assert_eq!(fs::read_to_string(&taken).unwrap(), "mine");
+    // The work page was enough to tell the file would be kept
+    assert_eq!(archive.hits("/downloads/2002/x.html"), 0);
+
+    // The kept file wasn't recorded as downloaded, so the work is still to be downloaded
+    run_cli(
+        &archive,
+        dest.path(),
+        "2002\n",
+        &["--format", "html", "--on-existing", "rename"],
+    )
+    .await
+    .unwrap();
+
+    assert_eq!(
+        files_in(dest.path()),
+        ["Taken [ao3 2002] (2).html", "Taken [ao3 2002].html"]
+    );
+    assert_eq!(archive.hits("/downloads/2002/x.html"), 1);
+    assert_eq!(fs::read_to_string(&taken).unwrap(), "mine");
+}
+
+#[test]
+fn max_size_takes_suffixes() {
+    assert_eq!(parse_size("1024"), Ok(1024));
+    assert_eq!(parse_size("512K"), Ok(512 << 10));
+    assert_eq!(parse_size("50m"), Ok(50 << 20));
+    assert_eq!(parse_size("2G"), Ok(2 << 30));
+    assert!(parse_size("0").is_err());
+    assert!(parse_size("M").is_err());
+    assert!(parse_size("1.5G").is_err());
+}
+
+#[test]
+fn password_stdin_needs_logging_in() {
+    assert!(Cli::try_parse_from(["ao3dl", "works.txt", "--password-stdin", "--no-login"]).is_err());
+    assert!(
+        Cli::try_parse_from([
+            "ao3dl",
+            "works.txt",
+            "--password-stdin",
+            "--password-command",
+            "pass show ao3"
+        ])
+        .is_err()
+    );
+}